@@ -1,401 +1,4894 @@
 
-pub mod report {
+pub mod merchant {
+#[test]
+    fn test_default_normaliser_only_trims() {
+        let normaliser = Normaliser::default();
+
+        assert_eq!(normaliser.merchant("  POS PURCHASE 1234  WOOLWORTHS "), "POS PURCHASE 1234  WOOLWORTHS");
+    }
+
+#[test]
+    fn test_cleaning_pipeline() {
+        let normaliser = Normaliser {
+            strip_prefixes: vec![String::from("POS PURCHASE"), String::from("CARD")],
+            mask_digits: true,
+            collapse_whitespace: true,
+            aliases: Vec::new()
+        };
+
+        assert_eq!(normaliser.clean("POS PURCHASE 1234 WOOLWORTHS 123  CAPE TOWN 2019-11-01 CARD 4321"), "# WOOLWORTHS # CAPE TOWN #-#-# CARD #");
+        assert_eq!(normaliser.clean("pos purchase card  SHELL"), "SHELL");
+    }
+
+#[test]
+    fn test_aliases_map_variants_to_canonical_merchant() {
+        let normaliser = Normaliser {
+            strip_prefixes: vec![String::from("POS PURCHASE")],
+            mask_digits: true,
+            collapse_whitespace: true,
+            aliases: vec![(String::from("WOOLWORTHS"), vec![String::from("WOOLWORTHS"), String::from("WW FOODS")])]
+        };
+
+        assert_eq!(normaliser.merchant("POS PURCHASE 1234 WOOLWORTHS 123 CAPE TOWN"), "WOOLWORTHS");
+        assert_eq!(normaliser.merchant("ww foods  sea point"), "WOOLWORTHS");
+        assert_eq!(normaliser.merchant("PICK N PAY 12"), "PICK N PAY #");
+    }
+
+    /// Cleans bank descriptions so that the same merchant reads the same way from one transaction
+    /// to the next. The steps run in field order; the default leaves descriptions as they are.
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct Normaliser {
+        /// Removed from the start of descriptions, ignoring case, as many times as they appear.
+        pub strip_prefixes: Vec<String>,
+        /// Replaces each run of digits with a single `#`.
+        pub mask_digits: bool,
+        pub collapse_whitespace: bool,
+        /// Canonical merchant names, each with the variants that map to it.
+        pub aliases: Vec<(String, Vec<String>)>
+    }
+
+    impl Normaliser {
+        pub fn clean(&self, description: &str) -> String {
+            let mut cleaned = String::from(description.trim());
+
+            while let Some(prefix) = self.strip_prefixes.iter().find(|p| !p.is_empty() && cleaned.get(..p.len()).is_some_and(|start| start.eq_ignore_ascii_case(p))) {
+                cleaned = String::from(cleaned[prefix.len()..].trim_start());
+            }
+
+            if self.mask_digits {
+                let mut masked = String::new();
+                for c in cleaned.chars() {
+                    if !c.is_ascii_digit() {
+                        masked.push(c);
+                    } else if !masked.ends_with('#') {
+                        masked.push('#');
+                    }
+                }
+                cleaned = masked;
+            }
+
+            if self.collapse_whitespace {
+                cleaned = cleaned.split_whitespace().collect::<Vec<&str>>().join(" ");
+            }
+
+            cleaned
+        }
+
+        /// The canonical merchant for the first alias with a variant in the cleaned description,
+        /// otherwise the cleaned description itself.
+        pub fn merchant(&self, description: &str) -> String {
+            let cleaned = self.clean(description);
+            let upper = cleaned.to_uppercase();
+
+            self.aliases.iter()
+                .find(|(_, variants)| variants.iter().any(|v| upper.contains(&v.to_uppercase())))
+                .map(|(canonical, _)| canonical.clone())
+                .unwrap_or(cleaned)
+        }
+    }
+}
+
+pub mod account {
+#[test]
+    fn test_account_types() {
+        assert_eq!(AccountType::parse("credit card"), Some(AccountType::CreditCard));
+        assert_eq!(AccountType::parse("loan"), None);
+        assert_eq!(AccountType::Savings.name(), "savings");
+    }
+
+#[test]
+    fn test_files_are_matched_to_accounts() {
+        let accounts = vec![
+            Account { name: String::from("everyday"), institution: String::from("FNB"), currency: String::from("ZAR"), account_type: AccountType::Cheque, files: String::from("cheque_*.csv") },
+            Account { name: String::from("card"), institution: String::from("FNB"), currency: String::from("ZAR"), account_type: AccountType::CreditCard, files: String::from("card_??????.csv") },
+        ];
+
+        assert_eq!(find(&accounts, "cheque_201911.csv").map(|a| a.name.as_str()), Some("everyday"));
+        assert_eq!(find(&accounts, "card_201911.csv").map(|a| a.name.as_str()), Some("card"));
+        assert_eq!(find(&accounts, "card_2019.csv"), None);
+        assert_eq!(find(&accounts, "savings.csv"), None);
+    }
+
+    pub const ACCOUNT_TYPES: [&str; 3] = ["cheque", "credit card", "savings"];
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum AccountType {
+        Cheque,
+        CreditCard,
+        Savings
+    }
+
+    impl AccountType {
+        pub fn parse(name: &str) -> Option<AccountType> {
+            match name {
+                "cheque" => Some(AccountType::Cheque),
+                "credit card" => Some(AccountType::CreditCard),
+                "savings" => Some(AccountType::Savings),
+                _ => None
+            }
+        }
+
+        pub fn name(&self) -> &'static str {
+            match self {
+                AccountType::Cheque => "cheque",
+                AccountType::CreditCard => "credit card",
+                AccountType::Savings => "savings"
+            }
+        }
+    }
+
+    /// An account statements are downloaded from, declared in the `accounts` section.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Account {
+        pub name: String,
+        pub institution: String,
+        pub currency: String,
+        pub account_type: AccountType,
+        /// The names of this account's statement files, where `*` stands for any text and `?` for
+        /// any one character.
+        pub files: String
+    }
+
+    impl Account {
+        pub fn holds(&self, file_name: &str) -> bool {
+            let pattern: Vec<char> = self.files.chars().collect();
+            let name: Vec<char> = file_name.chars().collect();
+            glob(&pattern, &name)
+        }
+    }
+
+    /// The first account whose statements are named like `file_name`.
+    pub fn find<'a>(accounts: &'a [Account], file_name: &str) -> Option<&'a Account> {
+        accounts.iter().find(|a| a.holds(file_name))
+    }
+
+    fn glob(pattern: &[char], name: &[char]) -> bool {
+        match (pattern.first(), name.first()) {
+            (None, None) => true,
+            (Some('*'), _) => glob(&pattern[1..], name) || (!name.is_empty() && glob(pattern, &name[1..])),
+            (Some('?'), Some(_)) => glob(&pattern[1..], &name[1..]),
+            (Some(p), Some(n)) if p == n => glob(&pattern[1..], &name[1..]),
+            _ => false
+        }
+    }
+}
+
+pub mod currency {
+    use std::path::Path;
+    use csv::Reader;
+    use chrono::NaiveDate;
+    use rust_decimal::Decimal;
+    use std::str::FromStr;
+    use crate::store;
+
+#[cfg(test)]
+    fn sample_rates() -> Rates {
+        let mut rates = Rates::new();
+        rates.add("USD", "ZAR", NaiveDate::from_ymd(2019, 11, 1), Decimal::from_str("14.80").unwrap());
+        rates.add("USD", "ZAR", NaiveDate::from_ymd(2019, 11, 15), Decimal::from_str("15.00").unwrap());
+        rates.add("ZAR", "GBP", NaiveDate::from_ymd(2019, 11, 1), Decimal::from_str("0.05").unwrap());
+        rates
+    }
+
+#[test]
+    fn test_converts_with_the_latest_rate_on_or_before_the_date() {
+        let rates = sample_rates();
+        let amount = Decimal::new(10, 0);
+
+        assert_eq!(rates.convert(amount, "USD", "ZAR", NaiveDate::from_ymd(2019, 11, 14)), Some(Decimal::from_str("148.00").unwrap()));
+        assert_eq!(rates.convert(amount, "USD", "ZAR", NaiveDate::from_ymd(2019, 11, 30)), Some(Decimal::from_str("150.00").unwrap()));
+        assert_eq!(rates.convert(amount, "USD", "ZAR", NaiveDate::from_ymd(2019, 10, 31)), None);
+        assert_eq!(rates.convert(amount, "ZAR", "ZAR", NaiveDate::from_ymd(2019, 10, 31)), Some(amount));
+    }
+
+#[test]
+    fn test_converts_with_the_inverse_rate() {
+        let rates = sample_rates();
+
+        assert_eq!(rates.convert(Decimal::new(300, 0), "ZAR", "USD", NaiveDate::from_ymd(2019, 11, 1)), Some(Decimal::from_str("20.27").unwrap()));
+        assert_eq!(rates.convert(Decimal::new(1, 0), "GBP", "ZAR", NaiveDate::from_ymd(2019, 11, 1)), Some(Decimal::from_str("20.00").unwrap()));
+        assert_eq!(rates.convert(Decimal::new(1, 0), "GBP", "USD", NaiveDate::from_ymd(2019, 11, 1)), None);
+    }
+
+#[test]
+    fn test_loading_rates() {
+        let path = std::env::temp_dir().join(format!("bsi_rates_{}.csv", std::process::id()));
+        std::fs::write(&path, "date,from,to,rate\n20191101,USD,ZAR,14.80\n").unwrap();
+        let rates = Rates::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(rates.convert(Decimal::new(1, 0), "USD", "ZAR", NaiveDate::from_ymd(2019, 11, 2)), Some(Decimal::from_str("14.80").unwrap()));
+        assert!(Rates::load(Path::new("/nonexistent/bank_statement_importer_rates.csv")).unwrap().is_empty());
+    }
+
+#[test]
+    fn test_loading_bad_rates_names_the_line() {
+        let path = std::env::temp_dir().join(format!("bsi_bad_rates_{}.csv", std::process::id()));
+        std::fs::write(&path, "date,from,to,rate\n20191101,USD,ZAR,14.80\n20191102,USD,ZAR,fifteen\n").unwrap();
+        let loaded = Rates::load(&path);
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(loaded.unwrap_err().to_string(), "line 3: invalid rate fifteen");
+    }
+
+    /// Exchange rates by date, each giving how much of one currency a unit of another is worth.
+    #[derive(Debug, Clone, Default)]
+    pub struct Rates {
+        rates: Vec<(String, String, NaiveDate, Decimal)>
+    }
+
+    impl Rates {
+        pub fn new() -> Rates {
+            Rates { rates: Vec::new() }
+        }
+
+        /// Reads a CSV file of date, from, to and rate columns. A missing file has no rates.
+        pub fn load(path: &Path) -> Result<Rates, csv::Error> {
+            let mut rates = Rates::new();
+
+            if !path.exists() {
+                return Ok(rates);
+            }
+
+            for result in Reader::from_path(path)?.records() {
+                let record = result?;
+                let field = |index: usize| record.get(index).unwrap_or("").trim();
+                let date = NaiveDate::parse_from_str(field(0), "%Y%m%d").map_err(|_| store::invalid_row(&record, &format!("invalid date {}", field(0))))?;
+                let rate = Decimal::from_str(field(3)).map_err(|_| store::invalid_row(&record, &format!("invalid rate {}", field(3))))?;
+                rates.add(field(1), field(2), date, rate);
+            }
+
+            Ok(rates)
+        }
+
+        pub fn add(&mut self, from: &str, to: &str, date: NaiveDate, rate: Decimal) {
+            self.rates.push((from.to_uppercase(), to.to_uppercase(), date, rate));
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.rates.is_empty()
+        }
+
+        /// Converts `amount` at the latest rate on or before `date`, either as given or inverted,
+        /// rounded to cents. Returns `None` when there is no such rate.
+        pub fn convert(&self, amount: Decimal, from: &str, to: &str, date: NaiveDate) -> Option<Decimal> {
+            let (from, to) = (from.to_uppercase(), to.to_uppercase());
+            if from == to {
+                return Some(amount);
+            }
+
+            self.rates.iter()
+                .filter(|(_, _, d, rate)| *d <= date && *rate != Decimal::new(0, 0))
+                .filter_map(|(f, t, d, rate)| {
+                    if *f == from && *t == to {
+                        Some((*d, amount * *rate))
+                    } else if *f == to && *t == from {
+                        Some((*d, amount / *rate))
+                    } else {
+                        None
+                    }
+                })
+                .max_by_key(|(d, _)| *d)
+                .map(|(_, converted)| converted.round_dp(2))
+        }
+    }
+}
+
+pub mod transfer {
     use csv::StringRecord;
     use chrono::NaiveDate;
-    use sha2::{Sha256, Digest};
     use rust_decimal::Decimal;
     use std::str::FromStr;
+    use crate::report::{ACCOUNT, CURRENCY};
 
 #[test]
-    fn test_categorised_entry_instantiation() {
-        let record = StringRecord::from(vec!["  20191101  ", "  foo bar  ", "  -191.60  ", "  0.00  "]);
-        let ce = CategorisedEntry::new("personal", "bar cat", &record);
+    fn test_pairs_opposite_amounts_across_accounts_within_the_window() {
+        let records = vec![
+            StringRecord::from(vec!["20191101", "CARD PAYMENT", "-500.00", "0.00", "everyday"]),
+            StringRecord::from(vec!["20191101", "SHOP", "-500.00", "0.00", "card"]),
+            StringRecord::from(vec!["20191104", "PAYMENT RECEIVED", "500.00", "0.00", "card"]),
+            StringRecord::from(vec!["20191102", "REFUND", "500.00", "0.00", "everyday"]),
+            StringRecord::from(vec!["20191120", "SAVINGS", "-100.00", "0.00", "everyday"]),
+            StringRecord::from(vec!["20191101", "INTEREST", "100.00", "0.00", "savings"]),
+            StringRecord::from(vec!["20191201", "TOP UP", "100.00", "0.00", "savings"]),
+        ];
 
-        assert_eq!(ce.category_type, "personal");
-        assert_eq!(ce.category, "bar cat");
-        assert_eq!(ce.date, NaiveDate::from_ymd(2019,11,1));
-        assert_eq!(ce.description, "foo bar");
-        assert_eq!(ce.expense, true);
-        assert_eq!(ce.amount, Decimal::from_str("191.6").unwrap());
+        assert_eq!(pair(&records, 3), vec![(0, 2), (1, 3)]);
+        assert_eq!(pair(&records, 20), vec![(0, 2), (1, 3), (4, 6)]);
     }
 
 #[test]
-    fn test_categorised_entry_instantiation_with_income() {
-        let record = StringRecord::from(vec!["20191101", "foo bar", "191.60", "0.00"]);
-        let ce = CategorisedEntry::new("personal", "bar cat", &record);
+    fn test_does_not_pair_amounts_in_different_currencies() {
+        let records = vec![
+            StringRecord::from(vec!["20191101", "TOP UP", "-100.00", "0.00", "everyday", "ZAR"]),
+            StringRecord::from(vec!["20191101", "TOP UP", "100.00", "0.00", "travel", "USD"]),
+        ];
 
-        assert_eq!(ce.expense, false);
-        assert_eq!(ce.amount, Decimal::from_str("191.6").unwrap());
+        assert!(pair(&records, 3).is_empty());
+    }
+
+    /// Pairs money leaving one account with the same amount in the same currency reaching another
+    /// account no more than `days` apart, returning the indices of each debit and its credit. Each
+    /// debit takes the closest credit left. Records with no account are never paired.
+    pub fn pair(records: &[StringRecord], days: i64) -> Vec<(usize, usize)> {
+        let parsed: Vec<Option<(NaiveDate, Decimal, &str)>> = records.iter().map(|record| {
+            let date = NaiveDate::parse_from_str(record.get(0)?.trim(), "%Y%m%d").ok()?;
+            let amount = Decimal::from_str(record.get(2)?.trim()).ok()?;
+            let account = record.get(ACCOUNT).map(|a| a.trim()).filter(|a| !a.is_empty())?;
+            Some((date, amount, account))
+        }).collect();
+        let currency = |index: usize| records[index].get(CURRENCY).unwrap_or("").trim().to_uppercase();
+
+        let mut pairs: Vec<(usize, usize)> = Vec::new();
+        let mut credited: Vec<usize> = Vec::new();
+        for (debit, parsed_debit) in parsed.iter().enumerate() {
+            let (date, amount, account) = match parsed_debit {
+                Some(parsed_debit) if parsed_debit.1.is_sign_negative() => *parsed_debit,
+                _ => continue
+            };
+            let credit = parsed.iter().enumerate()
+                .filter(|(index, _)| !credited.contains(index))
+                .filter_map(|(index, p)| p.map(|p| (index, p)))
+                .filter(|(index, (d, a, acc))| *a == -amount && *acc != account && (*d - date).num_days().abs() <= days && currency(*index) == currency(debit))
+                .min_by_key(|(_, (d, _, _))| (*d - date).num_days().abs())
+                .map(|(index, _)| index);
+            if let Some(credit) = credit {
+                credited.push(credit);
+                pairs.push((debit, credit));
+            }
+        }
+        pairs
+    }
+}
+
+pub mod schema {
+    use std::fmt;
+    use yaml_rust::Yaml;
+    use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+    use yaml_rust::scanner::{Marker, TScalarStyle};
+    use crate::migration::VERSION;
+    use crate::account::ACCOUNT_TYPES;
+
+    /// Something wrong with a config file, and the line it is on.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Problem {
+        pub line: usize,
+        pub message: String
+    }
+
+    impl fmt::Display for Problem {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "line {}: {}", self.line, self.message)
+        }
+    }
+
+    /// A YAML node with the line it starts on. Unlike `Yaml`, mappings keep duplicate keys.
+    enum Node {
+        Scalar(Yaml, usize),
+        Sequence(Vec<Node>, usize),
+        Mapping(Vec<(Node, Node)>, usize)
+    }
+
+    impl Node {
+        fn line(&self) -> usize {
+            match self {
+                Node::Scalar(_, line) | Node::Sequence(_, line) | Node::Mapping(_, line) => *line
+            }
+        }
+
+        fn as_str(&self) -> Option<&str> {
+            match self {
+                Node::Scalar(value, _) => value.as_str(),
+                _ => None
+            }
+        }
+
+        fn get(&self, key: &str) -> Option<&Node> {
+            self.entry(key).map(|(_, v)| v)
+        }
+
+        fn entry(&self, key: &str) -> Option<(&Node, &Node)> {
+            match self {
+                Node::Mapping(entries, _) => entries.iter().find(|(k, _)| k.as_str() == Some(key)).map(|(k, v)| (k, v)),
+                _ => None
+            }
+        }
+
+        fn describe(&self) -> &'static str {
+            match self {
+                Node::Scalar(Yaml::String(_), _) => "a string",
+                Node::Scalar(Yaml::Integer(_), _) | Node::Scalar(Yaml::Real(_), _) => "a number",
+                Node::Scalar(Yaml::Boolean(_), _) => "true or false",
+                Node::Scalar(Yaml::Null, _) => "empty",
+                Node::Scalar(_, _) => "a value",
+                Node::Sequence(_, _) => "a list",
+                Node::Mapping(_, _) => "a mapping"
+            }
+        }
+    }
+
+    /// Builds a tree of `Node`s from parser events.
+    struct Builder {
+        stack: Vec<(Node, Option<Node>)>,
+        root: Option<Node>
     }
 
+    impl Builder {
+        fn push(&mut self, node: Node) {
+            match self.stack.last_mut() {
+                Some((Node::Sequence(items, _), _)) => items.push(node),
+                Some((Node::Mapping(entries, _), key)) => match key.take() {
+                    Some(k) => entries.push((k, node)),
+                    None => *key = Some(node)
+                },
+                _ => if self.root.is_none() {
+                    self.root = Some(node)
+                }
+            }
+        }
+    }
+
+    impl MarkedEventReceiver for Builder {
+        fn on_event(&mut self, event: Event, mark: Marker) {
+            match event {
+                Event::Scalar(value, style, _, _) => {
+                    let value = if style == TScalarStyle::Plain { Yaml::from_str(&value) } else { Yaml::String(value) };
+                    self.push(Node::Scalar(value, mark.line()));
+                },
+                Event::SequenceStart(_) => self.stack.push((Node::Sequence(Vec::new(), mark.line()), None)),
+                Event::MappingStart(_) => self.stack.push((Node::Mapping(Vec::new(), mark.line()), None)),
+                Event::SequenceEnd | Event::MappingEnd => {
+                    if let Some((node, _)) = self.stack.pop() {
+                        self.push(node);
+                    }
+                },
+                Event::Alias(_) => self.push(Node::Scalar(Yaml::Null, mark.line())),
+                _ => {}
+            }
+        }
+    }
+
+    const TYPES: [&str; 2] = ["personal", "work"];
+    const SECTIONS: [&str; 7] = ["version", "include", "currency", "categories", "patterns", "cleaning", "accounts"];
+    const ACCOUNT_FIELDS: [&str; 4] = ["institution", "currency", "type", "files"];
+
 #[test]
-    fn test_initialising_categorised_entry_creates_fingerprint() {
-        let record = StringRecord::from(vec![" 20191101   ", "  foo bar  ", "  -191.60 ", "  0.00  "]);
-        let ce = CategorisedEntry::new("personal", "bar cat", &record);
+    fn test_valid_config_has_no_problems() {
+        let contents = "categories:\n  personal: [groceries]\n  work: []\npatterns:\n  personal:\n    groceries: [SHOP]\n  work: {}\n";
 
-        let mut hasher = Sha256::new();
-        hasher.input("20191101foo bar-191.600.00");
-        let fingerprint = hex::encode(hasher.result());
+        assert!(check(contents).is_empty());
+    }
 
-        assert_eq!(ce.record_fingerprint, fingerprint);
+#[test]
+    fn test_missing_sections() {
+        let contents = "categories:\n  personal: []\n  work: []\npatterns:\n  personal: {}\n";
+
+        assert_eq!(check(contents), vec![Problem { line: 4, message: String::from("patterns is missing work") }]);
+        assert_eq!(check("categories:\n  personal: []\n  work: []\n"), vec![Problem { line: 1, message: String::from("The patterns section is missing") }]);
     }
 
 #[test]
-    fn test_equality() {
-        assert_eq!(
-            CategorisedEntry {
-                category_type: String::from("personal"),
-                category: String::from("bar"),
-                date: NaiveDate::from_ymd(2019,11,1),
-                description: String::from("fuzzy"),
-                expense: true,
-                amount: Decimal::new(100,0),
-                record_fingerprint: String::from("abc123")
-            },
-            CategorisedEntry {
-                category_type: String::from("personal"),
-                category: String::from("bar"),
-                date: NaiveDate::from_ymd(2019,11,1),
-                description: String::from("fuzzy"),
-                expense: true,
-                amount: Decimal::new(100,0),
-                record_fingerprint: String::from("abc456")
-            },
-            );
+    fn test_reports_every_problem_with_its_line() {
+        let contents = [
+            "categories:",
+            "  personal:",
+            "    - groceries",
+            "    - groceries",
+            "    - 42",
+            "    - fuel",
+            "  work: []",
+            "patterns:",
+            "  personal:",
+            "    groceries: [SHOP]",
+            "    travel: [AIRLINE]",
+            "    42: []",
+            "  work: {}",
+            "budgets: {}",
+        ].join("\n");
 
-        assert_ne!(
-            CategorisedEntry {
-                category_type: String::from("personal"),
-                category: String::from("bar"),
-                date: NaiveDate::from_ymd(2019,11,1),
-                description: String::from("fuzzy"),
-                expense: true,
-                amount: Decimal::new(100,0),
-                record_fingerprint: String::from("abc123")
-            },
-            CategorisedEntry {
-                category_type: String::from("work"),
-                category: String::from("bar"),
-                date: NaiveDate::from_ymd(2019,11,1),
-                description: String::from("fuzzy"),
-                expense: true,
-                amount: Decimal::new(100,0),
-                record_fingerprint: String::from("abc123")
-            },
-            );
+        let problems: Vec<String> = check(&contents).iter().map(|p| p.to_string()).collect();
+        assert_eq!(problems, vec![
+            "line 4: Category groceries is listed twice (first on line 3)",
+            "line 5: Category names must be a string, not a number",
+            "line 6: Category fuel has no pattern list under patterns.personal",
+            "line 11: Patterns are given for travel, which is not a personal category",
+            "line 12: Category names must be a string, not a number",
+            "line 14: Unknown section budgets",
+        ]);
+    }
 
-        assert_ne!(
-            CategorisedEntry {
-                category_type: String::from("personal"),
-                category: String::from("bar"),
-                date: NaiveDate::from_ymd(2019,11,1),
-                description: String::from("fuzzy"),
-                expense: true,
-                amount: Decimal::new(100,0),
-                record_fingerprint: String::from("abc123")
-            },
-            CategorisedEntry {
-                category_type: String::from("personal"),
-                category: String::from("baz"),
-                date: NaiveDate::from_ymd(2019,11,1),
-                description: String::from("fuzzy"),
-                expense: true,
-                amount: Decimal::new(100,0),
-                record_fingerprint: String::from("abc123")
-            },
-            );
+#[test]
+    fn test_wrongly_typed_values() {
+        let contents = "categories:\n  personal: groceries\n  work: []\npatterns:\n  personal: []\n  work:\n    travel: AIRLINE\ncleaning:\n  mask_digits: yes please\n";
 
-        assert_ne!(
-            CategorisedEntry {
-                category_type: String::from("personal"),
-                category: String::from("bar"),
-                date: NaiveDate::from_ymd(2019,11,2),
-                description: String::from("fuzzy"),
-                expense: true,
-                amount: Decimal::new(100,0),
-                record_fingerprint: String::from("abc123")
-            },
-            CategorisedEntry {
-                category_type: String::from("work"),
-                category: String::from("bar"),
-                date: NaiveDate::from_ymd(2019,11,1),
-                description: String::from("fuzzy"),
-                expense: true,
-                amount: Decimal::new(100,0),
-                record_fingerprint: String::from("abc123")
-            },
-            );
+        let problems: Vec<String> = check(contents).iter().map(|p| p.to_string()).collect();
+        assert_eq!(problems, vec![
+            "line 2: categories.personal must be a list, not a string",
+            "line 5: patterns.personal must be a mapping, not a list",
+            "line 7: Patterns are given for travel, which is not a work category",
+            "line 7: Patterns for travel must be a list, not a string",
+            "line 9: cleaning.mask_digits must be true or false, not a string",
+        ]);
+    }
+
+#[test]
+    fn test_version_must_be_understood() {
+        let contents = "version: 99\ncategories:\n  personal: []\n  work: []\npatterns:\n  personal: {}\n  work: {}\n";
+
+        assert_eq!(check(contents), vec![Problem { line: 1, message: format!("Version 99 is newer than this release understands (up to {})", VERSION) }]);
+    }
+
+#[test]
+    fn test_accounts() {
+        let contents = [
+            "categories:",
+            "  personal: []",
+            "  work: []",
+            "patterns:",
+            "  personal: {}",
+            "  work: {}",
+            "accounts:",
+            "  everyday:",
+            "    institution: FNB",
+            "    currency: ZAR",
+            "    type: cheque",
+            "    files: cheque_*.csv",
+            "  card:",
+            "    institution: FNB",
+            "    type: store card",
+            "    files: [card.csv]",
+            "    limit: 1000",
+        ].join("\n");
+
+        let problems: Vec<String> = check(&contents).iter().map(|p| p.to_string()).collect();
+        assert_eq!(problems, vec![
+            "line 13: Account card is missing currency",
+            "line 15: accounts.card.type must be one of cheque, credit card, savings",
+            "line 16: accounts.card.files must be a string, not a list",
+            "line 17: Unknown account field limit",
+        ]);
+    }
+
+#[test]
+    fn test_currency_must_be_a_string() {
+        let contents = "currency: [ZAR]\ncategories:\n  personal: []\n  work: []\npatterns:\n  personal: {}\n  work: {}\n";
+
+        assert_eq!(check(contents), vec![Problem { line: 1, message: String::from("The currency must be a string, not a list") }]);
+        assert!(check(&contents.replace("[ZAR]", "ZAR")).is_empty());
+    }
+
+#[test]
+    fn test_syntax_error() {
+        let problems = check("categories: [personal\n");
+
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].line, 2);
+    }
+
+    /// Checks a config file, returning every problem found. An empty list means the file can be loaded.
+    pub fn check(contents: &str) -> Vec<Problem> {
+        let mut builder = Builder { stack: Vec::new(), root: None };
+        if let Err(error) = Parser::new(contents.chars()).load(&mut builder, false) {
+            let message = error.to_string();
+            let message = message.split(" at line").next().unwrap_or(&message);
+            return vec![Problem { line: error.marker().line(), message: String::from(message) }];
+        }
+
+        let mut problems: Vec<Problem> = Vec::new();
+        let root = match builder.root {
+            Some(root @ Node::Mapping(_, _)) => root,
+            Some(other) => return vec![Problem { line: other.line(), message: format!("The config must be a mapping, not {}", other.describe()) }],
+            None => return vec![Problem { line: 1, message: String::from("The config is empty") }]
+        };
+
+        if let Node::Mapping(entries, _) = &root {
+            for (key, _) in entries.iter() {
+                match key.as_str() {
+                    Some(name) if SECTIONS.contains(&name) => {},
+                    Some(name) => problems.push(Problem { line: key.line(), message: format!("Unknown section {}", name) }),
+                    None => problems.push(Problem { line: key.line(), message: format!("Section names must be a string, not {}", key.describe()) })
+                }
+            }
+        }
+
+        let categories = section(&root, "categories", &mut problems);
+        let patterns = section(&root, "patterns", &mut problems);
+
+        for entry_type in TYPES.iter() {
+            let listed = categories.and_then(|c| typed(c, "categories", entry_type, &mut problems));
+            let mut names: Vec<(String, usize)> = Vec::new();
+
+            match listed {
+                Some(Node::Sequence(items, _)) => {
+                    for item in items {
+                        match item.as_str() {
+                            Some(name) => match names.iter().find(|(n, _)| n == name) {
+                                Some((_, first)) => problems.push(Problem { line: item.line(), message: format!("Category {} is listed twice (first on line {})", name, first) }),
+                                None => names.push((String::from(name), item.line()))
+                            },
+                            None => problems.push(Problem { line: item.line(), message: format!("Category names must be a string, not {}", item.describe()) })
+                        }
+                    }
+                },
+                Some(other) => problems.push(Problem { line: other.line(), message: format!("categories.{} must be a list, not {}", entry_type, other.describe()) }),
+                None => {}
+            }
+
+            let pattern_lists = patterns.and_then(|p| typed(p, "patterns", entry_type, &mut problems));
+            let mut with_patterns: Vec<String> = Vec::new();
+
+            match pattern_lists {
+                Some(Node::Mapping(entries, _)) => {
+                    for (key, value) in entries {
+                        let category = match key.as_str() {
+                            Some(category) => category,
+                            None => {
+                                problems.push(Problem { line: key.line(), message: format!("Category names must be a string, not {}", key.describe()) });
+                                continue;
+                            }
+                        };
+                        if with_patterns.iter().any(|c| c == category) {
+                            problems.push(Problem { line: key.line(), message: format!("Patterns for {} are given twice", category) });
+                        }
+                        with_patterns.push(String::from(category));
+                        if listed.is_some() && !names.iter().any(|(n, _)| n == category) {
+                            problems.push(Problem { line: key.line(), message: format!("Patterns are given for {}, which is not a {} category", category, entry_type) });
+                        }
+                        strings(value, &format!("Patterns for {}", category), &mut problems);
+                    }
+                },
+                Some(other) => problems.push(Problem { line: other.line(), message: format!("patterns.{} must be a mapping, not {}", entry_type, other.describe()) }),
+                None => {}
+            }
+
+            if let Some(Node::Mapping(_, _)) = pattern_lists {
+                for (name, line) in names.iter().filter(|(n, _)| !with_patterns.contains(n)) {
+                    problems.push(Problem { line: *line, message: format!("Category {} has no pattern list under patterns.{}", name, entry_type) });
+                }
+            }
+        }
+
+        if let Some(version) = root.get("version") {
+            match version {
+                Node::Scalar(Yaml::Integer(number), _) if *number > VERSION => problems.push(Problem { line: version.line(), message: format!("Version {} is newer than this release understands (up to {})", number, VERSION) }),
+                Node::Scalar(Yaml::Integer(number), _) if *number >= 0 => {},
+                other => problems.push(Problem { line: other.line(), message: format!("The version must be a whole number, not {}", other.describe()) })
+            }
+        }
+
+        if let Some(include) = root.get("include") {
+            strings(include, "include", &mut problems);
+        }
+
+        if let Some(currency) = root.get("currency").filter(|c| c.as_str().is_none()) {
+            problems.push(Problem { line: currency.line(), message: format!("The currency must be a string, not {}", currency.describe()) });
+        }
+
+        if let Some(cleaning) = root.get("cleaning") {
+            check_cleaning(cleaning, &mut problems);
+        }
+
+        if let Some(accounts) = root.get("accounts") {
+            check_accounts(accounts, &mut problems);
+        }
+
+        problems.sort_by_key(|p| p.line);
+        problems
+    }
+
+    /// Returns the section, with the line its name is on.
+    fn section<'a>(root: &'a Node, name: &str, problems: &mut Vec<Problem>) -> Option<(usize, &'a Node)> {
+        match root.entry(name) {
+            Some((key, node @ Node::Mapping(_, _))) => Some((key.line(), node)),
+            Some((_, other)) => {
+                problems.push(Problem { line: other.line(), message: format!("The {} section must be a mapping, not {}", name, other.describe()) });
+                None
+            },
+            None => {
+                problems.push(Problem { line: root.line(), message: format!("The {} section is missing", name) });
+                None
+            }
+        }
+    }
+
+    fn typed<'a>((line, section): (usize, &'a Node), name: &str, entry_type: &str, problems: &mut Vec<Problem>) -> Option<&'a Node> {
+        let found = section.get(entry_type);
+        if found.is_none() {
+            problems.push(Problem { line, message: format!("{} is missing {}", name, entry_type) });
+        }
+        found
+    }
+
+    fn strings(node: &Node, name: &str, problems: &mut Vec<Problem>) {
+        match node {
+            Node::Sequence(items, _) => {
+                for item in items.iter().filter(|i| i.as_str().is_none()) {
+                    problems.push(Problem { line: item.line(), message: format!("{} must be strings, not {}", name, item.describe()) });
+                }
+            },
+            other => problems.push(Problem { line: other.line(), message: format!("{} must be a list, not {}", name, other.describe()) })
+        }
+    }
+
+    fn check_accounts(accounts: &Node, problems: &mut Vec<Problem>) {
+        let entries = match accounts {
+            Node::Mapping(entries, _) => entries,
+            other => {
+                problems.push(Problem { line: other.line(), message: format!("The accounts section must be a mapping, not {}", other.describe()) });
+                return;
+            }
+        };
+
+        for (key, value) in entries {
+            let name = match key.as_str() {
+                Some(name) => name,
+                None => {
+                    problems.push(Problem { line: key.line(), message: format!("Account names must be a string, not {}", key.describe()) });
+                    continue;
+                }
+            };
+            let fields = match value {
+                Node::Mapping(fields, _) => fields,
+                other => {
+                    problems.push(Problem { line: other.line(), message: format!("Account {} must be a mapping, not {}", name, other.describe()) });
+                    continue;
+                }
+            };
+
+            for field in ACCOUNT_FIELDS.iter().filter(|f| value.get(f).is_none()) {
+                problems.push(Problem { line: key.line(), message: format!("Account {} is missing {}", name, field) });
+            }
+            for (field, setting) in fields {
+                match field.as_str() {
+                    Some(field) if ACCOUNT_FIELDS.contains(&field) => match setting.as_str() {
+                        Some(account_type) if field == "type" && !ACCOUNT_TYPES.contains(&account_type) => {
+                            problems.push(Problem { line: setting.line(), message: format!("accounts.{}.type must be one of {}", name, ACCOUNT_TYPES.join(", ")) });
+                        },
+                        Some(_) => {},
+                        None => problems.push(Problem { line: setting.line(), message: format!("accounts.{}.{} must be a string, not {}", name, field, setting.describe()) })
+                    },
+                    _ => problems.push(Problem { line: field.line(), message: format!("Unknown account field {}", field.as_str().unwrap_or("")) })
+                }
+            }
+        }
+    }
+
+    fn check_cleaning(cleaning: &Node, problems: &mut Vec<Problem>) {
+        let entries = match cleaning {
+            Node::Mapping(entries, _) => entries,
+            other => {
+                problems.push(Problem { line: other.line(), message: format!("The cleaning section must be a mapping, not {}", other.describe()) });
+                return;
+            }
+        };
+
+        for (key, value) in entries {
+            match key.as_str() {
+                Some("strip_prefixes") => strings(value, "cleaning.strip_prefixes", problems),
+                Some(step @ "mask_digits") | Some(step @ "collapse_whitespace") => {
+                    if let Node::Scalar(Yaml::Boolean(_), _) = value {
+                    } else {
+                        problems.push(Problem { line: value.line(), message: format!("cleaning.{} must be true or false, not {}", step, value.describe()) });
+                    }
+                },
+                Some("aliases") => match value {
+                    Node::Mapping(aliases, _) => {
+                        for (canonical, variants) in aliases {
+                            match canonical.as_str() {
+                                Some(canonical) => strings(variants, &format!("Variants of {}", canonical), problems),
+                                None => problems.push(Problem { line: canonical.line(), message: format!("Merchant names must be a string, not {}", canonical.describe()) })
+                            }
+                        }
+                    },
+                    other => problems.push(Problem { line: other.line(), message: format!("cleaning.aliases must be a mapping, not {}", other.describe()) })
+                },
+                _ => problems.push(Problem { line: key.line(), message: String::from("Unknown cleaning step") })
+            }
+        }
+    }
+}
+
+pub mod migration {
+    use yaml_rust::Yaml;
+    use yaml_rust::yaml::Hash;
+    #[cfg(test)]
+    use yaml_rust::YamlLoader;
+
+    /// The config version this build reads and writes.
+    pub const VERSION: i64 = 1;
+
+    /// Upgrades a config from the version at its index to the next one.
+    const STEPS: [fn(&mut Hash); 1] = [unversioned];
+
+    /// Files from before the version field have the same layout as version 1.
+    fn unversioned(_config: &mut Hash) {
+    }
+
+#[test]
+    fn test_version() {
+        let load = |contents: &str| YamlLoader::load_from_str(contents).unwrap().pop().unwrap();
+
+        assert_eq!(version(&load("categories: {}")), Ok(0));
+        assert_eq!(version(&load("version: 3")), Ok(3));
+        assert!(version(&load("version: one")).is_err());
+        assert!(version(&load("version: -1")).is_err());
+    }
+
+    /// The version a config was written as. Files without a version field are version 0.
+    pub fn version(config: &Yaml) -> Result<i64, String> {
+        match &config["version"] {
+            Yaml::BadValue => Ok(0),
+            Yaml::Integer(version) if *version >= 0 => Ok(*version),
+            _ => Err(String::from("The config version must be a whole number"))
+        }
+    }
+
+#[test]
+    fn test_migrating_unversioned_config() {
+        let mut config = YamlLoader::load_from_str("categories: {}\npatterns: {}").unwrap().pop().unwrap();
+
+        assert_eq!(migrate(&mut config), Ok(0));
+        assert_eq!(config["version"], Yaml::Integer(VERSION));
+        assert_eq!(config.as_hash().unwrap().keys().next(), Some(&Yaml::from_str("version")));
+        assert_eq!(migrate(&mut config), Ok(VERSION));
+    }
+
+#[test]
+    fn test_refuses_newer_config() {
+        let mut config = YamlLoader::load_from_str(&format!("version: {}", VERSION + 1)).unwrap().pop().unwrap();
+
+        assert!(migrate(&mut config).is_err());
+        assert_eq!(config["version"], Yaml::Integer(VERSION + 1));
+    }
+
+    /// Upgrades `config` to `VERSION` one step at a time, returning the version it started at.
+    /// Configs from a newer version are refused rather than guessed at.
+    pub fn migrate(config: &mut Yaml) -> Result<i64, String> {
+        let from = version(config)?;
+        if from > VERSION {
+            return Err(format!("The config is version {}, which is newer than this release understands (up to {})", from, VERSION));
+        }
+
+        if let Yaml::Hash(contents) = config {
+            for step in from..VERSION {
+                STEPS[step as usize](contents);
+            }
+
+            if from < VERSION {
+                let mut upgraded = Hash::new();
+                upgraded.insert(Yaml::from_str("version"), Yaml::Integer(VERSION));
+                for (key, value) in contents.iter().filter(|(k, _)| k.as_str() != Some("version")) {
+                    upgraded.insert(key.clone(), value.clone());
+                }
+                *contents = upgraded;
+            }
+        }
+
+        Ok(from)
+    }
+}
+
+pub mod store {
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, Write};
+    use std::path::{Path, PathBuf};
+    use chrono::Local;
+
+    /// `path` with `suffix` added to its file name.
+    fn sibling(path: &Path, suffix: &str) -> PathBuf {
+        let mut name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+        name.push(suffix);
+        path.with_file_name(name)
+    }
+
+#[test]
+    fn test_write_atomically_replaces_file() {
+        let path = std::env::temp_dir().join(format!("bsi_atomic_{}.yml", std::process::id()));
+        fs::write(&path, "old").unwrap();
+
+        write_atomically(&path, "new").unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        let leftover = sibling(&path, &format!(".{}.tmp", std::process::id())).exists();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(contents, "new");
+        assert!(!leftover);
+    }
+
+    /// Writes to a temporary file next to `path` and renames it into place, so that the file is
+    /// either the old version or the new one even if writing is interrupted.
+    pub fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+        let temp_path = sibling(path, &format!(".{}.tmp", std::process::id()));
+
+        let written = File::create(&temp_path).and_then(|mut file| {
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()
+        });
+        if let Err(error) = written.and_then(|_| fs::rename(&temp_path, path)) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    /// An error for a row of a CSV file that could not be read, naming its line.
+    pub fn invalid_row(record: &csv::StringRecord, message: &str) -> csv::Error {
+        let line = record.position().map(|p| p.line()).unwrap_or(0);
+        csv::Error::from(io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line, message)))
+    }
+
+    /// The backups of `path`, oldest first.
+    fn backups(path: &Path) -> io::Result<Vec<PathBuf>> {
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let directory = match path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            Some(directory) => directory.to_path_buf(),
+            None => PathBuf::from(".")
+        };
+
+        let mut backups: Vec<PathBuf> = Vec::new();
+        for file in fs::read_dir(directory)? {
+            let file = file?.path();
+            let file_name = file.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+            let stamp = file_name.strip_prefix(&format!("{}.", name)).and_then(|rest| rest.strip_suffix(".bak"));
+            if stamp.is_some_and(|s| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit())) {
+                backups.push(file);
+            }
+        }
+        backups.sort();
+        Ok(backups)
+    }
+
+#[test]
+    fn test_back_up_skips_unchanged_files_and_rotates() {
+        let directory = std::env::temp_dir().join(format!("bsi_backups_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("config.yml");
+
+        fs::write(&path, "one").unwrap();
+        let first = back_up_as(&path, "20191101120000000", 2).unwrap();
+        let unchanged = back_up_as(&path, "20191101120000001", 2).unwrap();
+        fs::write(&path, "two").unwrap();
+        back_up_as(&path, "20191101120000002", 2).unwrap();
+        fs::write(&path, "three").unwrap();
+        back_up_as(&path, "20191101120000003", 2).unwrap();
+        fs::write(directory.join("config.yml.v0.bak"), "migrated").unwrap();
+
+        let kept = backups(&path).unwrap();
+        let contents: Vec<String> = kept.iter().map(|b| fs::read_to_string(b).unwrap()).collect();
+        let _ = fs::remove_dir_all(&directory);
+
+        assert_eq!(first, Some(directory.join("config.yml.20191101120000000.bak")));
+        assert_eq!(unchanged, None);
+        assert_eq!(contents, vec!["two", "three"]);
+    }
+
+    /// Copies `path` to a backup named after the current time, unless the newest backup already
+    /// has the same contents, then removes all but the `keep` newest backups.
+    pub fn back_up(path: &Path, keep: usize) -> io::Result<Option<PathBuf>> {
+        back_up_as(path, &Local::now().format("%Y%m%d%H%M%S%3f").to_string(), keep)
+    }
+
+    fn back_up_as(path: &Path, stamp: &str, keep: usize) -> io::Result<Option<PathBuf>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let contents = fs::read(path)?;
+        let existing = backups(path)?;
+        if let Some(newest) = existing.last() {
+            if fs::read(newest)? == contents {
+                return Ok(None);
+            }
+        }
+
+        let backup_path = sibling(path, &format!(".{}.bak", stamp));
+        fs::write(&backup_path, contents)?;
+
+        let mut all = backups(path)?;
+        let excess = all.len().saturating_sub(keep);
+        for old in all.drain(..excess) {
+            fs::remove_file(old)?;
+        }
+
+        Ok(Some(backup_path))
+    }
+
+#[test]
+    fn test_lock_excludes_other_sessions_until_dropped() {
+        let path = std::env::temp_dir().join(format!("bsi_lock_{}.yml", std::process::id()));
+
+        let lock = Lock::acquire(&path).unwrap();
+        let second = Lock::acquire(&path);
+        drop(lock);
+        let third = Lock::acquire(&path);
+
+        assert_eq!(second.err().map(|e| e.kind()), Some(io::ErrorKind::AlreadyExists));
+        assert!(third.is_ok());
+    }
+
+#[test]
+    #[cfg(target_os = "linux")]
+    fn test_lock_left_by_a_finished_process_is_taken_over() {
+        let path = std::env::temp_dir().join(format!("bsi_stale_lock_{}.yml", std::process::id()));
+        fs::write(sibling(&path, ".lock"), "4294967295\n").unwrap();
+
+        let lock = Lock::acquire(&path);
+
+        assert!(lock.is_ok());
+    }
+
+    /// Marks a file as being changed by this process, by creating a lock file next to it that is
+    /// removed when the lock is dropped.
+    pub struct Lock {
+        path: PathBuf
+    }
+
+    impl Lock {
+        /// Fails with `AlreadyExists`, naming the lock file, if another session holds the lock.
+        pub fn acquire(path: &Path) -> io::Result<Lock> {
+            let lock_path = sibling(path, ".lock");
+
+            match OpenOptions::new().write(true).create_new(true).open(&lock_path) {
+                Ok(mut file) => {
+                    writeln!(file, "{}", std::process::id())?;
+                    Ok(Lock { path: lock_path })
+                },
+                Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {
+                    let contents = fs::read_to_string(&lock_path).unwrap_or_default();
+                    if abandoned(contents.trim()) {
+                        fs::remove_file(&lock_path)?;
+                        return Lock::acquire(path);
+                    }
+                    let holder = match contents.trim() {
+                        "" => String::new(),
+                        process => format!(" (process {})", process)
+                    };
+                    Err(io::Error::new(io::ErrorKind::AlreadyExists, format!(
+                        "{} is in use by another session{}; remove {} if that session is no longer running",
+                        path.display(), holder, lock_path.display()
+                    )))
+                },
+                Err(error) => Err(error)
+            }
+        }
+    }
+
+    /// Whether the process that wrote a lock has exited without removing it, which can only be
+    /// told on Linux with `/proc` mounted.
+    fn abandoned(holder: &str) -> bool {
+        let proc = Path::new("/proc");
+        cfg!(target_os = "linux") && proc.join("self").exists() && holder.parse::<u32>().is_ok() && !proc.join(holder).exists()
+    }
+
+    impl Drop for Lock {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+pub mod report {
+    use csv::StringRecord;
+    use chrono::NaiveDate;
+    use sha2::{Sha256, Digest};
+    use rust_decimal::Decimal;
+    use std::str::FromStr;
+    use crate::merchant::Normaliser;
+    use crate::currency::Rates;
+
+#[test]
+    fn test_categorised_entry_instantiation() {
+        let record = StringRecord::from(vec!["  20191101  ", "  foo bar  ", "  -191.60  ", "  0.00  "]);
+        let ce = CategorisedEntry::new("personal", "bar cat", &record);
+
+        assert_eq!(ce.category_type, "personal");
+        assert_eq!(ce.category, "bar cat");
+        assert_eq!(ce.date, NaiveDate::from_ymd(2019,11,1));
+        assert_eq!(ce.description, "foo bar");
+        assert!(ce.expense);
+        assert_eq!(ce.amount, Decimal::from_str("191.6").unwrap());
+    }
+
+#[test]
+    fn test_categorised_entry_instantiation_with_income() {
+        let record = StringRecord::from(vec!["20191101", "foo bar", "191.60", "0.00"]);
+        let ce = CategorisedEntry::new("personal", "bar cat", &record);
+
+        assert!(!ce.expense);
+        assert_eq!(ce.amount, Decimal::from_str("191.6").unwrap());
+    }
+
+#[test]
+    fn test_initialising_categorised_entry_creates_fingerprint() {
+        let record = StringRecord::from(vec![" 20191101   ", "  foo bar  ", "  -191.60 ", "  0.00  "]);
+        let ce = CategorisedEntry::new("personal", "bar cat", &record);
+
+        let mut hasher = Sha256::new();
+        hasher.input("20191101foo bar-191.600.00");
+        let fingerprint = hex::encode(hasher.result());
+
+        assert_eq!(ce.record_fingerprint, fingerprint);
+    }
+
+#[test]
+    fn test_equality() {
+        assert_eq!(
+            CategorisedEntry {
+                category_type: String::from("personal"),
+                category: String::from("bar"),
+                date: NaiveDate::from_ymd(2019,11,1),
+                description: String::from("fuzzy"),
+                merchant: String::from("fuzzy"),
+                account: String::new(),
+                expense: true,
+                amount: Decimal::new(100,0),
+                currency: String::new(),
+                original_amount: Decimal::new(100,0),
+                base_currency: String::new(),
+                record_fingerprint: String::from("abc123"),
+                claim_reference: None,
+                manual: Some(false),
+                transfer: None,
+                refund_of: None
+            },
+            CategorisedEntry {
+                category_type: String::from("personal"),
+                category: String::from("bar"),
+                date: NaiveDate::from_ymd(2019,11,1),
+                description: String::from("fuzzy"),
+                merchant: String::from("fuzzy"),
+                account: String::new(),
+                expense: true,
+                amount: Decimal::new(100,0),
+                currency: String::new(),
+                original_amount: Decimal::new(100,0),
+                base_currency: String::new(),
+                record_fingerprint: String::from("abc456"),
+                claim_reference: Some(String::from("CLAIM-201911-01")),
+                manual: Some(false),
+                transfer: None,
+                refund_of: None
+            },
+            );
+
+        assert_ne!(
+            CategorisedEntry {
+                category_type: String::from("personal"),
+                category: String::from("bar"),
+                date: NaiveDate::from_ymd(2019,11,1),
+                description: String::from("fuzzy"),
+                merchant: String::from("fuzzy"),
+                account: String::new(),
+                expense: true,
+                amount: Decimal::new(100,0),
+                currency: String::new(),
+                original_amount: Decimal::new(100,0),
+                base_currency: String::new(),
+                record_fingerprint: String::from("abc123"),
+                claim_reference: None,
+                manual: Some(false),
+                transfer: None,
+                refund_of: None
+            },
+            CategorisedEntry {
+                category_type: String::from("work"),
+                category: String::from("bar"),
+                date: NaiveDate::from_ymd(2019,11,1),
+                description: String::from("fuzzy"),
+                merchant: String::from("fuzzy"),
+                account: String::new(),
+                expense: true,
+                amount: Decimal::new(100,0),
+                currency: String::new(),
+                original_amount: Decimal::new(100,0),
+                base_currency: String::new(),
+                record_fingerprint: String::from("abc123"),
+                claim_reference: None,
+                manual: Some(false),
+                transfer: None,
+                refund_of: None
+            },
+            );
+
+        assert_ne!(
+            CategorisedEntry {
+                category_type: String::from("personal"),
+                category: String::from("bar"),
+                date: NaiveDate::from_ymd(2019,11,1),
+                description: String::from("fuzzy"),
+                merchant: String::from("fuzzy"),
+                account: String::new(),
+                expense: true,
+                amount: Decimal::new(100,0),
+                currency: String::new(),
+                original_amount: Decimal::new(100,0),
+                base_currency: String::new(),
+                record_fingerprint: String::from("abc123"),
+                claim_reference: None,
+                manual: Some(false),
+                transfer: None,
+                refund_of: None
+            },
+            CategorisedEntry {
+                category_type: String::from("personal"),
+                category: String::from("baz"),
+                date: NaiveDate::from_ymd(2019,11,1),
+                description: String::from("fuzzy"),
+                merchant: String::from("fuzzy"),
+                account: String::new(),
+                expense: true,
+                amount: Decimal::new(100,0),
+                currency: String::new(),
+                original_amount: Decimal::new(100,0),
+                base_currency: String::new(),
+                record_fingerprint: String::from("abc123"),
+                claim_reference: None,
+                manual: Some(false),
+                transfer: None,
+                refund_of: None
+            },
+            );
+
+        assert_ne!(
+            CategorisedEntry {
+                category_type: String::from("personal"),
+                category: String::from("bar"),
+                date: NaiveDate::from_ymd(2019,11,2),
+                description: String::from("fuzzy"),
+                merchant: String::from("fuzzy"),
+                account: String::new(),
+                expense: true,
+                amount: Decimal::new(100,0),
+                currency: String::new(),
+                original_amount: Decimal::new(100,0),
+                base_currency: String::new(),
+                record_fingerprint: String::from("abc123"),
+                claim_reference: None,
+                manual: Some(false),
+                transfer: None,
+                refund_of: None
+            },
+            CategorisedEntry {
+                category_type: String::from("work"),
+                category: String::from("bar"),
+                date: NaiveDate::from_ymd(2019,11,1),
+                description: String::from("fuzzy"),
+                merchant: String::from("fuzzy"),
+                account: String::new(),
+                expense: true,
+                amount: Decimal::new(100,0),
+                currency: String::new(),
+                original_amount: Decimal::new(100,0),
+                base_currency: String::new(),
+                record_fingerprint: String::from("abc123"),
+                claim_reference: None,
+                manual: Some(false),
+                transfer: None,
+                refund_of: None
+            },
+            );
+
+        assert_ne!(
+            CategorisedEntry {
+                category_type: String::from("personal"),
+                category: String::from("bar"),
+                date: NaiveDate::from_ymd(2019,11,1),
+                description: String::from("fizzy"),
+                merchant: String::from("fizzy"),
+                account: String::new(),
+                expense: true,
+                amount: Decimal::new(100,0),
+                currency: String::new(),
+                original_amount: Decimal::new(100,0),
+                base_currency: String::new(),
+                record_fingerprint: String::from("abc123"),
+                claim_reference: None,
+                manual: Some(false),
+                transfer: None,
+                refund_of: None
+            },
+            CategorisedEntry {
+                category_type: String::from("work"),
+                category: String::from("bar"),
+                date: NaiveDate::from_ymd(2019,11,1),
+                description: String::from("fuzzy"),
+                merchant: String::from("fuzzy"),
+                account: String::new(),
+                expense: true,
+                amount: Decimal::new(100,0),
+                currency: String::new(),
+                original_amount: Decimal::new(100,0),
+                base_currency: String::new(),
+                record_fingerprint: String::from("abc123"),
+                claim_reference: None,
+                manual: Some(false),
+                transfer: None,
+                refund_of: None
+            },
+            );
+
+        assert_ne!(
+            CategorisedEntry {
+                category_type: String::from("personal"),
+                category: String::from("bar"),
+                date: NaiveDate::from_ymd(2019,11,1),
+                description: String::from("fuzzy"),
+                merchant: String::from("fuzzy"),
+                account: String::new(),
+                expense: false,
+                amount: Decimal::new(100,0),
+                currency: String::new(),
+                original_amount: Decimal::new(100,0),
+                base_currency: String::new(),
+                record_fingerprint: String::from("abc123"),
+                claim_reference: None,
+                manual: Some(false),
+                transfer: None,
+                refund_of: None
+            },
+            CategorisedEntry {
+                category_type: String::from("work"),
+                category: String::from("bar"),
+                date: NaiveDate::from_ymd(2019,11,1),
+                description: String::from("fuzzy"),
+                merchant: String::from("fuzzy"),
+                account: String::new(),
+                expense: true,
+                amount: Decimal::new(100,0),
+                currency: String::new(),
+                original_amount: Decimal::new(100,0),
+                base_currency: String::new(),
+                record_fingerprint: String::from("abc123"),
+                claim_reference: None,
+                manual: Some(false),
+                transfer: None,
+                refund_of: None
+            },
+            );
+
+        assert_ne!(
+            CategorisedEntry {
+                category_type: String::from("personal"),
+                category: String::from("bar"),
+                date: NaiveDate::from_ymd(2019,11,1),
+                description: String::from("fuzzy"),
+                merchant: String::from("fuzzy"),
+                account: String::new(),
+                expense: true,
+                amount: Decimal::new(100,0),
+                currency: String::new(),
+                original_amount: Decimal::new(100,0),
+                base_currency: String::new(),
+                record_fingerprint: String::from("abc123"),
+                claim_reference: None,
+                manual: Some(false),
+                transfer: None,
+                refund_of: None
+            },
+            CategorisedEntry {
+                category_type: String::from("work"),
+                category: String::from("bar"),
+                date: NaiveDate::from_ymd(2019,11,1),
+                description: String::from("fuzzy"),
+                merchant: String::from("fuzzy"),
+                account: String::new(),
+                expense: true,
+                amount: Decimal::new(100,0),
+                currency: String::new(),
+                original_amount: Decimal::new(100,0),
+                base_currency: String::new(),
+                record_fingerprint: String::from("abc123"),
+                claim_reference: None,
+                manual: Some(false),
+                transfer: None,
+                refund_of: None
+            },
+            );
+    }
+
+    /// The field statement records carry their account name in, after date, description, amount
+    /// and balance.
+    pub const ACCOUNT: usize = 4;
+
+    /// The field statement records carry their currency in, after the account.
+    pub const CURRENCY: usize = 5;
+
+    /// The category type of transfers between accounts, whose category is the other account.
+    pub const TRANSFER: &str = "transfer";
+
+#[derive(Debug, Clone)]
+    pub struct CategorisedEntry {
+        pub category_type: String,
+        pub category: String,
+        pub date: NaiveDate,
+        pub description: String,
+        /// The description as cleaned by the configured normaliser, or its canonical merchant.
+        pub merchant: String,
+        /// The account whose statement the entry came from, or empty when no account matched.
+        pub account: String,
+        pub expense: bool,
+        /// The amount in the report's base currency, which totals are made of.
+        pub amount: Decimal,
+        /// The currency of the original amount, or empty when it is not known.
+        pub currency: String,
+        pub original_amount: Decimal,
+        /// The currency `amount` is in, or empty when it is not known.
+        pub base_currency: String,
+        pub record_fingerprint: String,
+        pub claim_reference: Option<String>,
+        /// Whether the user chose the category rather than a pattern, or `None` for entries
+        /// recorded before that was noted.
+        pub manual: Option<bool>,
+        /// The fingerprint of the other side of a transfer between accounts.
+        pub transfer: Option<String>,
+        /// The fingerprint of the debit this credit refunds in full or in part.
+        pub refund_of: Option<String>
+    }
+
+    impl CategorisedEntry {
+        fn new(category_type: &str, category: &str, original_record: &StringRecord) -> CategorisedEntry {
+
+            let signed = original_record.get(2).unwrap().trim().parse::<f32>().unwrap();
+            let amount = Decimal::from_str(&format!("{}", signed.abs())).unwrap();
+            let currency = String::from(original_record.get(CURRENCY).unwrap_or("").trim());
+
+            CategorisedEntry {
+                category_type: String::from(category_type),
+                category: String::from(category),
+                date: NaiveDate::parse_from_str(original_record.get(0).unwrap().trim(), "%Y%m%d").unwrap(),
+                description: String::from(original_record.get(1).unwrap().trim()),
+                merchant: String::from(original_record.get(1).unwrap().trim()),
+                account: String::from(original_record.get(ACCOUNT).unwrap_or("").trim()),
+                expense: signed < 0.0,
+                amount,
+                base_currency: currency.clone(),
+                currency,
+                original_amount: amount,
+                record_fingerprint: fingerprint(original_record),
+                claim_reference: None,
+                manual: Some(false),
+                transfer: None,
+                refund_of: None
+            }
+        }
+    }
+
+    fn fingerprint(original_record: &StringRecord) -> String {
+        let mut hasher = Sha256::new();
+        hasher.input(original_record.get(0).unwrap().trim());
+        hasher.input(original_record.get(1).unwrap().trim());
+        hasher.input(original_record.get(2).unwrap().trim());
+        hasher.input(original_record.get(3).unwrap().trim());
+        hex::encode(hasher.result())
+    }
+
+    /// Words that say how money moved rather than who it moved to or from.
+    const GENERIC_WORDS: [&str; 16] = ["POS", "CARD", "PURCHASE", "PAYMENT", "DEBIT", "CREDIT", "ONLINE", "ORDER", "TRANSACTION", "FROM", "TO", "REFUND", "REVERSAL", "REVERSED", "RETURN", "RETURNED"];
+
+    /// Words in a description that mark a credit as money given back.
+    const REFUND_MARKERS: [&str; 5] = ["REFUND", "REVERSAL", "REVERSED", "RETURN", "RETURNED"];
+
+    fn words(text: &str) -> Vec<String> {
+        text.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()).map(|w| w.to_uppercase()).collect()
+    }
+
+    /// The words that name a merchant, leaving out generic ones and references with digits.
+    fn merchant_words(merchant: &str) -> Vec<String> {
+        words(merchant).into_iter()
+            .filter(|w| !GENERIC_WORDS.contains(&w.as_str()) && !w.chars().any(|c| c.is_ascii_digit()))
+            .collect()
+    }
+
+    /// Whether two merchants are taken to be the same, which they are when the words naming them
+    /// agree as far as the shorter goes, so that "OUTFITTERS CAPE TOWN" is refunded by
+    /// "POS OUTFITTERS REFUND" but "CARD PURCHASE CAFE" is not refunded by "CARD REFUND SHOP".
+    fn same_merchant(a: &str, b: &str) -> bool {
+        let (a, b) = (merchant_words(a), merchant_words(b));
+        let shorter = a.len().min(b.len());
+        shorter > 0 && a[..shorter] == b[..shorter]
+    }
+
+    /// Whether `description` says it is a refund or reversal.
+    pub fn marked_as_refund(description: &str) -> bool {
+        words(description).iter().any(|w| REFUND_MARKERS.contains(&w.as_str()))
+    }
+
+    impl PartialEq for CategorisedEntry {
+        fn eq(&self, other: &Self) -> bool {
+            self.category_type == other.category_type &&
+                self.category == other.category &&
+                self.date == other.date &&
+                self.description == other.description &&
+                self.expense == other.expense && 
+                self.amount == other.amount
+        }
+    }
+
+#[test]
+    fn test_money() {
+        assert_eq!(money(Decimal::from_str("191.6").unwrap()), "191.60");
+        assert_eq!(money(Decimal::from_str("-0.126").unwrap()), "-0.13");
+        assert_eq!(money(Decimal::new(0, 0)), "0.00");
+    }
+
+    pub fn money(amount: Decimal) -> String {
+        format!("{:.2}", amount.round_dp(2))
+    }
+
+#[test]
+    fn test_activity_report_creation() {
+        let report = ActivityReport::new();
+
+        assert!(report.entries.is_empty())
+    }
+
+#[test]
+    fn test_add_entry_to_activity_report() {
+        let mut report = ActivityReport::new();
+        let record = StringRecord::from(vec!["20191101", "foo bar", "-191.60", "0.00"]);
+        let expected_entry = CategorisedEntry::new("personal", "bar cat", &record);
+
+        report.add_entry("personal", "bar cat", &record);
+
+        assert_eq!(report.entries, vec![expected_entry]);
+    }
+
+#[test]
+    fn test_do_not_add_entries_that_map_to_the_same_record() {
+        let mut report = ActivityReport::new();
+        let record_1 = StringRecord::from(vec!["20191101", "foo bar", "-191.60", "0.00"]);
+        let expected_entry_1 = CategorisedEntry::new("personal", "bar cat", &record_1);
+        let record_2 = StringRecord::from(vec!["20191102", "fuzzy wuzzy", "111.11", "0.00"]);
+        let expected_entry_2 = CategorisedEntry::new("work", "baz cat", &record_2);
+
+        report.add_entry("personal", "bar cat", &record_1);
+        report.add_entry("work", "baz cat", &record_2);
+        report.add_entry("work", "foo cat", &record_1);
+
+        assert_eq!(report.entries, vec![expected_entry_1, expected_entry_2]);
+    }
+
+#[test]
+    fn test_remove_record() {
+        let mut report = ActivityReport::new();
+        let record_1 = StringRecord::from(vec!["20191101", "foo bar", "-191.60", "0.00"]);
+        let record_2 = StringRecord::from(vec!["20191102", "fuzzy wuzzy", "111.11", "0.00"]);
+        let expected_entry = CategorisedEntry::new("work", "baz cat", &record_2);
+
+        assert!(report.add_entry("personal", "bar cat", &record_1));
+        assert!(report.add_entry("work", "baz cat", &record_2));
+        assert!(!report.add_entry("work", "foo cat", &record_1));
+        report.remove_record(&record_1);
+
+        assert_eq!(report.entries, vec![expected_entry]);
+    }
+
+#[test]
+    fn test_total_entries() {
+        let mut report = ActivityReport::new();
+        let personal_expense_1 = StringRecord::from(vec!["20191101", "personal_expense_one", "-191.60", "0.00"]);
+        let personal_expense_2 = StringRecord::from(vec!["20191101", "personal_expense_two", "-150.30", "0.00"]);
+        let personal_income_1 = StringRecord::from(vec!["20191101", "personal_income_one", "500.30", "0.00"]);
+        let personal_income_2 = StringRecord::from(vec!["20191101", "personal_income_two", "1000.90", "0.00"]);
+        let work_expense_1 = StringRecord::from(vec!["20191101", "work_expense_one", "-300.10", "0.00"]);
+        let work_expense_2 = StringRecord::from(vec!["20191101", "work_expense_two", "-400.70", "0.00"]);
+
+        report.add_entry("personal", "bar cat", &personal_expense_1);
+        report.add_entry("personal", "bar cat", &personal_expense_2);
+        report.add_entry("personal", "bar cat", &personal_income_1);
+        report.add_entry("personal", "bar cat", &personal_income_2);
+        report.add_entry("work", "bar cat", &work_expense_1);
+        report.add_entry("work", "bar cat", &work_expense_2);
+
+        assert_eq!(report.total("work", true), Decimal::from_str("700.8").unwrap());
+        assert_eq!(report.total("personal", true), Decimal::from_str("341.9").unwrap());
+        assert_eq!(report.total("personal", false), Decimal::from_str("1501.2").unwrap());
+    }
+
+#[test]
+    fn test_category_totals() {
+        let mut report = ActivityReport::new();
+        report.add_entry("work", "travel", &StringRecord::from(vec!["20191101", "flight", "-300.00", "0.00"]));
+        report.add_entry("personal", "groceries", &StringRecord::from(vec!["20191101", "shop one", "-50.00", "0.00"]));
+        report.add_entry("personal", "eating out", &StringRecord::from(vec!["20191101", "restaurant", "-80.00", "0.00"]));
+        report.add_entry("personal", "groceries", &StringRecord::from(vec!["20191102", "shop two", "-40.00", "0.00"]));
+        report.add_entry("personal", "salary", &StringRecord::from(vec!["20191125", "employer", "5000.00", "0.00"]));
+
+        assert_eq!(report.category_types(), vec!["personal", "work"]);
+        assert_eq!(
+            report.category_totals("personal", true),
+            vec![
+                (String::from("groceries"), Decimal::from_str("90").unwrap()),
+                (String::from("eating out"), Decimal::from_str("80").unwrap()),
+            ]);
+        assert_eq!(report.category_totals("personal", false), vec![(String::from("salary"), Decimal::from_str("5000").unwrap())]);
+    }
+
+#[test]
+    fn test_grouping_by_account() {
+        let mut report = ActivityReport::new();
+        report.add_entry("personal", "groceries", &StringRecord::from(vec!["20191101", "shop", "-10.00", "0.00", "everyday"]));
+        report.add_entry("personal", "fuel", &StringRecord::from(vec!["20191102", "garage", "-20.00", "0.00", "card"]));
+        report.add_entry("personal", "salary", &StringRecord::from(vec!["20191125", "employer", "500.00", "0.00", "everyday"]));
+        report.add_entry("personal", "groceries", &StringRecord::from(vec!["20191103", "market", "-5.00", "0.00"]));
+
+        assert_eq!(report.entries()[0].account, "everyday");
+        assert_eq!(report.entries()[3].account, "");
+        assert_eq!(report.accounts(), vec!["card", "everyday"]);
+        assert_eq!(report.account_total("everyday", true), Decimal::new(10, 0));
+        assert_eq!(report.account_total("everyday", false), Decimal::new(500, 0));
+        assert_eq!(report.for_account("card").total("personal", true), Decimal::new(20, 0));
+    }
+
+#[test]
+    fn test_transfers_are_left_out_of_totals() {
+        let mut report = ActivityReport::new();
+        report.add_entry("personal", "groceries", &StringRecord::from(vec!["20191101", "shop", "-10.00", "0.00", "card"]));
+        let debit = StringRecord::from(vec!["20191102", "CARD PAYMENT", "-500.00", "0.00", "everyday"]);
+        let credit = StringRecord::from(vec!["20191103", "PAYMENT RECEIVED", "500.00", "0.00", "card"]);
+
+        assert!(report.add_transfer(&debit, &credit));
+        assert!(!report.add_transfer(&debit, &credit));
+
+        assert_eq!(report.entries().len(), 3);
+        assert_eq!(report.entries()[1].category, "card");
+        assert_eq!(report.entries()[2].category, "everyday");
+        assert_eq!(report.entries()[1].transfer, Some(report.entries()[2].record_fingerprint.clone()));
+        assert_eq!(report.category_types(), vec!["personal"]);
+        assert_eq!(report.total(TRANSFER, true), Decimal::new(0, 0));
+        assert_eq!(report.account_total("card", false), Decimal::new(0, 0));
+        assert_eq!(report.account_total("card", true), Decimal::new(10, 0));
+        assert_eq!(report.accounts(), vec!["card"]);
+        assert_eq!(report.transfers().len(), 1);
+    }
+
+#[test]
+    fn test_refunds_reduce_the_expense_of_their_category() {
+        let mut report = ActivityReport::new();
+        report.add_entry("personal", "clothing", &StringRecord::from(vec!["20191101", "OUTFITTERS CAPE TOWN", "-100.00", "0.00", "card"]));
+        report.add_entry("personal", "groceries", &StringRecord::from(vec!["20191102", "TESCO", "-50.00", "0.00", "card"]));
+        report.add_entry("personal", "salary", &StringRecord::from(vec!["20191125", "EMPLOYER", "500.00", "0.00", "card"]));
+        let refund = StringRecord::from(vec!["20191110", "OUTFITTERS REFUND", "40.00", "0.00", "card"]);
+
+        let original = report.refunded_entry(&report.entry("", "", &refund), &[]).unwrap().clone();
+        assert_eq!(original.description, "OUTFITTERS CAPE TOWN");
+        assert!(report.refunded_entry(&report.entries()[2], &[]).is_none());
+        assert!(report.add_refund(&refund, &original));
+        assert!(!report.add_refund(&refund, &original));
+
+        assert_eq!(report.entries()[3].category, "clothing");
+        assert_eq!(report.entries()[3].refund_of, Some(original.record_fingerprint.clone()));
+        assert_eq!(report.total("personal", true), Decimal::new(110, 0));
+        assert_eq!(report.total("personal", false), Decimal::new(500, 0));
+        assert_eq!(
+            report.category_totals("personal", true),
+            vec![(String::from("clothing"), Decimal::new(60, 0)), (String::from("groceries"), Decimal::new(50, 0))]);
+        assert_eq!(report.account_total("card", true), Decimal::new(110, 0));
+        assert_eq!(report.refunds().len(), 1);
+
+        report.count_refunds_as_income();
+        assert_eq!(report.total("personal", true), Decimal::new(150, 0));
+        assert_eq!(report.total("personal", false), Decimal::new(540, 0));
+    }
+
+#[test]
+    fn test_refunds_of_earlier_debits_are_kept_apart_from_expense() {
+        let mut earlier = ActivityReport::new();
+        earlier.add_entry("personal", "clothing", &StringRecord::from(vec!["20191025", "OUTFITTERS", "-100.00", "0.00", "card"]));
+        let mut report = ActivityReport::new();
+        report.add_entry("personal", "groceries", &StringRecord::from(vec!["20191102", "TESCO", "-10.00", "0.00", "card"]));
+        report.add_refund(&StringRecord::from(vec!["20191105", "OUTFITTERS REFUND", "40.00", "0.00", "card"]), &earlier.entries()[0]);
+
+        assert_eq!(report.total("personal", true), Decimal::new(10, 0));
+        assert_eq!(report.category_totals("personal", true), vec![(String::from("groceries"), Decimal::new(10, 0))]);
+        assert_eq!(report.account_total("card", true), Decimal::new(10, 0));
+        assert_eq!(report.earlier_refunds("personal"), Decimal::new(40, 0));
+
+        report.count_refunds_as_income();
+        assert_eq!(report.total("personal", false), Decimal::new(40, 0));
+        assert_eq!(report.earlier_refunds("personal"), Decimal::new(0, 0));
+    }
+
+#[test]
+    fn test_refunds_need_more_than_a_shared_first_word() {
+        assert!(same_merchant("OUTFITTERS CAPE TOWN", "POS OUTFITTERS REFUND"));
+        assert!(same_merchant("Outfitters", "OUTFITTERS 1234"));
+        assert!(!same_merchant("CARD PURCHASE CAFE", "CARD REFUND SHOP"));
+        assert!(!same_merchant("POS CAPE UNION MART", "POS CAPE TOWN"));
+        assert!(!same_merchant("POS 1234", "POS REFUND"));
+        assert!(marked_as_refund("OUTFITTERS REFUND"));
+        assert!(marked_as_refund("reversal: outfitters"));
+        assert!(!marked_as_refund("REFUNDABLE DEPOSIT"));
+    }
+
+#[test]
+    fn test_refunds_only_match_what_is_left_of_an_earlier_debit() {
+        let mut earlier = ActivityReport::new();
+        earlier.add_entry("personal", "clothing", &StringRecord::from(vec!["20191020", "OUTFITTERS", "-100.00", "0.00"]));
+        earlier.add_entry("personal", "clothing", &StringRecord::from(vec!["20191028", "OUTFITTERS", "-60.00", "0.00"]));
+        earlier.add_entry("personal", "clothing", &StringRecord::from(vec!["20191030", "OUTFITTERS", "-30.00", "0.00", "", "USD"]));
+        let history = earlier.entries().to_vec();
+
+        let mut report = ActivityReport::new();
+        let credits: Vec<StringRecord> = vec![("20191105", "60.00"), ("20191106", "50.00"), ("20191107", "60.00"), ("20191019", "10.00")].into_iter()
+            .map(|(date, amount)| StringRecord::from(vec![date, "OUTFITTERS", amount, "0.00"]))
+            .collect();
+
+        let exact = report.refunded_entry(&report.entry("", "", &credits[0]), &history).unwrap().clone();
+        assert_eq!(exact.amount, Decimal::new(60, 0));
+        report.add_refund(&credits[0], &exact);
+
+        let partial = report.refunded_entry(&report.entry("", "", &credits[1]), &history).unwrap().clone();
+        assert_eq!(partial.amount, Decimal::new(100, 0));
+        report.add_refund(&credits[1], &partial);
+
+        assert!(report.refunded_entry(&report.entry("", "", &credits[2]), &history).is_none());
+        assert!(report.refunded_entry(&report.entry("", "", &credits[3]), &history).is_none());
+    }
+
+#[test]
+    fn test_totals_are_converted_to_the_base_currency() {
+        let mut rates = Rates::new();
+        rates.add("USD", "ZAR", NaiveDate::from_ymd(2019,11,1), Decimal::new(15, 0));
+        let mut report = ActivityReport::new();
+        report.add_entry("personal", "travel", &StringRecord::from(vec!["20191101", "hotel", "-100.00", "0.00", "card", "USD"]));
+        report.convert_to("ZAR", rates);
+        report.add_entry("personal", "travel", &StringRecord::from(vec!["20191102", "taxi", "-10.00", "0.00", "card", "usd"]));
+        report.add_entry("personal", "groceries", &StringRecord::from(vec!["20191103", "shop", "-50.00", "0.00", "everyday", "ZAR"]));
+        report.add_entry("personal", "groceries", &StringRecord::from(vec!["20191104", "market", "-5.00", "0.00"]));
+
+        assert_eq!(report.base_currency(), Some("ZAR"));
+        assert_eq!(report.entries()[0].amount, Decimal::new(1500, 0));
+        assert_eq!(report.entries()[0].original_amount, Decimal::new(100, 0));
+        assert_eq!(report.entries()[0].currency, "USD");
+        assert_eq!(report.entries()[0].base_currency, "ZAR");
+        assert_eq!(report.entries()[1].amount, Decimal::new(150, 0));
+        assert_eq!(report.entries()[3].base_currency, "");
+        assert_eq!(report.total("personal", true), Decimal::new(1705, 0));
+    }
+
+#[test]
+    fn test_rank_categories() {
+        let mut report = ActivityReport::new();
+        report.add_entry("personal", "groceries", &StringRecord::from(vec!["20191101", "shop one", "-50.00", "0.00"]));
+        report.add_entry("personal", "groceries", &StringRecord::from(vec!["20191102", "shop two", "-40.00", "0.00"]));
+        report.add_entry("personal", "fuel", &StringRecord::from(vec!["20191103", "garage one", "-80.00", "0.00"]));
+        report.add_entry("personal", "fuel", &StringRecord::from(vec!["20191104", "garage two", "-80.00", "0.00"]));
+        report.add_entry("personal", "fuel", &StringRecord::from(vec!["20191105", "garage three", "-80.00", "0.00"]));
+        report.add_entry("personal", "books", &StringRecord::from(vec!["20191106", "bookshop", "-20.00", "0.00"]));
+        report.add_entry("work", "travel", &StringRecord::from(vec!["20191107", "airline", "-300.00", "0.00"]));
+
+        let categories: Vec<String> = vec!["books", "clothing", "fuel", "garden", "groceries"].into_iter().map(String::from).collect();
+
+        assert_eq!(
+            rank_categories(&categories, "personal", report.entries(), 1),
+            vec!["books", "fuel", "groceries", "clothing", "garden"]);
+    }
+
+    /// Orders `categories` so that the `recent` most recently used come first, followed by the rest
+    /// by how often they have been used, and then any that have never been used.
+    pub fn rank_categories<'a, I>(categories: &[String], category_type: &str, entries: I, recent: usize) -> Vec<String>
+        where I: IntoIterator<Item = &'a CategorisedEntry> {
+        let mut usage: Vec<(String, usize, NaiveDate)> = Vec::new();
+
+        for entry in entries.into_iter().filter(|e| e.category_type == category_type && categories.contains(&e.category)) {
+            match usage.iter_mut().find(|(category, _, _)| *category == entry.category) {
+                Some((_, count, last_used)) => {
+                    *count += 1;
+                    *last_used = (*last_used).max(entry.date);
+                },
+                None => usage.push((entry.category.clone(), 1, entry.date))
+            }
+        }
+
+        usage.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+        let mut ranked: Vec<String> = usage.iter().take(recent).map(|(category, _, _)| category.clone()).collect();
+
+        let mut frequent: Vec<&(String, usize, NaiveDate)> = usage.iter().skip(recent).collect();
+        frequent.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        ranked.extend(frequent.into_iter().map(|(category, _, _)| category.clone()));
+
+        let mut unused: Vec<String> = categories.iter().filter(|c| !ranked.contains(c)).cloned().collect();
+        unused.sort();
+        ranked.extend(unused);
+        ranked
+    }
+
+    pub struct ActivityReport {
+        entries: Vec<CategorisedEntry>,
+        normaliser: Normaliser,
+        /// The base currency amounts are reported in, and the rates to convert to it.
+        conversion: Option<(String, Rates)>,
+        /// Whether refunds count as income rather than reducing the expense of their category.
+        refunds_as_income: bool
+    }
+
+    impl Default for ActivityReport {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl ActivityReport {
+        pub fn new() -> ActivityReport {
+            ActivityReport { entries: Vec::new(), normaliser: Normaliser::default(), conversion: None, refunds_as_income: false }
+        }
+
+        /// A report that works out the merchant of each entry added with `normaliser`.
+        pub fn with_normaliser(normaliser: Normaliser) -> ActivityReport {
+            ActivityReport { entries: Vec::new(), normaliser, conversion: None, refunds_as_income: false }
+        }
+
+        pub fn from_entries(entries: Vec<CategorisedEntry>) -> ActivityReport {
+            let mut report = ActivityReport::new();
+            for entry in entries {
+                if !report.record_present(&entry) {
+                    report.entries.push(entry);
+                }
+            }
+            report
+        }
+
+        /// Reports amounts in `base` from now on, converting entries in other currencies with
+        /// `rates`. Entries with no currency, or no rate, keep their original amount and
+        /// currency, so callers should check for them first.
+        pub fn convert_to(&mut self, base: &str, rates: Rates) {
+            self.conversion = Some((base.to_uppercase(), rates));
+            let mut entries = std::mem::take(&mut self.entries);
+            for entry in entries.iter_mut() {
+                self.convert(entry);
+            }
+            self.entries = entries;
+        }
+
+        pub fn base_currency(&self) -> Option<&str> {
+            self.conversion.as_ref().map(|(base, _)| base.as_str())
+        }
+
+        fn convert(&self, entry: &mut CategorisedEntry) {
+            if let Some((base, rates)) = &self.conversion {
+                match rates.convert(entry.original_amount, &entry.currency, base, entry.date).filter(|_| !entry.currency.is_empty()) {
+                    Some(amount) => {
+                        entry.amount = amount;
+                        entry.base_currency = base.clone();
+                    },
+                    None => {
+                        entry.amount = entry.original_amount;
+                        entry.base_currency = entry.currency.clone();
+                    }
+                }
+            }
+        }
+
+        /// The entry `original_record` would be added as, without adding it.
+        pub fn entry(&self, category_type: &str, category: &str, original_record: &StringRecord) -> CategorisedEntry {
+            let mut entry = CategorisedEntry::new(category_type, category, original_record);
+            entry.merchant = self.normaliser.merchant(&entry.description);
+            self.convert(&mut entry);
+            entry
+        }
+
+        /// Returns whether the entry was added, which it is not if its record is already present.
+        pub fn add_entry(&mut self, category_type: &str, category: &str, original_record: &StringRecord) -> bool {
+            let entry = self.entry(category_type, category, original_record);
+
+            if !self.record_present(&entry) {
+                self.entries.push(entry);
+                true
+            } else {
+                false
+            }
+        }
+
+        /// Adds an entry whose category the user chose, which recategorising leaves alone.
+        pub fn add_manual_entry(&mut self, category_type: &str, category: &str, original_record: &StringRecord) -> bool {
+            let added = self.add_entry(category_type, category, original_record);
+            if added {
+                if let Some(entry) = self.entries.last_mut() {
+                    entry.manual = Some(true);
+                }
+            }
+            added
+        }
+
+        /// Adds both sides of a transfer between accounts, which are left out of the totals. Returns
+        /// whether they were added, which they are not if either record is already present.
+        pub fn add_transfer(&mut self, debit: &StringRecord, credit: &StringRecord) -> bool {
+            let mut from = self.entry(TRANSFER, credit.get(ACCOUNT).unwrap_or("").trim(), debit);
+            let mut to = self.entry(TRANSFER, debit.get(ACCOUNT).unwrap_or("").trim(), credit);
+            if self.record_present(&from) || self.record_present(&to) {
+                return false;
+            }
+
+            from.transfer = Some(to.record_fingerprint.clone());
+            to.transfer = Some(from.record_fingerprint.clone());
+            self.entries.push(from);
+            self.entries.push(to);
+            true
+        }
+
+        /// The money leaving an account in each transfer.
+        pub fn transfers(&self) -> Vec<&CategorisedEntry> {
+            self.entries.iter().filter(|e| e.transfer.is_some() && e.expense).collect()
+        }
+
+        /// The debit that `credit` refunds in full or in part: an earlier one from the same
+        /// merchant and in the same currency, in the report or in `earlier`, such as history or
+        /// debits still to be classified, with at least the credit's amount not yet refunded. An
+        /// exact match is preferred, then the latest.
+        pub fn refunded_entry<'a>(&'a self, credit: &CategorisedEntry, earlier: &'a [CategorisedEntry]) -> Option<&'a CategorisedEntry> {
+            let entries: Vec<&CategorisedEntry> = earlier.iter().filter(|e| !self.record_present(e)).chain(self.entries.iter()).collect();
+            let outstanding = |original: &CategorisedEntry| -> Decimal {
+                original.original_amount - entries.iter()
+                    .filter(|e| e.refund_of.as_ref() == Some(&original.record_fingerprint))
+                    .map(|e| e.original_amount)
+                    .sum::<Decimal>()
+            };
+
+            let mut candidates: Vec<&CategorisedEntry> = entries.iter().cloned()
+                .filter(|e| e.expense && e.transfer.is_none() && e.refund_of.is_none())
+                .filter(|e| e.date <= credit.date && e.currency.eq_ignore_ascii_case(&credit.currency))
+                .filter(|e| same_merchant(&e.merchant, &credit.merchant))
+                .filter(|e| outstanding(e) >= credit.original_amount)
+                .collect();
+            candidates.sort_by(|a, b| {
+                (b.original_amount == credit.original_amount).cmp(&(a.original_amount == credit.original_amount))
+                    .then_with(|| b.date.cmp(&a.date))
+            });
+            candidates.first().cloned()
+        }
+
+        /// Adds the credit in `original_record` as a refund of the debit `original`, in that debit's
+        /// category. Returns whether it was added, which it is not if its record is already present.
+        pub fn add_refund(&mut self, original_record: &StringRecord, original: &CategorisedEntry) -> bool {
+            let mut entry = self.entry(&original.category_type, &original.category, original_record);
+            entry.refund_of = Some(original.record_fingerprint.clone());
+
+            if !self.record_present(&entry) {
+                self.entries.push(entry);
+                true
+            } else {
+                false
+            }
+        }
+
+        /// The credits linked to a debit they refund.
+        pub fn refunds(&self) -> Vec<&CategorisedEntry> {
+            self.entries.iter().filter(|e| e.refund_of.is_some()).collect()
+        }
+
+        /// Whether `entry` refunds a debit from before the report.
+        fn refunds_earlier(&self, entry: &CategorisedEntry) -> bool {
+            entry.refund_of.as_ref().is_some_and(|debit| !self.entries.iter().any(|e| &e.record_fingerprint == debit))
+        }
+
+        /// What was refunded of `category_type` debits from before the report, which is left out
+        /// of its totals unless refunds count as income.
+        pub fn earlier_refunds(&self, category_type: &str) -> Decimal {
+            if self.refunds_as_income {
+                return Decimal::new(0, 0);
+            }
+            self.entries.iter().filter(|e| e.category_type == category_type && self.refunds_earlier(e)).map(|e| e.amount).sum()
+        }
+
+        /// Counts refunds as income from now on, as they were before they were linked to the
+        /// debits they refund.
+        pub fn count_refunds_as_income(&mut self) {
+            self.refunds_as_income = true;
+        }
+
+        /// What `entry` adds to the expense or income totals: nothing for transfers, and less
+        /// expense for refunds unless they count as income.
+        fn contribution(&self, entry: &CategorisedEntry, expense: bool) -> Option<Decimal> {
+            if entry.transfer.is_some() {
+                None
+            } else if entry.refund_of.is_some() && !self.refunds_as_income {
+                // Refunds of debits from before the report are shown apart, as the expense they
+                // would come off is not in it
+                if expense && !self.refunds_earlier(entry) { Some(-entry.amount) } else { None }
+            } else if entry.expense == expense {
+                Some(entry.amount)
+            } else {
+                None
+            }
+        }
+
+        pub fn remove_record(&mut self, original_record: &StringRecord) {
+            let fingerprint = fingerprint(original_record);
+            self.entries.retain(|e| e.record_fingerprint != fingerprint);
+        }
+
+        pub fn entries(&self) -> &[CategorisedEntry] {
+            &self.entries
+        }
+
+        pub fn total(&self, category_type: &str, expense: bool) -> Decimal {
+            self.entries.iter().filter(|x| x.category_type == category_type).filter_map(|x| self.contribution(x, expense)).sum()
+        }
+
+        /// The types of the entries, leaving out transfers.
+        pub fn category_types(&self) -> Vec<String> {
+            let mut types: Vec<String> = Vec::new();
+            for entry in self.entries.iter().filter(|e| e.transfer.is_none()) {
+                if !types.contains(&entry.category_type) {
+                    types.push(entry.category_type.clone());
+                }
+            }
+            types.sort();
+            types
+        }
+
+        pub fn category_totals(&self, category_type: &str, expense: bool) -> Vec<(String, Decimal)> {
+            let mut totals: Vec<(String, Decimal)> = Vec::new();
+            for entry in self.entries.iter().filter(|x| x.category_type == category_type) {
+                if let Some(contribution) = self.contribution(entry, expense) {
+                    match totals.iter_mut().find(|(category, _)| *category == entry.category) {
+                        Some((_, amount)) => *amount += contribution,
+                        None => totals.push((entry.category.clone(), contribution))
+                    }
+                }
+            }
+            totals.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            totals
+        }
+
+        /// The accounts entries came from, leaving out transfers and entries with no account.
+        pub fn accounts(&self) -> Vec<String> {
+            let mut accounts: Vec<String> = self.entries.iter().filter(|e| e.transfer.is_none()).map(|e| e.account.clone()).filter(|a| !a.is_empty()).collect();
+            accounts.sort();
+            accounts.dedup();
+            accounts
+        }
+
+        pub fn account_total(&self, account: &str, expense: bool) -> Decimal {
+            self.entries.iter().filter(|x| x.account == account).filter_map(|x| self.contribution(x, expense)).sum()
+        }
+
+        /// A report of only the entries from `account`.
+        pub fn for_account(&self, account: &str) -> ActivityReport {
+            ActivityReport {
+                entries: self.entries.iter().filter(|e| e.account == account).cloned().collect(),
+                normaliser: self.normaliser.clone(),
+                conversion: self.conversion.clone(),
+                refunds_as_income: self.refunds_as_income
+            }
+        }
+
+        fn record_present(&self, entry: &CategorisedEntry) -> bool {
+            self.entries.iter().any(|e| e.record_fingerprint == entry.record_fingerprint)
+        }
+
+    }
+}
+
+pub mod history {
+    use std::io;
+    use std::path::Path;
+    use csv::{Reader, StringRecord, Writer};
+    use chrono::NaiveDate;
+    use rust_decimal::Decimal;
+    use std::str::FromStr;
+    use crate::report::{ActivityReport, CategorisedEntry};
+    use crate::store;
+
+    const COLUMNS: [&str; 16] = ["category_type", "category", "date", "description", "merchant", "account", "expense", "amount", "currency", "original_amount", "base_currency", "record_fingerprint", "claim_reference", "manual", "transfer", "refund_of"];
+
+#[test]
+    fn test_recording_a_report_skips_entries_already_in_history() {
+        let mut first = ActivityReport::new();
+        first.add_entry("personal", "groceries", &StringRecord::from(vec!["20191101", "foo", "-10.00", "0.00"]));
+        let mut second = ActivityReport::new();
+        second.add_entry("personal", "groceries", &StringRecord::from(vec!["20191101", "foo", "-10.00", "0.00"]));
+        second.add_entry("work", "travel", &StringRecord::from(vec!["20191102", "bar", "-20.00", "0.00"]));
+
+        let mut history = History::new();
+        history.record(&first);
+        history.record(&second);
+
+        assert_eq!(history.entries().len(), 2);
+        assert_eq!(history.entries()[1].category, "travel");
+    }
+
+#[test]
+    fn test_history_round_trips_through_csv() {
+        let mut report = ActivityReport::new();
+        report.add_entry("personal", "groceries", &StringRecord::from(vec!["20191101", "foo, bar", "-10.50", "0.00"]));
+        report.add_entry("work", "consulting", &StringRecord::from(vec!["20191102", "baz", "1000.00", "0.00"]));
+        let original = report.entries()[0].clone();
+        report.add_refund(&StringRecord::from(vec!["20191103", "foo, bar", "2.50", "0.00"]), &original);
+        let mut history = History::new();
+        history.record(&report);
+        let fingerprint = history.entries()[1].record_fingerprint.clone();
+        history.mark_claimed(&[fingerprint], "CLAIM-201911-01");
+
+        let path = std::env::temp_dir().join(format!("bsi_history_round_trip_{}.csv", std::process::id()));
+        history.save(&path).unwrap();
+        let loaded = History::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(loaded.entries(), history.entries());
+        assert_eq!(loaded.entries()[0].record_fingerprint, history.entries()[0].record_fingerprint);
+        assert_eq!(loaded.entries()[0].merchant, "foo, bar");
+        assert_eq!(loaded.entries()[0].claim_reference, None);
+        assert_eq!(loaded.entries()[1].claim_reference, Some(String::from("CLAIM-201911-01")));
+        assert_eq!(loaded.entries()[1].manual, Some(false));
+        assert_eq!(loaded.entries()[1].original_amount, history.entries()[1].original_amount);
+        assert_eq!(loaded.entries()[2].refund_of, Some(original.record_fingerprint));
+    }
+
+#[test]
+    fn test_marking_entries_as_claimed() {
+        let mut report = ActivityReport::new();
+        report.add_entry("work", "travel", &StringRecord::from(vec!["20191101", "flight", "-300.00", "0.00"]));
+        report.add_entry("work", "meals", &StringRecord::from(vec!["20191102", "lunch", "-30.00", "0.00"]));
+        let mut history = History::new();
+        history.record(&report);
+
+        let fingerprint = history.entries()[0].record_fingerprint.clone();
+        history.mark_claimed(&[fingerprint], "CLAIM-201911-01");
+
+        assert_eq!(history.entries()[0].claim_reference, Some(String::from("CLAIM-201911-01")));
+        assert_eq!(history.entries()[1].claim_reference, None);
+        assert_eq!(history.claim_references(), vec!["CLAIM-201911-01"]);
+    }
+
+#[test]
+    fn test_renaming_a_category_in_history() {
+        let mut report = ActivityReport::new();
+        report.add_entry("personal", "food", &StringRecord::from(vec!["20191101", "shop", "-10.00", "0.00"]));
+        report.add_entry("work", "food", &StringRecord::from(vec!["20191102", "lunch", "-30.00", "0.00"]));
+        let mut history = History::new();
+        history.record(&report);
+
+        assert_eq!(history.rename_category("personal", "food", "groceries"), 1);
+        assert_eq!(history.entries()[0].category, "groceries");
+        assert_eq!(history.entries()[1].category, "food");
+    }
+
+#[test]
+    fn test_recategorising_replays_rules_over_a_date_range() {
+        let mut report = ActivityReport::new();
+        report.add_entry("personal", "shopping", &StringRecord::from(vec!["20191101", "TESCO STORES", "-10.00", "0.00"]));
+        report.add_manual_entry("personal", "gifts", &StringRecord::from(vec!["20191102", "TESCO EXPRESS", "-20.00", "0.00"]));
+        report.add_entry("personal", "shopping", &StringRecord::from(vec!["20191201", "TESCO STORES", "-30.00", "0.00"]));
+        report.add_entry("personal", "fuel", &StringRecord::from(vec!["20191103", "SHELL", "-40.00", "0.00"]));
+        report.add_transfer(&StringRecord::from(vec!["20191104", "TESCO CARD", "-50.00", "0.00", "everyday"]), &StringRecord::from(vec!["20191104", "TESCO CARD", "50.00", "0.00", "card"]));
+        let original = report.entries()[0].clone();
+        report.add_refund(&StringRecord::from(vec!["20191105", "TESCO REFUND", "5.00", "0.00"]), &original);
+        let mut history = History::new();
+        history.record(&report);
+
+        let matcher = |_: &str, description: &str| if description.contains("TESCO") { Some(String::from("groceries")) } else { None };
+        let changes = history.recategorise(NaiveDate::from_ymd(2019,11,1), NaiveDate::from_ymd(2019,12,1), matcher);
+
+        assert_eq!(changes.iter().map(|c| (c.from.as_str(), c.to.as_str(), c.manual)).collect::<Vec<_>>(), vec![("shopping", "groceries", Some(false)), ("gifts", "groceries", Some(true))]);
+
+        history.apply(&changes[..1]);
+        assert_eq!(history.entries()[0].category, "groceries");
+        assert_eq!(history.entries()[1].category, "gifts");
+        assert_eq!(history.entries()[1].manual, Some(true));
+        assert_eq!(history.entries()[2].category, "shopping");
+        assert_eq!(history.entries()[3].category, "fuel");
+        assert_eq!(history.entries()[6].category, "groceries");
+    }
+
+#[test]
+    fn test_history_from_before_the_manual_column_does_not_know_who_chose() {
+        let path = std::env::temp_dir().join(format!("bsi_history_legacy_{}.csv", std::process::id()));
+        std::fs::write(&path, "category_type,category,date,description,expense,amount,record_fingerprint\npersonal,groceries,20191101,SHOP,true,10.00,abc\n").unwrap();
+
+        let history = History::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(history.entries()[0].manual, None);
+        assert_eq!(history.entries()[0].original_amount, Decimal::new(1000, 2));
+    }
+
+#[test]
+    fn test_loading_bad_history_names_the_line() {
+        let path = std::env::temp_dir().join(format!("bsi_bad_history_{}.csv", std::process::id()));
+        std::fs::write(&path, "category_type,category,date,description,expense,amount,record_fingerprint\npersonal,groceries,20191101,SHOP,true,ten,abc\n").unwrap();
+
+        let loaded = History::load(&path);
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(loaded.err().unwrap().to_string(), "line 2: invalid amount ten");
+    }
+
+#[test]
+    fn test_history_from_before_the_base_currency_column_knows_unconverted_amounts() {
+        let path = std::env::temp_dir().join(format!("bsi_history_base_currency_{}.csv", std::process::id()));
+        std::fs::write(&path, "category_type,category,date,description,expense,amount,currency,original_amount,record_fingerprint\n\
+            personal,travel,20191101,HOTEL,true,1500.00,USD,100.00,abc\n\
+            personal,groceries,20191102,SHOP,true,50.00,ZAR,50.00,def\n").unwrap();
+
+        let history = History::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(history.entries()[0].base_currency, "");
+        assert_eq!(history.entries()[1].base_currency, "ZAR");
+    }
+
+#[test]
+    fn test_loading_missing_history_is_empty() {
+        let history = History::load(Path::new("/nonexistent/bank_statement_importer_history.csv")).unwrap();
+
+        assert!(history.entries().is_empty());
+    }
+
+    pub struct History {
+        entries: Vec<CategorisedEntry>
+    }
+
+    impl Default for History {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl History {
+        pub fn new() -> History {
+            History { entries: Vec::new() }
+        }
+
+        pub fn load(path: &Path) -> Result<History, csv::Error> {
+            let mut history = History::new();
+
+            if !path.exists() {
+                return Ok(history);
+            }
+
+            let mut reader = Reader::from_path(path)?;
+            let headers = reader.headers()?.clone();
+
+            for result in reader.records() {
+                let record = result?;
+                let field = |name: &str| column(&headers, &record, name);
+
+                let decimal = |name: &str| Decimal::from_str(&field(name)).map_err(|_| store::invalid_row(&record, &format!("invalid {} {}", name, field(name))));
+                let amount = decimal("amount")?;
+                let original_amount = if field("original_amount").is_empty() { amount } else { decimal("original_amount")? };
+                let date = NaiveDate::parse_from_str(&field("date"), "%Y%m%d").map_err(|_| store::invalid_row(&record, &format!("invalid date {}", field("date"))))?;
+                history.entries.push(CategorisedEntry {
+                    category_type: field("category_type"),
+                    category: field("category"),
+                    date,
+                    description: field("description"),
+                    merchant: Some(field("merchant")).filter(|m| !m.is_empty()).unwrap_or_else(|| field("description")),
+                    account: field("account"),
+                    expense: field("expense") == "true",
+                    amount,
+                    // Entries recorded before the column existed are in their own currency unless they were converted
+                    base_currency: Some(field("base_currency")).filter(|b| !b.is_empty())
+                        .unwrap_or_else(|| if amount == original_amount { field("currency") } else { String::new() }),
+                    currency: field("currency"),
+                    original_amount,
+                    record_fingerprint: field("record_fingerprint"),
+                    claim_reference: Some(field("claim_reference")).filter(|r| !r.is_empty()),
+                    // Entries recorded before the column existed could have been chosen either way
+                    manual: match field("manual").as_str() {
+                        "true" => Some(true),
+                        "false" => Some(false),
+                        _ => None
+                    },
+                    transfer: Some(field("transfer")).filter(|t| !t.is_empty()),
+                    refund_of: Some(field("refund_of")).filter(|r| !r.is_empty())
+                });
+            }
+
+            Ok(history)
+        }
+
+        /// Writes the history to a new file that then replaces `path`, so that a crash part way
+        /// through leaves the old history whole.
+        pub fn save(&self, path: &Path) -> Result<(), csv::Error> {
+            let mut writer = Writer::from_writer(Vec::new());
+            writer.write_record(COLUMNS)?;
+
+            for entry in self.entries.iter() {
+                writer.write_record(&[
+                    entry.category_type.clone(),
+                    entry.category.clone(),
+                    entry.date.format("%Y%m%d").to_string(),
+                    entry.description.clone(),
+                    entry.merchant.clone(),
+                    entry.account.clone(),
+                    entry.expense.to_string(),
+                    entry.amount.to_string(),
+                    entry.currency.clone(),
+                    entry.original_amount.to_string(),
+                    entry.base_currency.clone(),
+                    entry.record_fingerprint.clone(),
+                    entry.claim_reference.clone().unwrap_or_default(),
+                    entry.manual.map(|m| m.to_string()).unwrap_or_default(),
+                    entry.transfer.clone().unwrap_or_default(),
+                    entry.refund_of.clone().unwrap_or_default()
+                ])?;
+            }
+
+            writer.flush()?;
+            let contents = writer.into_inner().map_err(|e| io::Error::other(e.to_string()))?;
+            store::write_atomically(path, &String::from_utf8_lossy(&contents))?;
+            Ok(())
+        }
+
+        pub fn record(&mut self, report: &ActivityReport) {
+            for entry in report.entries() {
+                if !self.entries.iter().any(|e| e.record_fingerprint == entry.record_fingerprint) {
+                    self.entries.push(entry.clone());
+                }
+            }
+        }
+
+        pub fn entries(&self) -> &[CategorisedEntry] {
+            &self.entries
+        }
+
+        pub fn between(&self, start_date: NaiveDate, end_date_excl: NaiveDate) -> Vec<CategorisedEntry> {
+            self.entries.iter().filter(|e| e.date >= start_date && e.date < end_date_excl).cloned().collect()
+        }
+
+        pub fn mark_claimed(&mut self, fingerprints: &[String], reference: &str) {
+            for entry in self.entries.iter_mut().filter(|e| fingerprints.contains(&e.record_fingerprint)) {
+                entry.claim_reference = Some(String::from(reference));
+            }
+        }
+
+        pub fn claim_references(&self) -> Vec<String> {
+            let mut references: Vec<String> = self.entries.iter().filter_map(|e| e.claim_reference.clone()).collect();
+            references.sort();
+            references.dedup();
+            references
+        }
+
+        /// Works out what `matcher` would change for entries between the dates, other than
+        /// transfers and refunds, which follow the debits they refund. `matcher` is given an
+        /// entry's type and description. Entries it finds no category for keep theirs.
+        pub fn recategorise<F>(&self, start_date: NaiveDate, end_date_excl: NaiveDate, matcher: F) -> Vec<Recategorisation>
+            where F: Fn(&str, &str) -> Option<String> {
+            self.entries.iter()
+                .filter(|e| e.date >= start_date && e.date < end_date_excl && e.transfer.is_none() && e.refund_of.is_none())
+                .filter_map(|e| {
+                    matcher(&e.category_type, &e.description).filter(|c| *c != e.category).map(|to| Recategorisation {
+                        record_fingerprint: e.record_fingerprint.clone(),
+                        date: e.date,
+                        description: e.description.clone(),
+                        category_type: e.category_type.clone(),
+                        from: e.category.clone(),
+                        to,
+                        manual: e.manual
+                    })
+                })
+                .collect()
+        }
+
+        /// Applies the changes, after which the entries count as categorised by a pattern. Refunds
+        /// of the changed entries move with them.
+        pub fn apply(&mut self, changes: &[Recategorisation]) {
+            for change in changes {
+                for entry in self.entries.iter_mut().filter(|e| e.record_fingerprint == change.record_fingerprint || e.refund_of.as_ref() == Some(&change.record_fingerprint)) {
+                    entry.category = change.to.clone();
+                    entry.manual = Some(false);
+                }
+            }
+        }
+
+        /// Moves every entry in one category to another, returning how many were moved.
+        pub fn rename_category(&mut self, category_type: &str, from: &str, to: &str) -> usize {
+            let mut renamed = 0;
+            for entry in self.entries.iter_mut().filter(|e| e.category_type == category_type && e.category == from) {
+                entry.category = String::from(to);
+                renamed += 1;
+            }
+            renamed
+        }
+    }
+
+    /// A category that the current rules would give a history entry instead of the one it has.
+    #[derive(Debug, PartialEq)]
+    pub struct Recategorisation {
+        pub record_fingerprint: String,
+        pub date: NaiveDate,
+        pub description: String,
+        pub category_type: String,
+        pub from: String,
+        pub to: String,
+        /// Whether the user chose the current category by hand, or `None` when that is not known.
+        pub manual: Option<bool>
+    }
+
+    fn column(headers: &StringRecord, record: &StringRecord, name: &str) -> String {
+        match headers.iter().position(|h| h == name) {
+            Some(i) => String::from(record.get(i).unwrap_or("")),
+            None => String::new()
+        }
+    }
+}
+
+pub mod trend {
+    use chrono::{NaiveDate, Datelike};
+    use csv::Writer;
+    use rust_decimal::Decimal;
+    use crate::report::{CategorisedEntry, money};
+#[cfg(test)]
+    use csv::StringRecord;
+#[cfg(test)]
+    use std::str::FromStr;
+#[cfg(test)]
+    use crate::report::ActivityReport;
+
+#[cfg(test)]
+    fn sample_entries() -> Vec<CategorisedEntry> {
+        let mut report = ActivityReport::new();
+        report.add_entry("personal", "groceries", &StringRecord::from(vec!["20190915", "shop a", "-30.00", "0.00"]));
+        report.add_entry("personal", "groceries", &StringRecord::from(vec!["20191001", "shop a", "-100.00", "0.00"]));
+        report.add_entry("personal", "groceries", &StringRecord::from(vec!["20191120", "shop b", "-160.00", "0.00"]));
+        report.add_entry("personal", "groceries", &StringRecord::from(vec!["20191121", "shop c", "-20.00", "0.00"]));
+        report.add_entry("personal", "salary", &StringRecord::from(vec!["20191125", "employer", "5000.00", "0.00"]));
+        report.add_entry("work", "travel", &StringRecord::from(vec!["20181105", "airline", "-400.00", "0.00"]));
+        report.add_entry("work", "travel", &StringRecord::from(vec!["20191105", "airline", "-250.00", "0.00"]));
+        report.entries().to_vec()
+    }
+
+#[test]
+    fn test_period_parsing_and_stepping() {
+        let period = Period::parse("201901").unwrap();
+
+        assert_eq!(period.label(), "2019-01");
+        assert_eq!(period.previous().label(), "2018-12");
+        assert_eq!(period.previous_year().label(), "2018-01");
+        assert_eq!(Period::parse("201913"), None);
+        assert_eq!(Period::parse("2019"), None);
+    }
+
+#[test]
+    fn test_monthly_trend_sums_expenses_per_category() {
+        let trend = TrendReport::monthly(&sample_entries(), Period::parse("201911").unwrap(), 3, 2);
+
+        assert_eq!(trend.periods().iter().map(|p| p.label()).collect::<Vec<String>>(), vec!["2019-09", "2019-10", "2019-11"]);
+        assert_eq!(trend.rows().len(), 2);
+
+        let groceries = &trend.rows()[0];
+        assert_eq!(groceries.category, "groceries");
+        assert_eq!(groceries.amounts, vec![Decimal::from_str("30").unwrap(), Decimal::from_str("100").unwrap(), Decimal::from_str("180").unwrap()]);
+        assert_eq!(trend.change(groceries, 0), None);
+        assert_eq!(trend.change(groceries, 2), Some(Decimal::from_str("80").unwrap()));
+        assert_eq!(trend.rolling_average(groceries, 0), Decimal::from_str("30").unwrap());
+        assert_eq!(trend.rolling_average(groceries, 2), Decimal::from_str("140").unwrap());
+    }
+
+#[test]
+    fn test_refunds_reduce_the_month_they_are_in() {
+        let mut entries = sample_entries();
+        let mut report = ActivityReport::new();
+        report.add_refund(&StringRecord::from(vec!["20191002", "shop a", "25.00", "0.00"]), &entries[1]);
+        entries.extend(report.entries().to_vec());
+
+        let trend = TrendReport::monthly(&entries, Period::parse("201911").unwrap(), 3, 2);
+
+        assert_eq!(trend.rows()[0].amounts[1], Decimal::from_str("75").unwrap());
+    }
+
+#[test]
+    fn test_year_on_year_trend_compares_the_same_month() {
+        let trend = TrendReport::year_on_year(&sample_entries(), Period::parse("201911").unwrap(), 2, 3);
+
+        assert_eq!(trend.periods().iter().map(|p| p.label()).collect::<Vec<String>>(), vec!["2018-11", "2019-11"]);
+        let travel = trend.rows().iter().find(|r| r.category == "travel").unwrap();
+        assert_eq!(travel.amounts, vec![Decimal::from_str("400").unwrap(), Decimal::from_str("250").unwrap()]);
+        assert_eq!(trend.change(travel, 1), Some(Decimal::from_str("-150").unwrap()));
+    }
+
+#[test]
+    fn test_trend_csv_and_json_output() {
+        let trend = TrendReport::monthly(&sample_entries(), Period::parse("201911").unwrap(), 2, 2);
+
+        let csv = trend.to_csv();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("type,category,period,amount,change,rolling_average"));
+        assert_eq!(lines.next(), Some("personal,groceries,2019-10,100.00,,100.00"));
+        assert_eq!(lines.next(), Some("personal,groceries,2019-11,180.00,80.00,140.00"));
+
+        let json = trend.to_json();
+        assert!(json.starts_with("{\"periods\":[\"2019-10\",\"2019-11\"],\"rows\":[{\"type\":\"personal\",\"category\":\"groceries\""));
+        assert!(json.contains("{\"period\":\"2019-10\",\"amount\":\"100.00\",\"change\":null,\"rolling_average\":\"100.00\"}"));
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    pub struct Period {
+        year: i32,
+        month: u32
+    }
+
+    impl Period {
+        pub fn parse(period: &str) -> Option<Period> {
+            let date = NaiveDate::parse_from_str(&format!("{}01", period.trim()), "%Y%m%d").ok()?;
+            Some(Period::of(date))
+        }
+
+        pub fn of(date: NaiveDate) -> Period {
+            Period { year: date.year(), month: date.month() }
+        }
+
+        pub fn previous(self) -> Period {
+            if self.month == 1 {
+                Period { year: self.year - 1, month: 12 }
+            } else {
+                Period { year: self.year, month: self.month - 1 }
+            }
+        }
+
+        pub fn previous_year(self) -> Period {
+            Period { year: self.year - 1, month: self.month }
+        }
+
+        pub fn label(&self) -> String {
+            format!("{:04}-{:02}", self.year, self.month)
+        }
+    }
+
+    pub struct TrendRow {
+        pub category_type: String,
+        pub category: String,
+        pub amounts: Vec<Decimal>
+    }
+
+    pub struct TrendReport {
+        periods: Vec<Period>,
+        rows: Vec<TrendRow>,
+        window: usize
+    }
+
+    impl TrendReport {
+        pub fn monthly(entries: &[CategorisedEntry], last: Period, count: usize, window: usize) -> TrendReport {
+            TrendReport::build(entries, Self::periods_ending(last, count, Period::previous), window)
+        }
+
+        pub fn year_on_year(entries: &[CategorisedEntry], last: Period, count: usize, window: usize) -> TrendReport {
+            TrendReport::build(entries, Self::periods_ending(last, count, Period::previous_year), window)
+        }
+
+        fn periods_ending(last: Period, count: usize, step: fn(Period) -> Period) -> Vec<Period> {
+            let mut periods = vec![last];
+            while periods.len() < count {
+                let earliest = step(periods[periods.len() - 1]);
+                periods.push(earliest);
+            }
+            periods.reverse();
+            periods
+        }
+
+        fn build(entries: &[CategorisedEntry], periods: Vec<Period>, window: usize) -> TrendReport {
+            let mut rows: Vec<TrendRow> = Vec::new();
+
+            for entry in entries.iter().filter(|e| (e.expense || e.refund_of.is_some()) && e.transfer.is_none()) {
+                let index = match periods.iter().position(|p| *p == Period::of(entry.date)) {
+                    Some(i) => i,
+                    None => continue
+                };
+
+                let position = rows.iter().position(|r| r.category_type == entry.category_type && r.category == entry.category);
+                let row = match position {
+                    Some(i) => &mut rows[i],
+                    None => {
+                        rows.push(TrendRow {
+                            category_type: entry.category_type.clone(),
+                            category: entry.category.clone(),
+                            amounts: vec![Decimal::new(0, 0); periods.len()]
+                        });
+                        let last = rows.len() - 1;
+                        &mut rows[last]
+                    }
+                };
+
+                if entry.refund_of.is_some() {
+                    row.amounts[index] -= entry.amount;
+                } else {
+                    row.amounts[index] += entry.amount;
+                }
+            }
+
+            rows.sort_by(|a, b| (&a.category_type, &a.category).cmp(&(&b.category_type, &b.category)));
+
+            TrendReport { periods, rows, window: window.max(1) }
+        }
+
+        pub fn periods(&self) -> &[Period] {
+            &self.periods
+        }
+
+        pub fn rows(&self) -> &[TrendRow] {
+            &self.rows
+        }
+
+        pub fn change(&self, row: &TrendRow, index: usize) -> Option<Decimal> {
+            if index == 0 {
+                None
+            } else {
+                Some(row.amounts[index] - row.amounts[index - 1])
+            }
+        }
+
+        pub fn rolling_average(&self, row: &TrendRow, index: usize) -> Decimal {
+            let start = (index + 1).saturating_sub(self.window);
+            let values = &row.amounts[start..=index];
+            let sum: Decimal = values.iter().cloned().sum();
+
+            sum / Decimal::new(values.len() as i64, 0)
+        }
+
+        pub fn to_table(&self) -> String {
+            let mut lines: Vec<Vec<String>> = Vec::new();
+            let mut header = vec![String::from("Type"), String::from("Category"), String::new()];
+            header.extend(self.periods.iter().map(|p| p.label()));
+            lines.push(header);
+
+            for row in self.rows.iter() {
+                let mut amounts = vec![row.category_type.clone(), row.category.clone(), String::from("amount")];
+                let mut changes = vec![String::new(), String::new(), String::from("change")];
+                let mut averages = vec![String::new(), String::new(), format!("{}-period avg", self.window)];
+
+                for index in 0..self.periods.len() {
+                    amounts.push(money(row.amounts[index]));
+                    changes.push(match self.change(row, index) {
+                        Some(change) => signed_money(change),
+                        None => String::from("-")
+                    });
+                    averages.push(money(self.rolling_average(row, index)));
+                }
+
+                lines.push(amounts);
+                lines.push(changes);
+                lines.push(averages);
+            }
+
+            let widths: Vec<usize> = (0..lines[0].len())
+                .map(|column| lines.iter().map(|l| l[column].len()).max().unwrap_or(0))
+                .collect();
+
+            let mut out = String::new();
+            for line in lines {
+                let cells: Vec<String> = line.iter().enumerate().map(|(column, cell)| {
+                    if column < 3 {
+                        format!("{:<width$}", cell, width = widths[column])
+                    } else {
+                        format!("{:>width$}", cell, width = widths[column])
+                    }
+                }).collect();
+                out.push_str(cells.join("  ").trim_end());
+                out.push('\n');
+            }
+            out
+        }
+
+        pub fn to_csv(&self) -> String {
+            let mut writer = Writer::from_writer(Vec::new());
+            writer.write_record(["type", "category", "period", "amount", "change", "rolling_average"]).unwrap();
+
+            for row in self.rows.iter() {
+                for (index, period) in self.periods.iter().enumerate() {
+                    writer.write_record(&[
+                        row.category_type.clone(),
+                        row.category.clone(),
+                        period.label(),
+                        money(row.amounts[index]),
+                        self.change(row, index).map(money).unwrap_or_default(),
+                        money(self.rolling_average(row, index))
+                    ]).unwrap();
+                }
+            }
+
+            String::from_utf8(writer.into_inner().unwrap()).unwrap()
+        }
+
+        pub fn to_json(&self) -> String {
+            let periods: Vec<String> = self.periods.iter().map(|p| json_string(&p.label())).collect();
+            let rows: Vec<String> = self.rows.iter().map(|row| {
+                let values: Vec<String> = self.periods.iter().enumerate().map(|(index, period)| {
+                    format!(
+                        "{{\"period\":{},\"amount\":{},\"change\":{},\"rolling_average\":{}}}",
+                        json_string(&period.label()),
+                        json_string(&money(row.amounts[index])),
+                        self.change(row, index).map(|c| json_string(&money(c))).unwrap_or_else(|| String::from("null")),
+                        json_string(&money(self.rolling_average(row, index)))
+                    )
+                }).collect();
+
+                format!(
+                    "{{\"type\":{},\"category\":{},\"values\":[{}]}}",
+                    json_string(&row.category_type),
+                    json_string(&row.category),
+                    values.join(",")
+                )
+            }).collect();
+
+            format!("{{\"periods\":[{}],\"rows\":[{}]}}", periods.join(","), rows.join(","))
+        }
+    }
+
+    fn signed_money(amount: Decimal) -> String {
+        if amount > Decimal::new(0, 0) {
+            format!("+{}", money(amount))
+        } else {
+            money(amount)
+        }
+    }
+
+    fn json_string(value: &str) -> String {
+        let mut out = String::from("\"");
+        for c in value.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                c => out.push(c)
+            }
+        }
+        out.push('"');
+        out
+    }
+}
+
+pub mod html {
+    use chrono::NaiveDate;
+    use rust_decimal::Decimal;
+    use crate::report::{ActivityReport, money};
+#[cfg(test)]
+    use csv::StringRecord;
+
+    const COLOURS: [&str; 10] = ["#4e79a7", "#f28e2b", "#e15759", "#76b7b2", "#59a14f", "#edc948", "#b07aa1", "#ff9da7", "#9c755f", "#bab0ac"];
+
+#[test]
+    fn test_escape() {
+        assert_eq!(escape("<b>Tom & Jerry's \"cafe\"</b>"), "&lt;b&gt;Tom &amp; Jerry&#39;s &quot;cafe&quot;&lt;/b&gt;");
+    }
+
+#[test]
+    fn test_render_is_self_contained() {
+        let mut report = ActivityReport::new();
+        report.add_entry("personal", "groceries", &StringRecord::from(vec!["20191101", "Woolworths <Cape Town>", "-100.00", "0.00"]));
+        report.add_entry("personal", "eating out", &StringRecord::from(vec!["20191102", "Cafe", "-50.00", "0.00"]));
+        report.add_entry("work", "travel", &StringRecord::from(vec!["20191103", "Airline", "-300.00", "0.00"]));
+        report.add_entry("work", "travel", &StringRecord::from(vec!["20191104", "Hotel", "-80.00", "0.00", "card", "USD"]));
+
+        let page = render(&report, NaiveDate::from_ymd(2019, 11, 1), NaiveDate::from_ymd(2019, 12, 1));
+
+        assert!(page.starts_with("<!DOCTYPE html>"));
+        assert!(page.contains("2019-11-01 to 2019-11-30"));
+        assert!(page.contains("Woolworths &lt;Cape Town&gt;"));
+        assert!(page.contains("<td class=\"amount\">-80.00 USD</td>"));
+        assert_eq!(page.matches("<svg").count(), 4);
+        assert!(!page.contains("http"));
+        assert!(!page.contains("src="));
+    }
+
+#[test]
+    fn test_pie_chart_with_single_slice_is_a_circle() {
+        let chart = pie_chart(&[(String::from("only"), Decimal::new(10, 0))]);
+
+        assert!(chart.contains("<circle"));
+        assert!(!chart.contains("<path"));
+    }
+
+    pub fn render(report: &ActivityReport, start_date: NaiveDate, end_date_excl: NaiveDate) -> String {
+        let period = format!("{} to {}", start_date.format("%Y-%m-%d"), end_date_excl.pred().format("%Y-%m-%d"));
+        let mut body = String::new();
+
+        body.push_str(&format!("<h1>Activity report</h1>\n<p class=\"period\">{}</p>\n", escape(&period)));
+        body.push_str(&summary(report));
+
+        for category_type in report.category_types() {
+            // A category whose debits were all refunded has no expense to draw
+            let expenses: Vec<(String, Decimal)> = report.category_totals(&category_type, true)
+                .into_iter()
+                .filter(|(_, amount)| *amount > Decimal::new(0, 0))
+                .collect();
+            if expenses.is_empty() {
+                continue;
+            }
+            body.push_str(&format!("<section class=\"charts\">\n<h2>{} expenses</h2>\n", escape(&category_type)));
+            body.push_str(&pie_chart(&expenses));
+            body.push_str(&bar_chart(&expenses));
+            body.push_str("</section>\n");
+        }
+
+        body.push_str(&transactions(report));
+
+        format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Activity report {}</title>\n<style>{}</style>\n</head>\n<body>\n{}<script>{}</script>\n</body>\n</html>\n",
+            escape(&period),
+            STYLE,
+            body,
+            SORT_SCRIPT
+        )
+    }
+
+    fn summary(report: &ActivityReport) -> String {
+        let mut out = String::from("<h2>Totals</h2>\n<table class=\"summary\">\n<tr><th>Type</th><th>Income</th><th>Expense</th><th>Net</th></tr>\n");
+        for category_type in report.category_types() {
+            let income = report.total(&category_type, false);
+            let expense = report.total(&category_type, true);
+            out.push_str(&format!(
+                "<tr><td>{}</td><td class=\"amount\">{}</td><td class=\"amount\">{}</td><td class=\"amount\">{}</td></tr>\n",
+                escape(&category_type),
+                money(income),
+                money(expense),
+                money(income - expense)
+            ));
+        }
+        out.push_str("</table>\n");
+        for category_type in report.category_types() {
+            let refunded = report.earlier_refunds(&category_type);
+            if refunded != Decimal::new(0, 0) {
+                out.push_str(&format!("<p>Refunds of earlier {} purchases, not taken off the expense above: {}</p>\n", escape(&category_type), money(refunded)));
+            }
+        }
+
+        let accounts = report.accounts();
+        if !accounts.is_empty() {
+            out.push_str("<table class=\"summary\">\n<tr><th>Account</th><th>Income</th><th>Expense</th><th>Net</th></tr>\n");
+            for account in accounts {
+                let income = report.account_total(&account, false);
+                let expense = report.account_total(&account, true);
+                out.push_str(&format!(
+                    "<tr><td>{}</td><td class=\"amount\">{}</td><td class=\"amount\">{}</td><td class=\"amount\">{}</td></tr>\n",
+                    escape(&account),
+                    money(income),
+                    money(expense),
+                    money(income - expense)
+                ));
+            }
+            out.push_str("</table>\n");
+        }
+        out
+    }
+
+    fn pie_chart(totals: &[(String, Decimal)]) -> String {
+        let values: Vec<f64> = totals.iter().map(|(_, amount)| as_f64(*amount)).collect();
+        let sum: f64 = values.iter().sum();
+        let (cx, cy, r) = (110.0, 110.0, 100.0);
+        let mut out = String::from("<svg class=\"pie\" width=\"220\" height=\"220\" viewBox=\"0 0 220 220\">\n");
+
+        if values.len() == 1 {
+            out.push_str(&format!(
+                "<circle cx=\"{}\" cy=\"{}\" r=\"{}\" fill=\"{}\"><title>{}: {}</title></circle>\n",
+                cx, cy, r, COLOURS[0], escape(&totals[0].0), money(totals[0].1)
+            ));
+        } else {
+            let mut angle: f64 = -std::f64::consts::FRAC_PI_2;
+            for (index, value) in values.iter().enumerate() {
+                let sweep = if sum > 0.0 { value / sum * 2.0 * std::f64::consts::PI } else { 0.0 };
+                let (x1, y1) = (cx + r * angle.cos(), cy + r * angle.sin());
+                let (x2, y2) = (cx + r * (angle + sweep).cos(), cy + r * (angle + sweep).sin());
+                let large_arc = if sweep > std::f64::consts::PI { 1 } else { 0 };
+                out.push_str(&format!(
+                    "<path d=\"M {} {} L {:.2} {:.2} A {} {} 0 {} 1 {:.2} {:.2} Z\" fill=\"{}\"><title>{}: {}</title></path>\n",
+                    cx, cy, x1, y1, r, r, large_arc, x2, y2,
+                    COLOURS[index % COLOURS.len()], escape(&totals[index].0), money(totals[index].1)
+                ));
+                angle += sweep;
+            }
+        }
+
+        out.push_str("</svg>\n");
+        out
+    }
+
+    fn bar_chart(totals: &[(String, Decimal)]) -> String {
+        let max = totals.iter().map(|(_, amount)| as_f64(*amount)).fold(0.0, f64::max);
+        let height = totals.len() * 24 + 10;
+        let mut out = format!("<svg class=\"bar\" width=\"520\" height=\"{0}\" viewBox=\"0 0 520 {0}\">\n", height);
+
+        for (index, (category, amount)) in totals.iter().enumerate() {
+            let y = index * 24 + 5;
+            let width = if max > 0.0 { as_f64(*amount) / max * 260.0 } else { 0.0 };
+            out.push_str(&format!(
+                "<text x=\"150\" y=\"{}\" text-anchor=\"end\">{}</text><rect x=\"160\" y=\"{}\" width=\"{:.2}\" height=\"18\" fill=\"{}\"></rect><text x=\"{:.2}\" y=\"{}\">{}</text>\n",
+                y + 14, escape(category), y, width, COLOURS[index % COLOURS.len()], 165.0 + width, y + 14, money(*amount)
+            ));
+        }
+
+        out.push_str("</svg>\n");
+        out
+    }
+
+    fn transactions(report: &ActivityReport) -> String {
+        let mut out = String::from("<h2>Transactions</h2>\n<table id=\"transactions\" class=\"sortable\">\n<thead><tr><th>Date</th><th>Account</th><th>Type</th><th>Category</th><th>Description</th><th>Original</th><th>Amount</th></tr></thead>\n<tbody>\n");
+        for entry in report.entries() {
+            let amount = if entry.expense { -entry.amount } else { entry.amount };
+            let original = if entry.currency.is_empty() {
+                String::new()
+            } else {
+                let original_amount = if entry.expense { -entry.original_amount } else { entry.original_amount };
+                format!("{} {}", money(original_amount), entry.currency)
+            };
+            out.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td class=\"amount\">{}</td><td class=\"amount\" data-value=\"{}\">{}</td></tr>\n",
+                entry.date.format("%Y-%m-%d"),
+                escape(&entry.account),
+                escape(&entry.category_type),
+                escape(&entry.category),
+                escape(&entry.description),
+                escape(&original),
+                amount,
+                money(amount)
+            ));
+        }
+        out.push_str("</tbody>\n</table>\n");
+        out
+    }
+
+    fn as_f64(amount: Decimal) -> f64 {
+        amount.to_string().parse().unwrap_or(0.0)
+    }
+
+    pub fn escape(value: &str) -> String {
+        let mut out = String::new();
+        for c in value.chars() {
+            match c {
+                '&' => out.push_str("&amp;"),
+                '<' => out.push_str("&lt;"),
+                '>' => out.push_str("&gt;"),
+                '"' => out.push_str("&quot;"),
+                '\'' => out.push_str("&#39;"),
+                c => out.push(c)
+            }
+        }
+        out
+    }
+
+    const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; margin-bottom: 2em; }
+th, td { border: 1px solid #ccc; padding: 4px 8px; text-align: left; }
+td.amount { text-align: right; font-variant-numeric: tabular-nums; }
+table.sortable th { cursor: pointer; background: #f2f2f2; }
+section.charts svg { vertical-align: top; margin-right: 2em; }
+svg text { font-size: 12px; }
+@media print { table.sortable th { background: none; } }
+";
+
+    const SORT_SCRIPT: &str = "
+document.querySelectorAll('table.sortable').forEach(function (table) {
+  table.querySelectorAll('th').forEach(function (header, column) {
+    var ascending = true;
+    header.addEventListener('click', function () {
+      var body = table.tBodies[0];
+      var rows = Array.prototype.slice.call(body.rows);
+      rows.sort(function (a, b) {
+        var x = a.cells[column], y = b.cells[column];
+        var result = x.dataset.value !== undefined
+          ? parseFloat(x.dataset.value) - parseFloat(y.dataset.value)
+          : x.textContent.localeCompare(y.textContent);
+        return ascending ? result : -result;
+      });
+      ascending = !ascending;
+      rows.forEach(function (row) { body.appendChild(row); });
+    });
+  });
+});
+";
+}
+
+pub mod markdown {
+    use chrono::NaiveDate;
+    use rust_decimal::Decimal;
+    use crate::report::{ActivityReport, money};
+#[cfg(test)]
+    use csv::StringRecord;
+#[cfg(test)]
+    use crate::currency::Rates;
+
+#[test]
+    fn test_render_markdown_summary() {
+        let mut report = ActivityReport::new();
+        report.add_entry("personal", "groceries", &StringRecord::from(vec!["20191101", "shop", "-100.00", "0.00"]));
+        report.add_entry("personal", "salary", &StringRecord::from(vec!["20191125", "employer", "5000.00", "0.00"]));
+        report.add_entry("work", "travel | flights", &StringRecord::from(vec!["20191103", "airline", "-300.00", "0.00"]));
+
+        let sources = vec![String::from("statements/cheque.csv"), String::from("statements/card.csv")];
+        let page = render(&report, NaiveDate::from_ymd(2019, 11, 1), NaiveDate::from_ymd(2019, 12, 1), &sources);
+
+        assert!(page.contains("**Period:** 2019-11-01 to 2019-11-30"));
+        assert!(page.contains("- `statements/cheque.csv`\n- `statements/card.csv`\n"));
+        assert!(page.contains("| personal | 5000.00 | 100.00 | 4900.00 |"));
+        assert!(page.contains("| work | 0.00 | 300.00 | -300.00 |"));
+        assert!(page.contains("## personal\n\n| Category | Expense | Income |\n| --- | ---: | ---: |\n| groceries | 100.00 | 0.00 |\n| salary | 0.00 | 5000.00 |\n"));
+        assert!(page.contains("| travel \\| flights | 300.00 | 0.00 |"));
+        assert!(!page.contains("## Accounts"));
+        assert!(!page.contains("**Currency:**"));
+    }
+
+#[test]
+    fn test_render_markdown_groups_by_account() {
+        let mut report = ActivityReport::new();
+        report.add_entry("personal", "groceries", &StringRecord::from(vec!["20191101", "shop", "-100.00", "0.00", "everyday"]));
+        report.add_entry("personal", "fuel", &StringRecord::from(vec!["20191102", "garage", "-40.00", "0.00", "card"]));
+        report.add_entry("personal", "salary", &StringRecord::from(vec!["20191125", "employer", "5000.00", "0.00", "everyday"]));
+        report.convert_to("ZAR", Rates::new());
+
+        let page = render(&report, NaiveDate::from_ymd(2019, 11, 1), NaiveDate::from_ymd(2019, 12, 1), &[]);
+
+        assert!(page.contains("**Currency:** ZAR\n"));
+        assert!(page.contains("## Accounts\n\n| Account | Income | Expense | Net |\n| --- | ---: | ---: | ---: |\n| card | 0.00 | 40.00 | -40.00 |\n| everyday | 5000.00 | 100.00 | 4900.00 |\n"));
+        assert!(!page.contains("## Transfers"));
+    }
+
+#[test]
+    fn test_render_markdown_lists_refunds_against_their_category() {
+        let mut report = ActivityReport::new();
+        report.add_entry("personal", "clothing", &StringRecord::from(vec!["20191101", "OUTFITTERS", "-100.00", "0.00"]));
+        let original = report.entries()[0].clone();
+        report.add_refund(&StringRecord::from(vec!["20191108", "OUTFITTERS REFUND", "30.00", "0.00"]), &original);
+
+        let page = render(&report, NaiveDate::from_ymd(2019, 11, 1), NaiveDate::from_ymd(2019, 12, 1), &[]);
+
+        assert!(page.contains("| personal | 0.00 | 70.00 | -70.00 |"));
+        assert!(page.contains("## Refunds\n\n| Date | Description | Category | Amount |\n| --- | --- | --- | ---: |\n| 2019-11-08 | OUTFITTERS REFUND | clothing | 30.00 |\n"));
+        assert!(!page.contains("Refunds of earlier"));
+    }
+
+#[test]
+    fn test_render_markdown_shows_refunds_of_earlier_purchases_apart() {
+        let mut earlier = ActivityReport::new();
+        earlier.add_entry("personal", "clothing", &StringRecord::from(vec!["20191025", "OUTFITTERS", "-100.00", "0.00"]));
+        let mut report = ActivityReport::new();
+        report.add_refund(&StringRecord::from(vec!["20191108", "OUTFITTERS REFUND", "30.00", "0.00"]), &earlier.entries()[0]);
+
+        let page = render(&report, NaiveDate::from_ymd(2019, 11, 1), NaiveDate::from_ymd(2019, 12, 1), &[]);
+
+        assert!(page.contains("| personal | 0.00 | 0.00 | 0.00 |"));
+        assert!(page.contains("\nRefunds of earlier personal purchases, not taken off the expense above: 30.00\n"));
+    }
+
+#[test]
+    fn test_render_markdown_lists_transfers_apart_from_totals() {
+        let mut report = ActivityReport::new();
+        report.add_entry("personal", "groceries", &StringRecord::from(vec!["20191101", "shop", "-100.00", "0.00", "card"]));
+        report.add_transfer(
+            &StringRecord::from(vec!["20191105", "CARD PAYMENT", "-100.00", "0.00", "everyday"]),
+            &StringRecord::from(vec!["20191106", "PAYMENT RECEIVED", "100.00", "0.00", "card"])
+        );
+
+        let page = render(&report, NaiveDate::from_ymd(2019, 11, 1), NaiveDate::from_ymd(2019, 12, 1), &[]);
+
+        assert!(page.contains("| personal | 0.00 | 100.00 | -100.00 |"));
+        assert!(!page.contains("| transfer |"));
+        assert!(page.contains("## Transfers\n\n| Date | From | To | Amount |\n| --- | --- | --- | ---: |\n| 2019-11-05 | everyday | card | 100.00 |\n"));
+    }
+
+    pub fn render(report: &ActivityReport, start_date: NaiveDate, end_date_excl: NaiveDate, sources: &[String]) -> String {
+        let mut out = String::from("# Activity report\n\n");
+
+        out.push_str(&format!("**Period:** {} to {}\n\n", start_date.format("%Y-%m-%d"), end_date_excl.pred().format("%Y-%m-%d")));
+
+        if let Some(base) = report.base_currency() {
+            out.push_str(&format!("**Currency:** {}\n\n", base));
+        }
+
+        out.push_str("**Sources:**\n\n");
+        for source in sources {
+            out.push_str(&format!("- `{}`\n", source.replace('`', "'")));
+        }
+
+        out.push_str("\n## Summary\n\n| Type | Income | Expense | Net |\n| --- | ---: | ---: | ---: |\n");
+        for category_type in report.category_types() {
+            let income = report.total(&category_type, false);
+            let expense = report.total(&category_type, true);
+            out.push_str(&format!("| {} | {} | {} | {} |\n", cell(&category_type), money(income), money(expense), money(income - expense)));
+        }
+
+        let accounts = report.accounts();
+        if !accounts.is_empty() {
+            out.push_str("\n## Accounts\n\n| Account | Income | Expense | Net |\n| --- | ---: | ---: | ---: |\n");
+            for account in accounts {
+                let income = report.account_total(&account, false);
+                let expense = report.account_total(&account, true);
+                out.push_str(&format!("| {} | {} | {} | {} |\n", cell(&account), money(income), money(expense), money(income - expense)));
+            }
+        }
+
+        let transfers = report.transfers();
+        if !transfers.is_empty() {
+            out.push_str("\n## Transfers\n\n| Date | From | To | Amount |\n| --- | --- | --- | ---: |\n");
+            for transfer in transfers {
+                out.push_str(&format!("| {} | {} | {} | {} |\n", transfer.date.format("%Y-%m-%d"), cell(&transfer.account), cell(&transfer.category), money(transfer.amount)));
+            }
+        }
+
+        let refunds = report.refunds();
+        if !refunds.is_empty() {
+            out.push_str("\n## Refunds\n\n| Date | Description | Category | Amount |\n| --- | --- | --- | ---: |\n");
+            for refund in refunds {
+                out.push_str(&format!("| {} | {} | {} | {} |\n", refund.date.format("%Y-%m-%d"), cell(&refund.description), cell(&refund.category), money(refund.amount)));
+            }
+            for category_type in report.category_types() {
+                let refunded = report.earlier_refunds(&category_type);
+                if refunded != Decimal::new(0, 0) {
+                    out.push_str(&format!("\nRefunds of earlier {} purchases, not taken off the expense above: {}\n", category_type, money(refunded)));
+                }
+            }
+        }
+
+        for category_type in report.category_types() {
+            let expenses = report.category_totals(&category_type, true);
+            let income = report.category_totals(&category_type, false);
+
+            let mut categories: Vec<&String> = expenses.iter().chain(income.iter()).map(|(c, _)| c).collect();
+            categories.sort();
+            categories.dedup();
+
+            out.push_str(&format!("\n## {}\n\n| Category | Expense | Income |\n| --- | ---: | ---: |\n", category_type));
+            for category in categories {
+                let amount = |totals: &[(String, Decimal)]| {
+                    totals.iter().find(|(c, _)| c == category).map(|(_, a)| *a).unwrap_or_default()
+                };
+                out.push_str(&format!("| {} | {} | {} |\n", cell(category), money(amount(&expenses)), money(amount(&income))));
+            }
+        }
+
+        out
+    }
+
+    fn cell(value: &str) -> String {
+        value.replace('|', "\\|")
+    }
+}
+
+pub mod claim {
+    use chrono::NaiveDate;
+    use csv::Writer;
+    use rust_decimal::Decimal;
+    use crate::report::{ActivityReport, CategorisedEntry, money};
+    use crate::html::escape;
+#[cfg(test)]
+    use csv::StringRecord;
+#[cfg(test)]
+    use std::str::FromStr;
+
+#[cfg(test)]
+    fn sample_report() -> ActivityReport {
+        let mut report = ActivityReport::new();
+        report.add_entry("work", "travel", &StringRecord::from(vec!["20191103", "airline", "-300.00", "0.00"]));
+        report.add_entry("work", "meals", &StringRecord::from(vec!["20191104", "client lunch", "-45.50", "0.00"]));
+        report.add_entry("work", "travel", &StringRecord::from(vec!["20191105", "taxi", "-20.00", "0.00"]));
+        report.add_entry("work", "consulting", &StringRecord::from(vec!["20191106", "invoice paid", "9000.00", "0.00"]));
+        report.add_entry("personal", "groceries", &StringRecord::from(vec!["20191107", "shop", "-100.00", "0.00"]));
+        report
+    }
+
+#[test]
+    fn test_next_reference() {
+        let start = NaiveDate::from_ymd(2019, 11, 1);
+
+        assert_eq!(next_reference(start, &[]), "CLAIM-201911-01");
+        assert_eq!(next_reference(start, &[String::from("CLAIM-201910-01"), String::from("CLAIM-201911-01")]), "CLAIM-201911-02");
+    }
+
+#[test]
+    fn test_claim_only_includes_unclaimed_work_expenses() {
+        let mut entries = sample_report().entries().to_vec();
+        entries[2].claim_reference = Some(String::from("CLAIM-201910-01"));
+        let report = ActivityReport::from_entries(entries);
+
+        let claim = Claim::new("CLAIM-201911-01", &report, NaiveDate::from_ymd(2019, 11, 1), NaiveDate::from_ymd(2019, 12, 1));
+
+        assert_eq!(claim.entries().iter().map(|e| e.description.clone()).collect::<Vec<String>>(), vec!["client lunch", "airline"]);
+        assert_eq!(
+            claim.subtotals(),
+            vec![
+                (String::from("meals"), Decimal::from_str("45.5").unwrap()),
+                (String::from("travel"), Decimal::from_str("300").unwrap()),
+            ]);
+        assert_eq!(claim.total(), Decimal::from_str("345.5").unwrap());
+    }
+
+#[test]
+    fn test_claim_leaves_out_what_was_refunded() {
+        let mut report = sample_report();
+        let (airline, taxi) = (report.entries()[0].clone(), report.entries()[2].clone());
+        report.add_refund(&StringRecord::from(vec!["20191110", "airline refund", "100.00", "0.00"]), &airline);
+        report.add_refund(&StringRecord::from(vec!["20191111", "taxi refund", "20.00", "0.00"]), &taxi);
+
+        let claim = Claim::new("CLAIM-201911-01", &report, NaiveDate::from_ymd(2019, 11, 1), NaiveDate::from_ymd(2019, 12, 1));
+
+        assert_eq!(claim.entries().iter().map(|e| (e.description.as_str(), e.amount)).collect::<Vec<_>>(), vec![("client lunch", Decimal::from_str("45.50").unwrap()), ("airline", Decimal::new(200, 0))]);
+        assert_eq!(claim.total(), Decimal::from_str("245.5").unwrap());
+    }
+
+#[test]
+    fn test_claim_csv() {
+        let claim = Claim::new("CLAIM-201911-01", &sample_report(), NaiveDate::from_ymd(2019, 11, 1), NaiveDate::from_ymd(2019, 12, 1));
+
+        assert_eq!(
+            claim.to_csv(),
+            "reference,date,category,description,amount\n\
+             CLAIM-201911-01,2019-11-04,meals,client lunch,45.50\n\
+             CLAIM-201911-01,2019-11-03,travel,airline,300.00\n\
+             CLAIM-201911-01,2019-11-05,travel,taxi,20.00\n\
+             CLAIM-201911-01,,meals,Subtotal,45.50\n\
+             CLAIM-201911-01,,travel,Subtotal,320.00\n\
+             CLAIM-201911-01,,,Total,365.50\n");
+    }
+
+#[test]
+    fn test_claim_html_is_printable() {
+        let claim = Claim::new("CLAIM-201911-01", &sample_report(), NaiveDate::from_ymd(2019, 11, 1), NaiveDate::from_ymd(2019, 12, 1));
+        let page = claim.to_html();
+
+        assert!(page.contains("<title>Expense claim CLAIM-201911-01</title>"));
+        assert!(page.contains("2019-11-01 to 2019-11-30"));
+        assert!(page.contains("@media print"));
+        assert!(page.contains("<td>Subtotal: travel</td>"));
+        assert!(page.contains("365.50"));
+    }
+
+    pub fn next_reference(start_date: NaiveDate, existing: &[String]) -> String {
+        let prefix = format!("CLAIM-{}-", start_date.format("%Y%m"));
+        let count = existing.iter().filter(|r| r.starts_with(&prefix)).count();
+        format!("{}{:02}", prefix, count + 1)
+    }
+
+    pub struct Claim {
+        reference: String,
+        start_date: NaiveDate,
+        end_date_excl: NaiveDate,
+        entries: Vec<CategorisedEntry>
+    }
+
+    impl Claim {
+        /// Claims the unclaimed work expenses in the period, less what the report's refunds gave
+        /// back of them. Those refunded in full are left out.
+        pub fn new(reference: &str, report: &ActivityReport, start_date: NaiveDate, end_date_excl: NaiveDate) -> Claim {
+            let refunded = |debit: &CategorisedEntry| -> Decimal {
+                report.refunds().iter().filter(|r| r.refund_of.as_ref() == Some(&debit.record_fingerprint)).map(|r| r.amount).sum()
+            };
+            let mut entries: Vec<CategorisedEntry> = report.entries().iter()
+                .filter(|e| e.category_type == "work" && e.expense && e.claim_reference.is_none())
+                .filter(|e| e.date >= start_date && e.date < end_date_excl)
+                .map(|e| {
+                    let mut entry = e.clone();
+                    entry.amount -= refunded(e);
+                    entry
+                })
+                .filter(|e| e.amount > Decimal::new(0, 0))
+                .collect();
+            entries.sort_by(|a, b| (&a.category, a.date).cmp(&(&b.category, b.date)));
+
+            Claim { reference: String::from(reference), start_date, end_date_excl, entries }
+        }
+
+        pub fn reference(&self) -> &str {
+            &self.reference
+        }
+
+        pub fn entries(&self) -> &[CategorisedEntry] {
+            &self.entries
+        }
+
+        pub fn subtotals(&self) -> Vec<(String, Decimal)> {
+            let mut subtotals: Vec<(String, Decimal)> = Vec::new();
+            for entry in self.entries.iter() {
+                match subtotals.iter_mut().find(|(category, _)| *category == entry.category) {
+                    Some((_, amount)) => *amount += entry.amount,
+                    None => subtotals.push((entry.category.clone(), entry.amount))
+                }
+            }
+            subtotals
+        }
+
+        pub fn total(&self) -> Decimal {
+            self.entries.iter().map(|e| e.amount).sum()
+        }
+
+        pub fn to_csv(&self) -> String {
+            let mut writer = Writer::from_writer(Vec::new());
+            writer.write_record(["reference", "date", "category", "description", "amount"]).unwrap();
+
+            for entry in self.entries.iter() {
+                writer.write_record(&[
+                    self.reference.clone(),
+                    entry.date.format("%Y-%m-%d").to_string(),
+                    entry.category.clone(),
+                    entry.description.clone(),
+                    money(entry.amount)
+                ]).unwrap();
+            }
+            for (category, amount) in self.subtotals() {
+                writer.write_record(&[self.reference.clone(), String::new(), category, String::from("Subtotal"), money(amount)]).unwrap();
+            }
+            writer.write_record(&[self.reference.clone(), String::new(), String::new(), String::from("Total"), money(self.total())]).unwrap();
+
+            String::from_utf8(writer.into_inner().unwrap()).unwrap()
+        }
+
+        pub fn to_html(&self) -> String {
+            let period = format!("{} to {}", self.start_date.format("%Y-%m-%d"), self.end_date_excl.pred().format("%Y-%m-%d"));
+            let mut rows = String::new();
+
+            for (category, subtotal) in self.subtotals() {
+                for entry in self.entries.iter().filter(|e| e.category == category) {
+                    rows.push_str(&format!(
+                        "<tr><td>{}</td><td>{}</td><td>{}</td><td class=\"amount\">{}</td></tr>\n",
+                        entry.date.format("%Y-%m-%d"),
+                        escape(&entry.category),
+                        escape(&entry.description),
+                        money(entry.amount)
+                    ));
+                }
+                rows.push_str(&format!(
+                    "<tr class=\"subtotal\"><td></td><td>Subtotal: {}</td><td></td><td class=\"amount\">{}</td></tr>\n",
+                    escape(&category),
+                    money(subtotal)
+                ));
+            }
+
+            format!(
+                "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Expense claim {0}</title>\n<style>{1}</style>\n</head>\n<body>\n\
+                 <h1>Expense claim</h1>\n<p><strong>Reference:</strong> {0}<br>\n<strong>Period:</strong> {2}</p>\n\
+                 <table>\n<thead><tr><th>Date</th><th>Category</th><th>Description</th><th>Amount</th></tr></thead>\n<tbody>\n{3}</tbody>\n\
+                 <tfoot><tr class=\"total\"><td></td><td>Total</td><td></td><td class=\"amount\">{4}</td></tr></tfoot>\n</table>\n\
+                 <p class=\"signature\">Claimant signature: ____________________ &nbsp; Date: ____________</p>\n\
+                 <p class=\"signature\">Approved by: ____________________ &nbsp; Date: ____________</p>\n</body>\n</html>\n",
+                escape(&self.reference),
+                STYLE,
+                escape(&period),
+                rows,
+                money(self.total())
+            )
+        }
+    }
+
+    const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; color: #000; }
+table { border-collapse: collapse; width: 100%; margin-bottom: 3em; }
+th, td { border-bottom: 1px solid #999; padding: 4px 8px; text-align: left; }
+td.amount { text-align: right; font-variant-numeric: tabular-nums; }
+tr.subtotal td, tr.total td { font-weight: bold; }
+tr.total td { border-top: 2px solid #000; }
+p.signature { margin-top: 3em; }
+@media print { body { margin: 0; } }
+";
+}
+
+pub mod classifier {
+    use std::collections::BTreeMap;
+    use std::fs::File;
+    use std::path::Path;
+    use csv::{Reader, Writer};
+    use crate::report::CategorisedEntry;
+    use crate::store;
+    #[cfg(test)]
+    use csv::StringRecord;
+    #[cfg(test)]
+    use crate::report::ActivityReport;
+
+    const COLUMNS: [&str; 4] = ["category_type", "category", "token", "count"];
+
+#[test]
+    fn test_tokens() {
+        assert_eq!(tokens("Pizza Express*London 0123 01NOV"), vec!["PIZZA", "EXPRESS", "LONDON"]);
+        assert!(tokens("1234 X").is_empty());
+    }
+
+    /// The words of a description that say something about the merchant, upper cased.
+    pub fn tokens(description: &str) -> Vec<String> {
+        description
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|t| t.len() > 1 && !t.chars().any(|c| c.is_ascii_digit()))
+            .map(|t| t.to_uppercase())
+            .collect()
+    }
+
+    /// A category the classifier thinks a description belongs to, with its probability.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Prediction {
+        pub category_type: String,
+        pub category: String,
+        pub confidence: f64
+    }
+
+    struct Label {
+        category_type: String,
+        category: String,
+        documents: usize,
+        tokens: BTreeMap<String, usize>
+    }
+
+#[test]
+    fn test_predicts_category_from_past_descriptions() {
+        let mut report = ActivityReport::new();
+        report.add_entry("personal", "eating out", &StringRecord::from(vec!["20191101", "PIZZA EXPRESS LEEDS", "-20.00", "0.00"]));
+        report.add_entry("personal", "eating out", &StringRecord::from(vec!["20191102", "NANDOS LEEDS", "-15.00", "0.00"]));
+        report.add_entry("work", "travel", &StringRecord::from(vec!["20191103", "TRAINLINE LONDON", "-80.00", "0.00"]));
+        report.add_entry("work", "travel", &StringRecord::from(vec!["20191104", "TFL TRAVEL LONDON", "-5.00", "0.00"]));
+        let classifier = Classifier::train(report.entries());
+
+        let predictions = classifier.predict("PIZZA EXPRESS YORK");
+        assert_eq!(predictions[0].category_type, "personal");
+        assert_eq!(predictions[0].category, "eating out");
+        assert!(predictions[0].confidence > 0.75);
+        assert!((predictions.iter().map(|p| p.confidence).sum::<f64>() - 1.0).abs() < 1e-9);
+
+        assert_eq!(classifier.predict("TRAINLINE MANCHESTER")[0].category, "travel");
+        assert!(classifier.predict("0123").is_empty());
+        assert!(Classifier::new().predict("PIZZA").is_empty());
+    }
+
+#[test]
+    fn test_classifier_round_trips_through_csv() {
+        let mut report = ActivityReport::new();
+        report.add_entry("personal", "eating out", &StringRecord::from(vec!["20191101", "PIZZA EXPRESS, LEEDS", "-20.00", "0.00"]));
+        report.add_entry("work", "travel", &StringRecord::from(vec!["20191103", "TRAINLINE", "-80.00", "0.00"]));
+        let classifier = Classifier::train(report.entries());
+
+        let path = std::env::temp_dir().join(format!("bsi_model_round_trip_{}.csv", std::process::id()));
+        classifier.save(&path).unwrap();
+        let loaded = Classifier::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(loaded.predict("PIZZA HUT"), classifier.predict("PIZZA HUT"));
+        assert!(Classifier::load(Path::new("/nonexistent/bank_statement_importer_model.csv")).unwrap().is_empty());
+    }
+
+#[test]
+    fn test_loading_a_corrupt_model_names_the_line() {
+        let path = std::env::temp_dir().join(format!("bsi_bad_model_{}.csv", std::process::id()));
+        std::fs::write(&path, "category_type,category,token,count\npersonal,groceries,,two\n").unwrap();
+
+        let loaded = Classifier::load(&path);
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(loaded.err().unwrap().to_string(), "line 2: invalid count two");
+    }
+
+    /// A naive Bayes classifier over the words of past descriptions, labelled by type and category.
+    pub struct Classifier {
+        labels: Vec<Label>
+    }
+
+    impl Default for Classifier {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Classifier {
+        pub fn new() -> Classifier {
+            Classifier { labels: Vec::new() }
+        }
+
+        pub fn train(entries: &[CategorisedEntry]) -> Classifier {
+            let mut classifier = Classifier::new();
+            for entry in entries.iter().filter(|e| e.transfer.is_none()) {
+                classifier.learn(&entry.category_type, &entry.category, &entry.description);
+            }
+            classifier
+        }
+
+        pub fn learn(&mut self, category_type: &str, category: &str, description: &str) {
+            let label = self.label(category_type, category);
+            label.documents += 1;
+            for token in tokens(description) {
+                *label.tokens.entry(token).or_insert(0) += 1;
+            }
+        }
+
+        fn label(&mut self, category_type: &str, category: &str) -> &mut Label {
+            let position = match self.labels.iter().position(|l| l.category_type == category_type && l.category == category) {
+                Some(position) => position,
+                None => {
+                    self.labels.push(Label { category_type: String::from(category_type), category: String::from(category), documents: 0, tokens: BTreeMap::new() });
+                    self.labels.len() - 1
+                }
+            };
+            &mut self.labels[position]
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.labels.is_empty()
+        }
+
+        /// Every known category, most likely first. Empty when the description has no words to go on.
+        pub fn predict(&self, description: &str) -> Vec<Prediction> {
+            let words = tokens(description);
+            if words.is_empty() || self.labels.is_empty() {
+                return Vec::new();
+            }
+
+            let documents: usize = self.labels.iter().map(|l| l.documents).sum();
+            let mut vocabulary: Vec<&String> = self.labels.iter().flat_map(|l| l.tokens.keys()).collect();
+            vocabulary.sort();
+            vocabulary.dedup();
+
+            let scores: Vec<f64> = self.labels.iter().map(|label| {
+                let label_tokens: usize = label.tokens.values().sum();
+                let prior = (label.documents as f64 / documents as f64).ln();
+                words.iter().fold(prior, |score, word| {
+                    let count = label.tokens.get(word).cloned().unwrap_or(0);
+                    score + ((count + 1) as f64 / (label_tokens + vocabulary.len()) as f64).ln()
+                })
+            }).collect();
+
+            // Scale by the best score before exponentiating so that long descriptions do not underflow
+            let best = scores.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            let weights: Vec<f64> = scores.iter().map(|s| (s - best).exp()).collect();
+            let total: f64 = weights.iter().sum();
+
+            let mut predictions: Vec<Prediction> = self.labels.iter().zip(weights).map(|(label, weight)| Prediction {
+                category_type: label.category_type.clone(),
+                category: label.category.clone(),
+                confidence: weight / total
+            }).collect();
+            predictions.sort_by(|a, b| b.confidence.partial_cmp(&a.confidence).unwrap());
+            predictions
+        }
+
+        /// Reads a model saved by `save`. A missing file gives an untrained classifier.
+        pub fn load(path: &Path) -> Result<Classifier, csv::Error> {
+            let mut classifier = Classifier::new();
+
+            if !path.exists() {
+                return Ok(classifier);
+            }
+
+            let mut reader = Reader::from_path(path)?;
+            for result in reader.records() {
+                let record = result?;
+                let field = |index: usize| record.get(index).unwrap_or("");
+                let count: usize = field(3).parse().map_err(|_| store::invalid_row(&record, &format!("invalid count {}", field(3))))?;
+                let label = classifier.label(field(0), field(1));
+
+                if field(2).is_empty() {
+                    label.documents = count;
+                } else {
+                    label.tokens.insert(String::from(field(2)), count);
+                }
+            }
+
+            Ok(classifier)
+        }
+
+        /// Writes one row per category and word, plus a row with an empty word holding the number
+        /// of entries in the category.
+        pub fn save(&self, path: &Path) -> Result<(), csv::Error> {
+            let mut writer = Writer::from_writer(File::create(path)?);
+            writer.write_record(COLUMNS)?;
+
+            for label in self.labels.iter() {
+                writer.write_record([&label.category_type[..], &label.category, "", &label.documents.to_string()])?;
+                for (token, count) in label.tokens.iter() {
+                    writer.write_record([&label.category_type[..], &label.category, token, &count.to_string()])?;
+                }
+            }
+
+            writer.flush()?;
+            Ok(())
+        }
+    }
+}
+
+pub mod ui {
+    use std::io;
+    use std::fs;
+    use std::path::Path;
+    use std::collections::VecDeque;
+    use csv::StringRecord;
+    use crate::report::{ActivityReport, CategorisedEntry, ACCOUNT, money};
+    use crate::classifier::Prediction;
+
+#[test]
+    fn test_navigation() {
+        assert_eq!(navigation(":s"), Some(Navigation::Skip));
+        assert_eq!(navigation(":back"), Some(Navigation::Back));
+        assert_eq!(navigation(":u"), Some(Navigation::Undo));
+        assert_eq!(navigation(":cancel"), Some(Navigation::Cancel));
+        assert_eq!(navigation("s"), None);
+        assert_eq!(navigation("undo"), None);
+    }
+
+    /// Commands accepted at every prompt instead of an answer.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Navigation {
+        /// Come back to this entry after the others.
+        Skip,
+        /// Revisit the previous entry, keeping any configuration it added.
+        Back,
+        /// Revisit the previous entry, removing any category or pattern it added.
+        Undo,
+        /// Abandon the import, writing nothing.
+        Cancel
+    }
+
+    pub type Prompted<T> = Result<T, Navigation>;
+
+    /// What to do with a group of entries that one pattern mapped to one category.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Review {
+        /// Keep the category the pattern chose.
+        Accept,
+        /// Move the entries, and the pattern, to another category.
+        Reassign(String),
+        /// Replace the pattern; entries it no longer matches are classified again.
+        Refine(String)
+    }
+
+    pub fn navigation(answer: &str) -> Option<Navigation> {
+        match answer {
+            ":s" | ":skip" => Some(Navigation::Skip),
+            ":b" | ":back" => Some(Navigation::Back),
+            ":u" | ":undo" => Some(Navigation::Undo),
+            ":c" | ":cancel" => Some(Navigation::Cancel),
+            _ => None
+        }
+    }
+
+    fn interpret(answer: Option<String>) -> Prompted<Option<String>> {
+        match answer {
+            Some(answer) => match navigation(&answer) {
+                Some(command) => Err(command),
+                None => Ok(Some(answer))
+            },
+            None => Ok(None)
+        }
+    }
+
+    pub trait UserInterface {
+        /// Called once with every entry about to be classified. The rows the other methods are
+        /// given are positions in `entries`, so that identical entries can be told apart.
+        fn begin(&mut self, _entries: &[StringRecord]) {
+        }
+
+        fn display_entry(&mut self, row: usize, entry: &StringRecord);
+
+        /// Returns `None` when the type should be inferred from the configured patterns.
+        fn get_type(&mut self) -> Prompted<Option<&'static str>>;
+
+        fn display_automap(&mut self, category: &str);
+
+        /// Shows what the classifier makes of an entry no pattern matches, most likely first.
+        fn display_predictions(&mut self, _predictions: &[Prediction]) {
+        }
+
+        /// Shows `categories` numbered in the order given, which is the order `capture_category` uses.
+        fn display_categories(&mut self, category_type: &str, categories: &[String]);
+
+        /// Returns one of `categories`, or a confirmed new category. Returns `None` when the entry
+        /// should be deferred to the pending queue.
+        fn capture_category(&mut self, categories: &[String]) -> Prompted<Option<String>>;
+
+        /// Returns a new pattern for the category just chosen, offering `suggestions` taken from the
+        /// description along with how many other pending entries each would match.
+        fn capture_pattern(&mut self, suggestions: &[(String, usize)]) -> Prompted<Option<String>>;
+
+        /// Asks what to do with `entries`, at `rows`, which `pattern` mapped to `category`. Skipping
+        /// leaves the whole group pending.
+        fn review_group(&mut self, category_type: &str, category: &str, pattern: &str, rows: &[usize], entries: &[StringRecord], categories: &[String]) -> Prompted<Review>;
+
+        /// Asks whether `debit` and `credit`, the same amount leaving one account and reaching
+        /// another, are a transfer between the two. `rows` holds the row of each. Anything but a
+        /// clear yes leaves them to be classified separately.
+        fn confirm_transfer(&mut self, rows: (usize, usize), debit: &StringRecord, credit: &StringRecord) -> Prompted<bool>;
+
+        /// Asks whether `refund`, a credit from the same merchant as the earlier debit `original`,
+        /// refunds it in full or in part. `row` is the refund's row. Both are asked about before
+        /// they are classified, so `original` has no category if it is being imported too.
+        fn confirm_refund(&mut self, row: usize, refund: &CategorisedEntry, original: &CategorisedEntry) -> Prompted<bool>;
+
+        /// Called whenever the report changes.
+        fn display_totals(&mut self, _report: &ActivityReport) {
+        }
+
+        /// Tells the user something that needs no answer, such as why a command did nothing.
+        fn notice(&mut self, message: &str) {
+            println!("{}", message);
+        }
+
+        /// Called once classification is over, before anything else is written to the terminal.
+        fn finish(&mut self) {
+        }
+    }
+
+    fn print_entry(entry: &StringRecord) {
+        println!("{} {} {}", entry.get(0).unwrap(), entry.get(1).unwrap(), entry.get(2).unwrap());
+    }
+
+    fn print_transfer(debit: &StringRecord, credit: &StringRecord) {
+        println!();
+        println!("Possible transfer from {} to {}", debit.get(ACCOUNT).unwrap_or(""), credit.get(ACCOUNT).unwrap_or(""));
+        print_entry(debit);
+        print_entry(credit);
+    }
+
+    fn print_refund(refund: &CategorisedEntry, original: &CategorisedEntry) {
+        println!();
+        // Debits being imported alongside their refund have no category yet
+        let category = if original.category.is_empty() { String::new() } else { format!(" in {}", original.category) };
+        println!("Possible refund of {} {} {}{}", original.date.format("%Y%m%d"), original.description, money(original.amount), category);
+        println!("{} {} {}", refund.date.format("%Y%m%d"), refund.description, money(refund.amount));
+    }
+
+    fn print_predictions(predictions: &[Prediction]) {
+        if !predictions.is_empty() {
+            let described: Vec<String> = predictions.iter().map(|p| format!("{}/{} ({:.0}%)", p.category_type, p.category, p.confidence * 100.0)).collect();
+            println!("Suggested: {}", described.join(", "));
+        }
+    }
+
+    fn read_answer() -> Option<String> {
+        let mut answer = String::new();
+        match io::stdin().read_line(&mut answer) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(String::from(answer.trim()))
+        }
+    }
+
+#[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("groceries", "groceries"), 0);
+        assert_eq!(edit_distance("grocreies", "groceries"), 2);
+        assert_eq!(edit_distance("Groceries", "groceries"), 0);
+        assert_eq!(edit_distance("", "fuel"), 4);
+        assert_eq!(edit_distance("fuel", "fee"), 2);
+    }
+
+    pub fn edit_distance(a: &str, b: &str) -> usize {
+        let a: Vec<char> = a.to_lowercase().chars().collect();
+        let b: Vec<char> = b.to_lowercase().chars().collect();
+        let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+        for (i, ca) in a.iter().enumerate() {
+            let mut current = vec![i + 1];
+            for (j, cb) in b.iter().enumerate() {
+                let substitution = previous[j] + if ca == cb { 0 } else { 1 };
+                current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+            }
+            previous = current;
+        }
+
+        previous[b.len()]
+    }
+
+#[test]
+    fn test_close_matches() {
+        let categories: Vec<String> = vec!["groceries", "garden", "fuel", "eating out"].into_iter().map(String::from).collect();
+
+        assert_eq!(close_matches("grocries", &categories), vec!["groceries"]);
+        assert_eq!(close_matches("fule", &categories), vec!["fuel"]);
+        assert!(close_matches("insurance", &categories).is_empty());
+    }
+
+    pub fn close_matches(input: &str, categories: &[String]) -> Vec<String> {
+        let limit = (input.chars().count() / 3).max(2);
+        let mut matches: Vec<(usize, &String)> = categories.iter()
+            .map(|c| (edit_distance(input, c), c))
+            .filter(|(distance, _)| *distance <= limit)
+            .collect();
+        matches.sort();
+        matches.into_iter().map(|(_, c)| c.clone()).collect()
+    }
+
+#[test]
+    fn test_filter_categories() {
+        let categories: Vec<String> = vec!["groceries", "garden", "fuel", "eating out"].into_iter().map(String::from).collect();
+
+        assert_eq!(filter_categories("g", &categories), vec![(1, String::from("groceries")), (2, String::from("garden"))]);
+        assert_eq!(filter_categories("EAT", &categories), vec![(4, String::from("eating out"))]);
+        assert_eq!(filter_categories("gdn", &categories), vec![(2, String::from("garden"))]);
+        assert!(filter_categories("xyz", &categories).is_empty());
+    }
+
+    /// Categories starting with `input`, or failing that containing its characters in order, with
+    /// their position in the numbered list.
+    pub fn filter_categories(input: &str, categories: &[String]) -> Vec<(usize, String)> {
+        let input = input.to_lowercase();
+        let numbered = categories.iter().enumerate().map(|(i, c)| (i + 1, c.clone()));
+
+        let prefixed: Vec<(usize, String)> = numbered.clone().filter(|(_, c)| c.to_lowercase().starts_with(&input)).collect();
+        if !prefixed.is_empty() {
+            return prefixed;
+        }
+
+        numbered.filter(|(_, c)| {
+            let mut chars = c.to_lowercase().chars().collect::<Vec<char>>().into_iter();
+            input.chars().all(|i| chars.any(|c| c == i))
+        }).collect()
+    }
+
+#[test]
+    fn test_suggest_patterns() {
+        assert_eq!(suggest_patterns("TESCO STORES 3345 LONDON"), vec!["TESCO", "TESCO STORES"]);
+        assert_eq!(suggest_patterns("CARD 1234 01NOV19 AMAZON MKTPLACE REF AB12CD"), vec!["AMAZON", "AMAZON MKTPLACE"]);
+        assert_eq!(suggest_patterns("XXXX5678 12 NOV SHELL  GARAGE"), vec!["SHELL"]);
+        assert!(suggest_patterns("123456 01/11/2019").is_empty());
+    }
+
+    const MONTHS: [&str; 12] = ["JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC"];
+    const NOISE_WORDS: [&str; 5] = ["CARD", "POS", "DD", "SO", "REF"];
+
+    /// Whether a word from a description changes from one transaction to the next: card numbers,
+    /// dates, reference codes and the like.
+    fn varies(word: &str) -> bool {
+        let upper = word.to_uppercase();
+        word.chars().any(|c| c.is_ascii_digit())
+            || upper.chars().all(|c| c == 'X' || c == '*' || !c.is_alphanumeric())
+            || NOISE_WORDS.contains(&&upper[..])
+            || MONTHS.contains(&&upper[..])
+    }
+
+    /// Candidate patterns for a description, shortest first: the merchant word and the words that
+    /// follow it, leaving out anything that varies between transactions with the same merchant.
+    pub fn suggest_patterns(description: &str) -> Vec<String> {
+        let words: Vec<&str> = description.split_whitespace().collect();
+        let merchant: Vec<&str> = words.iter().skip_while(|w| varies(w)).take_while(|w| !varies(w)).cloned().collect();
+
+        (1..=merchant.len().min(3))
+            .map(|count| merchant[..count].join(" "))
+            .filter(|candidate| candidate.len() >= 3 && description.contains(&candidate[..]))
+            .collect()
+    }
+
+#[test]
+    fn test_choose_pattern() {
+        let suggestions = vec![(String::from("TESCO"), 2), (String::from("TESCO STORES"), 0)];
+
+        assert_eq!(choose_pattern(Some(String::from("2")), &suggestions), Some(String::from("TESCO STORES")));
+        assert_eq!(choose_pattern(Some(String::from("TESC")), &suggestions), Some(String::from("TESC")));
+        assert_eq!(choose_pattern(Some(String::from("")), &suggestions), None);
+        assert_eq!(choose_pattern(None, &suggestions), None);
+    }
+
+    /// Takes a suggestion by its number, or the answer itself as the pattern.
+    pub fn choose_pattern(answer: Option<String>, suggestions: &[(String, usize)]) -> Option<String> {
+        let answer = answer.filter(|p| !p.is_empty())?;
+        match answer.parse::<usize>().ok().and_then(|n| suggestions.get(n.wrapping_sub(1))) {
+            Some((suggestion, _)) => Some(suggestion.clone()),
+            None => Some(answer)
+        }
+    }
+
+    fn print_suggestions(suggestions: &[(String, usize)]) {
+        for (index, (suggestion, matches)) in suggestions.iter().enumerate() {
+            println!("{}. {} (matches {} other pending)", index + 1, suggestion, matches);
+        }
+    }
+
+#[test]
+    fn test_choose_category() {
+        let categories: Vec<String> = vec![String::from("groceries"), String::from("fuel"), String::from("garden")];
+        let answers = |list: Vec<&str>| {
+            let mut answers: VecDeque<String> = list.into_iter().map(String::from).collect();
+            move |_prompt: &str| interpret(answers.pop_front())
+        };
+
+        assert_eq!(choose_category(&categories, answers(vec!["fuel"])), Ok(Some(String::from("fuel"))));
+        assert_eq!(choose_category(&categories, answers(vec!["2"])), Ok(Some(String::from("fuel"))));
+        assert_eq!(choose_category(&categories, answers(vec!["4", "3"])), Ok(Some(String::from("garden"))));
+        assert_eq!(choose_category(&categories, answers(vec!["gro"])), Ok(Some(String::from("groceries"))));
+        assert_eq!(choose_category(&categories, answers(vec!["g", "3"])), Ok(Some(String::from("garden"))));
+        assert_eq!(choose_category(&categories, answers(vec!["grocries", "groceries"])), Ok(Some(String::from("groceries"))));
+        assert_eq!(choose_category(&categories, answers(vec!["c", "rent", "n", "c", "rent", "y"])), Ok(Some(String::from("rent"))));
+        assert_eq!(choose_category(&categories, answers(vec!["c", "fuel"])), Ok(Some(String::from("fuel"))));
+        assert_eq!(choose_category(&categories, answers(vec!["grocries"])), Ok(None));
+        assert_eq!(choose_category(&categories, answers(vec!["gro", ":b"])), Ok(Some(String::from("groceries"))));
+        assert_eq!(choose_category(&categories, answers(vec!["c", ":u"])), Err(Navigation::Undo));
+        assert_eq!(choose_category(&categories, answers(vec![":s"])), Err(Navigation::Skip));
+    }
+
+    /// Asks for a category until the answer names an existing one or a new category is confirmed.
+    /// Existing categories can be given by number, by name, or by a prefix that only one of them has.
+    /// Anything to tell the user about the previous answer is put ahead of the next prompt.
+    pub fn choose_category<F>(categories: &[String], mut ask: F) -> Prompted<Option<String>> where F: FnMut(&str) -> Prompted<Option<String>> {
+        let mut notices: Vec<String> = Vec::new();
+
+        loop {
+            notices.push(String::from("Enter a number or name, a few letters to filter, or 'c' to add a category"));
+            let prompt = notices.join("\n");
+            notices.clear();
+
+            let choice = match ask(&prompt)? {
+                Some(choice) => choice,
+                None => return Ok(None)
+            };
+
+            if choice == "c" {
+                let new_category = match ask("Enter the name of the new category")? {
+                    Some(new_category) => new_category,
+                    None => return Ok(None)
+                };
+                if new_category.is_empty() {
+                    continue;
+                }
+                if categories.contains(&new_category) {
+                    return Ok(Some(new_category));
+                }
+                let confirmation = ask(&format!("Create new category '{}'? Enter 'y' to confirm", new_category))?;
+                if confirmation.as_deref() == Some("y") {
+                    return Ok(Some(new_category));
+                }
+            } else if categories.contains(&choice) {
+                return Ok(Some(choice));
+            } else if let Ok(number) = choice.parse::<usize>() {
+                match categories.get(number.wrapping_sub(1)) {
+                    Some(category) => return Ok(Some(category.clone())),
+                    None => notices.push(format!("There is no category numbered {}", number))
+                }
+            } else if !choice.is_empty() {
+                let candidates = filter_categories(&choice, categories);
+                let prefixed = candidates.iter().all(|(_, c)| c.to_lowercase().starts_with(&choice.to_lowercase()));
+
+                if candidates.len() == 1 && prefixed {
+                    return Ok(Some(candidates[0].1.clone()));
+                } else if !candidates.is_empty() {
+                    for (number, category) in candidates {
+                        notices.push(format!("{}. {}", number, category));
+                    }
+                } else {
+                    notices.push(format!("Category {} does not exist", choice));
+                    let suggestions = close_matches(&choice, categories);
+                    if !suggestions.is_empty() {
+                        notices.push(format!("Did you mean: {}", suggestions.join(", ")));
+                    }
+                }
+            }
+        }
+    }
+
+    pub struct UI {
+    }
+
+    impl UserInterface for UI {
+        fn display_entry(&mut self, _row: usize, entry: &StringRecord) {
+            print_entry(entry);
+        }
+
+        fn get_type(&mut self) -> Prompted<Option<&'static str>> {
+            println!("Enter 'p' for personal or 'w' for work (or ':s' to skip, ':b' to go back, ':u' to undo, ':c' to cancel the import)");
+
+            match interpret(read_answer())?.as_deref() {
+                Some("p") => Ok(Some("personal")),
+                Some(_) => Ok(Some("work")),
+                None => Ok(None)
+            }
+        }
+
+        fn display_automap(&mut self, category: &str) {
+            println!("Automagically mapped to {}", category);
+        }
+
+        fn display_predictions(&mut self, predictions: &[Prediction]) {
+            print_predictions(predictions);
+        }
+
+        fn display_categories(&mut self, category_type: &str, categories: &[String]) {
+            println!("Existing {} categories", category_type);
+            println!();
+            for (index, cat) in categories.iter().enumerate() {
+                println!("{}. {}", index + 1, cat);
+            }
+        }
+
+        fn capture_category(&mut self, categories: &[String]) -> Prompted<Option<String>> {
+            choose_category(categories, |prompt| {
+                println!("{}", prompt);
+                interpret(read_answer())
+            })
+        }
+
+        fn capture_pattern(&mut self, suggestions: &[(String, usize)]) -> Prompted<Option<String>> {
+            if suggestions.is_empty() {
+                println!("Provide a pattern for this category or just hit enter");
+            } else {
+                println!("Suggested patterns");
+                print_suggestions(suggestions);
+                println!("Enter a number to use a suggestion, type a pattern or just hit enter");
+            }
+
+            Ok(choose_pattern(interpret(read_answer())?, suggestions))
+        }
+
+        fn review_group(&mut self, category_type: &str, category: &str, pattern: &str, _rows: &[usize], entries: &[StringRecord], categories: &[String]) -> Prompted<Review> {
+            println!();
+            println!("{} {} entries mapped to {} {} by '{}'", entries.len(), category_type, category_type, category, pattern);
+            for entry in entries {
+                print_entry(entry);
+            }
+
+            loop {
+                println!("Enter 'a' to accept, 'r' to reassign the group, 'e' to edit the pattern (or ':s' to leave pending)");
+
+                match interpret(read_answer())?.as_deref() {
+                    Some("a") | None => return Ok(Review::Accept),
+                    Some("r") => {
+                        self.display_categories(category_type, categories);
+                        let chosen = choose_category(categories, |prompt| {
+                            println!("{}", prompt);
+                            interpret(read_answer())
+                        })?;
+                        if let Some(chosen) = chosen {
+                            return Ok(Review::Reassign(chosen));
+                        }
+                    },
+                    Some("e") => {
+                        println!("Enter the new pattern");
+                        if let Some(refined) = interpret(read_answer())?.filter(|p| !p.is_empty()) {
+                            return Ok(Review::Refine(refined));
+                        }
+                    },
+                    Some(_) => ()
+                }
+            }
+        }
+
+        fn confirm_transfer(&mut self, _rows: (usize, usize), debit: &StringRecord, credit: &StringRecord) -> Prompted<bool> {
+            print_transfer(debit, credit);
+            println!("Enter 'y' if this is a transfer between your accounts, 'n' if not");
+
+            Ok(interpret(read_answer())?.as_deref() == Some("y"))
+        }
+
+        fn confirm_refund(&mut self, _row: usize, refund: &CategorisedEntry, original: &CategorisedEntry) -> Prompted<bool> {
+            print_refund(refund, original);
+            println!("Enter 'y' if this refunds the earlier purchase, 'n' to classify it as usual");
+
+            Ok(interpret(read_answer())?.as_deref() == Some("y"))
+        }
+    }
+
+#[test]
+    fn test_scripted_ui_answers_in_order() {
+        let mut ui = ScriptedUI::new(vec!["p", "groceries", "SHOP", "w", "c", "travel", "y", "", "x"].into_iter().map(String::from).collect());
+
+        assert_eq!(ui.get_type(), Ok(Some("personal")));
+        assert_eq!(ui.capture_category(&[String::from("groceries")]), Ok(Some(String::from("groceries"))));
+        assert_eq!(ui.capture_pattern(&[]), Ok(Some(String::from("SHOP"))));
+        assert_eq!(ui.get_type(), Ok(Some("work")));
+        assert_eq!(ui.capture_category(&[]), Ok(Some(String::from("travel"))));
+        assert_eq!(ui.capture_pattern(&[]), Ok(None));
+        assert_eq!(ui.get_type(), Ok(None));
+    }
+
+#[test]
+    fn test_scripted_ui_defers_once_answers_run_out() {
+        let mut ui = ScriptedUI::new(vec![String::from("p")]);
+
+        assert_eq!(ui.get_type(), Ok(Some("personal")));
+        assert_eq!(ui.capture_category(&[]), Ok(None));
+        assert_eq!(ui.capture_pattern(&[]), Ok(None));
+        assert_eq!(ui.get_type(), Ok(None));
+    }
+
+#[test]
+    fn test_scripted_ui_review() {
+        let mut ui = ScriptedUI::new(vec!["a", "r", "2", "e", "SHOP 12", ":s"].into_iter().map(String::from).collect());
+        let categories = vec![String::from("groceries"), String::from("fuel")];
+
+        assert_eq!(ui.review_group("personal", "groceries", "SHOP", &[], &[], &categories), Ok(Review::Accept));
+        assert_eq!(ui.review_group("personal", "groceries", "SHOP", &[], &[], &categories), Ok(Review::Reassign(String::from("fuel"))));
+        assert_eq!(ui.review_group("personal", "groceries", "SHOP", &[], &[], &categories), Ok(Review::Refine(String::from("SHOP 12"))));
+        assert_eq!(ui.review_group("personal", "groceries", "SHOP", &[], &[], &categories), Err(Navigation::Skip));
+        assert_eq!(ui.review_group("personal", "groceries", "SHOP", &[], &[], &categories), Ok(Review::Accept));
+    }
+
+#[test]
+    fn test_scripted_ui_transfers() {
+        let mut ui = ScriptedUI::new(vec!["y", "n", ":s"].into_iter().map(String::from).collect());
+        let debit = StringRecord::from(vec!["20191101", "CARD PAYMENT", "-500.00", "0.00", "everyday"]);
+        let credit = StringRecord::from(vec!["20191102", "PAYMENT RECEIVED", "500.00", "0.00", "card"]);
+
+        assert_eq!(ui.confirm_transfer((0, 1), &debit, &credit), Ok(true));
+        assert_eq!(ui.confirm_transfer((0, 1), &debit, &credit), Ok(false));
+        assert_eq!(ui.confirm_transfer((0, 1), &debit, &credit), Err(Navigation::Skip));
+        assert_eq!(ui.confirm_transfer((0, 1), &debit, &credit), Ok(false));
+    }
+
+#[test]
+    fn test_scripted_ui_refunds() {
+        let mut ui = ScriptedUI::new(vec!["y", "n"].into_iter().map(String::from).collect());
+        let mut report = ActivityReport::new();
+        report.add_entry("personal", "clothing", &StringRecord::from(vec!["20191101", "OUTFITTERS", "-100.00", "0.00"]));
+        report.add_entry("personal", "salary", &StringRecord::from(vec!["20191108", "OUTFITTERS REFUND", "30.00", "0.00"]));
+        let (original, refund) = (&report.entries()[0], &report.entries()[1]);
+
+        assert_eq!(ui.confirm_refund(1, refund, original), Ok(true));
+        assert_eq!(ui.confirm_refund(1, refund, original), Ok(false));
+        assert_eq!(ui.confirm_refund(1, refund, original), Ok(false));
+    }
 
-        assert_ne!(
-            CategorisedEntry {
-                category_type: String::from("personal"),
-                category: String::from("bar"),
-                date: NaiveDate::from_ymd(2019,11,1),
-                description: String::from("fizzy"),
-                expense: true,
-                amount: Decimal::new(100,0),
-                record_fingerprint: String::from("abc123")
-            },
-            CategorisedEntry {
-                category_type: String::from("work"),
-                category: String::from("bar"),
-                date: NaiveDate::from_ymd(2019,11,1),
-                description: String::from("fuzzy"),
-                expense: true,
-                amount: Decimal::new(100,0),
-                record_fingerprint: String::from("abc123")
-            },
-            );
+#[test]
+    fn test_scripted_ui_navigation() {
+        let mut ui = ScriptedUI::new(vec![":s", "p", ":b", ":undo"].into_iter().map(String::from).collect());
 
-        assert_ne!(
-            CategorisedEntry {
-                category_type: String::from("personal"),
-                category: String::from("bar"),
-                date: NaiveDate::from_ymd(2019,11,1),
-                description: String::from("fuzzy"),
-                expense: false,
-                amount: Decimal::new(100,0),
-                record_fingerprint: String::from("abc123")
-            },
-            CategorisedEntry {
-                category_type: String::from("work"),
-                category: String::from("bar"),
-                date: NaiveDate::from_ymd(2019,11,1),
-                description: String::from("fuzzy"),
-                expense: true,
-                amount: Decimal::new(100,0),
-                record_fingerprint: String::from("abc123")
-            },
-            );
+        assert_eq!(ui.get_type(), Err(Navigation::Skip));
+        assert_eq!(ui.get_type(), Ok(Some("personal")));
+        assert_eq!(ui.capture_category(&[]), Err(Navigation::Back));
+        assert_eq!(ui.capture_pattern(&[]), Err(Navigation::Undo));
+    }
 
-        assert_ne!(
-            CategorisedEntry {
-                category_type: String::from("personal"),
-                category: String::from("bar"),
-                date: NaiveDate::from_ymd(2019,11,1),
-                description: String::from("fuzzy"),
-                expense: true,
-                amount: Decimal::new(100,0),
-                record_fingerprint: String::from("abc123")
-            },
-            CategorisedEntry {
-                category_type: String::from("work"),
-                category: String::from("bar"),
-                date: NaiveDate::from_ymd(2019,11,1),
-                description: String::from("fuzzy"),
-                expense: true,
-                amount: Decimal::new(100,0),
-                record_fingerprint: String::from("abc123")
-            },
-            );
+    /// Answers prompts from a script, one answer per line, in the order the terminal would ask them.
+    pub struct ScriptedUI {
+        answers: VecDeque<String>
     }
 
-#[derive(Debug)]
-    struct CategorisedEntry {
-        category_type: String,
-        category: String,
-        date: NaiveDate,
-        description: String,
-        expense: bool,
-        amount: Decimal,
-        record_fingerprint: String
+    impl ScriptedUI {
+        pub fn new(answers: Vec<String>) -> ScriptedUI {
+            ScriptedUI { answers: answers.into_iter().collect() }
+        }
+
+        pub fn from_file(path: &Path) -> io::Result<ScriptedUI> {
+            let contents = fs::read_to_string(path)?;
+            Ok(ScriptedUI::new(contents.lines().map(|l| String::from(l.trim())).collect()))
+        }
+
+        fn next_answer(&mut self) -> Option<String> {
+            self.answers.pop_front()
+        }
     }
 
-    impl CategorisedEntry {
-        fn new(category_type: &str, category: &str, original_record: &StringRecord) -> CategorisedEntry {
+    impl UserInterface for ScriptedUI {
+        fn display_entry(&mut self, _row: usize, entry: &StringRecord) {
+            print_entry(entry);
+        }
 
-            let amount = original_record.get(2).unwrap().trim().parse::<f32>().unwrap();
+        fn get_type(&mut self) -> Prompted<Option<&'static str>> {
+            match interpret(self.next_answer())?.as_deref() {
+                Some("p") => Ok(Some("personal")),
+                Some("w") => Ok(Some("work")),
+                _ => Ok(None)
+            }
+        }
 
-            let mut hasher = Sha256::new();
-            hasher.input(original_record.get(0).unwrap().trim());
-            hasher.input(original_record.get(1).unwrap().trim());
-            hasher.input(original_record.get(2).unwrap().trim());
-            hasher.input(original_record.get(3).unwrap().trim());
+        fn display_automap(&mut self, category: &str) {
+            println!("Automagically mapped to {}", category);
+        }
 
-            CategorisedEntry {
-                category_type: String::from(category_type),
-                category: String::from(category),
-                date: NaiveDate::parse_from_str(original_record.get(0).unwrap().trim(), "%Y%m%d").unwrap(),
-                description: String::from(original_record.get(1).unwrap().trim()),
-                expense: amount < 0.0,
-                amount: Decimal::from_str(&format!("{}", amount.abs())).unwrap(),
-                record_fingerprint: hex::encode(hasher.result())
+        fn display_predictions(&mut self, predictions: &[Prediction]) {
+            print_predictions(predictions);
+        }
+
+        fn display_categories(&mut self, _category_type: &str, _categories: &[String]) {
+        }
+
+        fn capture_category(&mut self, categories: &[String]) -> Prompted<Option<String>> {
+            choose_category(categories, |_prompt| interpret(self.next_answer()))
+        }
+
+        fn capture_pattern(&mut self, suggestions: &[(String, usize)]) -> Prompted<Option<String>> {
+            Ok(choose_pattern(interpret(self.next_answer())?, suggestions))
+        }
+
+        /// Accepts the group once the answers run out, so that a script can leave review answers off.
+        fn review_group(&mut self, _category_type: &str, _category: &str, _pattern: &str, _rows: &[usize], _entries: &[StringRecord], categories: &[String]) -> Prompted<Review> {
+            match interpret(self.next_answer())?.as_deref() {
+                Some("r") => match choose_category(categories, |_prompt| interpret(self.next_answer()))? {
+                    Some(chosen) => Ok(Review::Reassign(chosen)),
+                    None => Ok(Review::Accept)
+                },
+                Some("e") => match interpret(self.next_answer())?.filter(|p| !p.is_empty()) {
+                    Some(refined) => Ok(Review::Refine(refined)),
+                    None => Ok(Review::Accept)
+                },
+                _ => Ok(Review::Accept)
             }
         }
+
+        /// Treats the pair as a transfer only when the answer is 'y', so that running out of answers
+        /// leaves it to be classified as usual.
+        fn confirm_transfer(&mut self, _rows: (usize, usize), debit: &StringRecord, credit: &StringRecord) -> Prompted<bool> {
+            print_transfer(debit, credit);
+            Ok(interpret(self.next_answer())?.as_deref() == Some("y"))
+        }
+
+        /// Treats the credit as a refund only when the answer is 'y', as with transfers.
+        fn confirm_refund(&mut self, _row: usize, refund: &CategorisedEntry, original: &CategorisedEntry) -> Prompted<bool> {
+            print_refund(refund, original);
+            Ok(interpret(self.next_answer())?.as_deref() == Some("y"))
+        }
     }
 
-    impl PartialEq for CategorisedEntry {
-        fn eq(&self, other: &Self) -> bool {
-            self.category_type == other.category_type &&
-                self.category == other.category &&
-                self.date == other.date &&
-                self.description == other.description &&
-                self.expense == other.expense && 
-                self.amount == other.amount
+#[test]
+    fn test_auto_ui_never_answers() {
+        let mut ui = AutoUI {};
+
+        assert_eq!(ui.get_type(), Ok(None));
+        assert_eq!(ui.capture_category(&[String::from("groceries")]), Ok(None));
+        assert_eq!(ui.capture_pattern(&[]), Ok(None));
+        assert_eq!(ui.review_group("personal", "groceries", "SHOP", &[], &[], &[]), Ok(Review::Accept));
+        assert_eq!(ui.confirm_transfer((0, 1), &StringRecord::new(), &StringRecord::new()), Ok(false));
+        let mut report = ActivityReport::new();
+        report.add_entry("personal", "clothing", &StringRecord::from(vec!["20191101", "OUTFITTERS", "-100.00", "0.00"]));
+        assert_eq!(ui.confirm_refund(0, &report.entries()[0], &report.entries()[0]), Ok(false));
+    }
+
+    /// Relies on the configured patterns alone; anything they do not match is left pending.
+    pub struct AutoUI {
+    }
+
+    impl UserInterface for AutoUI {
+        fn display_entry(&mut self, _row: usize, entry: &StringRecord) {
+            print_entry(entry);
+        }
+
+        fn get_type(&mut self) -> Prompted<Option<&'static str>> {
+            Ok(None)
+        }
+
+        fn display_automap(&mut self, category: &str) {
+            println!("Automagically mapped to {}", category);
+        }
+
+        fn display_categories(&mut self, _category_type: &str, _categories: &[String]) {
+        }
+
+        fn capture_category(&mut self, _categories: &[String]) -> Prompted<Option<String>> {
+            Ok(None)
+        }
+
+        fn capture_pattern(&mut self, _suggestions: &[(String, usize)]) -> Prompted<Option<String>> {
+            Ok(None)
+        }
+
+        fn review_group(&mut self, _category_type: &str, _category: &str, _pattern: &str, _rows: &[usize], _entries: &[StringRecord], _categories: &[String]) -> Prompted<Review> {
+            Ok(Review::Accept)
+        }
+
+        fn confirm_transfer(&mut self, _rows: (usize, usize), _debit: &StringRecord, _credit: &StringRecord) -> Prompted<bool> {
+            Ok(false)
+        }
+
+        fn confirm_refund(&mut self, _row: usize, _refund: &CategorisedEntry, _original: &CategorisedEntry) -> Prompted<bool> {
+            Ok(false)
         }
     }
+}
+
+pub mod tui {
+    use std::io::{self, Write};
+    use csv::StringRecord;
+    use crossterm::{execute, queue};
+    use crossterm::cursor::{Hide, MoveTo, Show};
+    use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+    use crossterm::style::{Attribute, Color, Print, ResetColor, SetAttribute, SetForegroundColor};
+    use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+    use crate::report::{ActivityReport, CategorisedEntry, ACCOUNT, TRANSFER, money};
+    use crate::classifier::Prediction;
+    use crate::ui::{UserInterface, Navigation, Prompted, Review, choose_category, choose_pattern, navigation};
+
+    const SIDE_PANEL_WIDTH: usize = 34;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Highlight {
+        Plain,
+        Heading,
+        Current,
+        Automatic,
+        Manual
+    }
+
+    struct Assignment {
+        category_type: Option<&'static str>,
+        category: String,
+        automatic: bool
+    }
+
+#[cfg(test)]
+    fn sample_ui() -> TuiUI {
+        let mut ui = TuiUI::new();
+        ui.begin(&[
+            StringRecord::from(vec!["20191101", "WOOLWORTHS", "-100.00", "0.00"]),
+            StringRecord::from(vec!["20191102", "UBER TRIP", "-50.00", "0.00"]),
+            StringRecord::from(vec!["20191103", "SALARY", "5000.00", "0.00"]),
+        ]);
+        ui
+    }
 
 #[test]
-    fn test_activity_report_creation() {
-        let report = ActivityReport::new();
+    fn test_notices_are_shown_in_the_heading_while_the_screen_is_up() {
+        let mut ui = sample_ui();
+        ui.active = true;
+        ui.notice("Nothing to go back to");
 
-        assert!(report.entries.is_empty())
+        let screen = ui.screen(100, 10);
+
+        assert_eq!(screen[0][0].0.trim_end(), " Transactions (arrow keys scroll) - Nothing to go back to");
     }
 
 #[test]
-    fn test_add_entry_to_activity_report() {
-        let mut report = ActivityReport::new();
-        let record = StringRecord::from(vec!["20191101", "foo bar", "-191.60", "0.00"]);
-        let expected_entry = CategorisedEntry::new("personal", "bar cat", &record);
+    fn test_screen_highlights_automatic_and_manual_assignments() {
+        let mut ui = sample_ui();
+        ui.display_entry(0, &StringRecord::from(vec!["20191101", "WOOLWORTHS", "-100.00", "0.00"]));
+        ui.current_type = Some("personal");
+        ui.display_automap("groceries");
+        ui.display_entry(1, &StringRecord::from(vec!["20191102", "UBER TRIP", "-50.00", "0.00"]));
+        ui.assign("travel", false);
+        ui.display_entry(2, &StringRecord::from(vec!["20191103", "SALARY", "5000.00", "0.00"]));
 
-        report.add_entry("personal", "bar cat", &record);
+        let screen = ui.screen(100, 12);
+        let list: Vec<&(String, Highlight)> = screen.iter().map(|row| &row[0]).collect();
 
-        assert_eq!(report.entries, vec![expected_entry]);
+        assert_eq!(list[0].1, Highlight::Heading);
+        assert!(list[1].0.contains("WOOLWORTHS") && list[1].0.contains("personal/groceries"));
+        assert_eq!(list[1].1, Highlight::Automatic);
+        assert!(list[2].0.contains("UBER TRIP") && list[2].0.contains("travel"));
+        assert_eq!(list[2].1, Highlight::Manual);
+        assert_eq!(list[3].1, Highlight::Current);
+        assert!(list[3].0.starts_with("> 20191103"));
     }
 
 #[test]
-    fn test_do_not_add_entries_that_map_to_the_same_record() {
-        let mut report = ActivityReport::new();
-        let record_1 = StringRecord::from(vec!["20191101", "foo bar", "-191.60", "0.00"]);
-        let expected_entry_1 = CategorisedEntry::new("personal", "bar cat", &record_1);
-        let record_2 = StringRecord::from(vec!["20191102", "fuzzy wuzzy", "111.11", "0.00"]);
-        let expected_entry_2 = CategorisedEntry::new("work", "baz cat", &record_2);
+    fn test_identical_entries_keep_their_own_rows() {
+        let mut ui = TuiUI::new();
+        let entry = StringRecord::from(vec!["20191101", "COFFEE", "-4.00", "0.00"]);
+        ui.begin(&[entry.clone(), entry.clone()]);
+        ui.display_entry(0, &entry);
+        ui.current_type = Some("personal");
+        ui.assign("eating out", false);
+        ui.display_entry(1, &entry);
 
-        report.add_entry("personal", "bar cat", &record_1);
-        report.add_entry("work", "baz cat", &record_2);
-        report.add_entry("work", "foo cat", &record_1);
+        let list: Vec<(String, Highlight)> = ui.screen(100, 12).iter().map(|row| row[0].clone()).collect();
 
-        assert_eq!(report.entries, vec![expected_entry_1, expected_entry_2]);
+        assert!(list[1].0.contains("personal/eating out"));
+        assert_eq!(list[1].1, Highlight::Manual);
+        assert!(list[2].0.starts_with("> 20191101"));
+        assert_eq!(list[2].1, Highlight::Current);
+        assert_eq!(list.iter().filter(|(text, _)| text.contains("COFFEE")).count(), 2);
     }
 
 #[test]
-    fn test_total_entries() {
+    fn test_screen_side_panel_shows_running_totals() {
+        let mut ui = sample_ui();
         let mut report = ActivityReport::new();
-        let personal_expense_1 = StringRecord::from(vec!["20191101", "personal_expense_one", "-191.60", "0.00"]);
-        let personal_expense_2 = StringRecord::from(vec!["20191101", "personal_expense_two", "-150.30", "0.00"]);
-        let personal_income_1 = StringRecord::from(vec!["20191101", "personal_income_one", "500.30", "0.00"]);
-        let personal_income_2 = StringRecord::from(vec!["20191101", "personal_income_two", "1000.90", "0.00"]);
-        let work_expense_1 = StringRecord::from(vec!["20191101", "work_expense_one", "-300.10", "0.00"]);
-        let work_expense_2 = StringRecord::from(vec!["20191101", "work_expense_two", "-400.70", "0.00"]);
+        report.add_entry("personal", "groceries", &StringRecord::from(vec!["20191101", "WOOLWORTHS", "-100.00", "0.00"]));
+        report.add_entry("personal", "salary", &StringRecord::from(vec!["20191103", "SALARY", "5000.00", "0.00"]));
+        ui.display_totals(&report);
 
-        report.add_entry("personal", "bar cat", &personal_expense_1);
-        report.add_entry("personal", "bar cat", &personal_expense_2);
-        report.add_entry("personal", "bar cat", &personal_income_1);
-        report.add_entry("personal", "bar cat", &personal_income_2);
-        report.add_entry("work", "bar cat", &work_expense_1);
-        report.add_entry("work", "bar cat", &work_expense_2);
+        let side: Vec<String> = ui.screen(100, 12).iter().map(|row| row[1].0.trim_end().to_string()).collect();
 
-        assert_eq!(report.total("work", true), Decimal::from_str("700.8").unwrap());
-        assert_eq!(report.total("personal", true), Decimal::from_str("341.9").unwrap());
-        assert_eq!(report.total("personal", false), Decimal::from_str("1501.2").unwrap());
+        assert!(side.contains(&String::from(" Classified 2 of 3")));
+        assert!(side.contains(&String::from(" personal")));
+        assert!(side.contains(&String::from("   Income         5000.00")));
+        assert!(side.contains(&String::from("   Expense         100.00")));
     }
 
-    pub struct ActivityReport {
-        entries: Vec<CategorisedEntry>
+#[test]
+    fn test_screen_scrolls_to_keep_current_entry_visible() {
+        let mut ui = TuiUI::new();
+        let entries: Vec<StringRecord> = (1..=20).map(|day| StringRecord::from(vec![format!("201911{:02}", day), format!("ENTRY {}", day), String::from("-1.00"), String::from("0.00")])).collect();
+        ui.begin(&entries);
+        ui.display_entry(15, &entries[15]);
+
+        let screen = ui.screen(100, 10);
+
+        assert!(screen.iter().any(|row| row[0].0.starts_with("> 20191116")));
+        assert!(!screen.iter().any(|row| row[0].0.contains("ENTRY 1 ")));
     }
 
-    impl ActivityReport {
-        pub fn new() -> ActivityReport {
-            ActivityReport { entries: Vec::new() }
+    /// A full-screen interface listing every entry, with a side panel of running totals.
+    pub struct TuiUI {
+        entries: Vec<StringRecord>,
+        assignments: Vec<Option<Assignment>>,
+        current: usize,
+        scroll: usize,
+        follow: bool,
+        current_type: Option<&'static str>,
+        categories: Vec<String>,
+        suggestions: Vec<(String, usize)>,
+        predictions: Vec<Prediction>,
+        totals: Vec<(String, String, String)>,
+        classified: usize,
+        prompt: String,
+        input: String,
+        /// Shown in the heading until the next key is pressed.
+        notice: String,
+        stopped: bool,
+        active: bool
+    }
+
+    impl Default for TuiUI {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl TuiUI {
+        pub fn new() -> TuiUI {
+            TuiUI {
+                entries: Vec::new(),
+                assignments: Vec::new(),
+                current: 0,
+                scroll: 0,
+                follow: true,
+                current_type: None,
+                categories: Vec::new(),
+                suggestions: Vec::new(),
+                predictions: Vec::new(),
+                totals: Vec::new(),
+                classified: 0,
+                prompt: String::new(),
+                input: String::new(),
+                notice: String::new(),
+                stopped: false,
+                active: false
+            }
         }
 
-        pub fn add_entry(&mut self, category_type: &str, category: &str, original_record: &StringRecord) {
-            let entry = CategorisedEntry::new(category_type, category, original_record);
+        fn assign(&mut self, category: &str, automatic: bool) {
+            if let Some(assignment) = self.assignments.get_mut(self.current) {
+                *assignment = Some(Assignment { category_type: self.current_type, category: String::from(category), automatic });
+            }
+        }
 
-            if !self.record_present(&entry) {
-                self.entries.push(entry);
+        fn list_height(height: usize) -> usize {
+            height.saturating_sub(4)
+        }
+
+        /// The first entry shown, moved if need be so that the current entry is visible unless the
+        /// list has been scrolled by hand since it was shown.
+        fn first_visible(&self, rows: usize) -> usize {
+            if !self.follow {
+                self.scroll
+            } else if self.current < self.scroll {
+                self.current
+            } else if self.current >= self.scroll + rows {
+                self.current + 1 - rows.max(1)
             } else {
-                println!("Skipping entry - already present");
+                self.scroll
             }
         }
 
-        pub fn total(&self, category_type: &str, expense: bool) -> Decimal {
-            self.entries.iter().filter(|x| x.category_type == category_type && x.expense == expense).map(|x| x.amount).sum()
+        /// Lays the screen out as rows of a list cell and a side panel cell, leaving the last three
+        /// rows for the prompt.
+        fn screen(&self, width: usize, height: usize) -> Vec<Vec<(String, Highlight)>> {
+            let side_width = if width >= 80 { SIDE_PANEL_WIDTH } else { 0 };
+            let list_width = width - side_width;
+            let rows = Self::list_height(height);
+            let first = self.first_visible(rows);
+
+            let heading = if self.notice.is_empty() {
+                String::from(" Transactions (arrow keys scroll)")
+            } else {
+                format!(" Transactions (arrow keys scroll) - {}", self.notice)
+            };
+            let mut list: Vec<(String, Highlight)> = vec![(fit(&heading, list_width), Highlight::Heading)];
+            for index in first..(first + rows).min(self.entries.len()) {
+                let entry = &self.entries[index];
+                let marker = if index == self.current { ">" } else { " " };
+                let (label, highlight) = match &self.assignments[index] {
+                    Some(a) => {
+                        let label = match a.category_type {
+                            Some(category_type) => format!("{}/{}", category_type, a.category),
+                            None => a.category.clone()
+                        };
+                        (label, if a.automatic { Highlight::Automatic } else { Highlight::Manual })
+                    },
+                    None => (String::new(), Highlight::Plain)
+                };
+                let description_width = list_width.saturating_sub(50).max(10);
+                let text = format!(
+                    "{} {} {:<dw$} {:>12}  {}",
+                    marker,
+                    entry.get(0).unwrap_or("").trim(),
+                    fit(entry.get(1).unwrap_or("").trim(), description_width),
+                    entry.get(2).unwrap_or("").trim(),
+                    label,
+                    dw = description_width
+                );
+                list.push((fit(&text, list_width), if index == self.current { Highlight::Current } else { highlight }));
+            }
+
+            let mut side: Vec<(String, Highlight)> = vec![
+                (fit(" Running totals", side_width), Highlight::Heading),
+                (fit(&format!(" Classified {} of {}", self.classified, self.entries.len()), side_width), Highlight::Plain),
+            ];
+            for (category_type, income, expense) in self.totals.iter() {
+                side.push((fit(&format!(" {}", category_type), side_width), Highlight::Heading));
+                side.push((fit(&format!("   Income  {:>14}", income), side_width), Highlight::Plain));
+                side.push((fit(&format!("   Expense {:>14}", expense), side_width), Highlight::Plain));
+            }
+            if !self.predictions.is_empty() {
+                side.push((fit(" Suggested categories", side_width), Highlight::Heading));
+                for prediction in self.predictions.iter() {
+                    side.push((fit(&format!(" {:>3.0}% {}/{}", prediction.confidence * 100.0, prediction.category_type, prediction.category), side_width), Highlight::Plain));
+                }
+            }
+            if !self.categories.is_empty() {
+                side.push((fit(" Categories", side_width), Highlight::Heading));
+                for (index, category) in self.categories.iter().enumerate() {
+                    side.push((fit(&format!(" {:>3}. {}", index + 1, category), side_width), Highlight::Plain));
+                }
+            }
+            if !self.suggestions.is_empty() {
+                side.push((fit(" Suggested patterns", side_width), Highlight::Heading));
+                for (index, (suggestion, matches)) in self.suggestions.iter().enumerate() {
+                    side.push((fit(&format!(" {:>3}. {} ({} pending)", index + 1, suggestion, matches), side_width), Highlight::Plain));
+                }
+            }
+
+            (0..=rows).map(|row| {
+                vec![
+                    list.get(row).cloned().unwrap_or_else(|| (fit("", list_width), Highlight::Plain)),
+                    side.get(row).cloned().unwrap_or_else(|| (fit("", side_width), Highlight::Plain))
+                ]
+            }).collect()
         }
 
-        fn record_present(&self, entry: &CategorisedEntry) -> bool {
-            let mut iter = self.entries.iter().filter(|e| e.record_fingerprint == entry.record_fingerprint);
+        fn draw(&mut self) {
+            if !self.active {
+                self.active = true;
+                let _ = terminal::enable_raw_mode();
+                let _ = execute!(io::stdout(), EnterAlternateScreen, Hide);
+            }
+
+            let (width, height) = terminal::size().unwrap_or((80, 24));
+            let (width, height) = (width as usize, height as usize);
+            self.scroll = self.first_visible(Self::list_height(height));
+            let mut out = io::stdout();
+
+            let _ = queue!(out, Clear(ClearType::All));
+            for (row, cells) in self.screen(width, height).into_iter().enumerate() {
+                let _ = queue!(out, MoveTo(0, row as u16));
+                for (text, highlight) in cells {
+                    let _ = match highlight {
+                        Highlight::Plain => queue!(out, Print(text)),
+                        Highlight::Heading => queue!(out, SetAttribute(Attribute::Bold), Print(text), SetAttribute(Attribute::Reset)),
+                        Highlight::Current => queue!(out, SetAttribute(Attribute::Reverse), Print(text), SetAttribute(Attribute::Reset)),
+                        Highlight::Automatic => queue!(out, SetForegroundColor(Color::Green), Print(text), ResetColor),
+                        Highlight::Manual => queue!(out, SetForegroundColor(Color::Cyan), Print(text), ResetColor)
+                    };
+                }
+            }
 
-            match iter.next() {
-                Some(_) => true,
-                None => false
+            let prompt_lines: Vec<&str> = self.prompt.lines().collect();
+            let shown = &prompt_lines[prompt_lines.len().saturating_sub(2)..];
+            let first_prompt_row = height.saturating_sub(3);
+            for (offset, line) in shown.iter().enumerate() {
+                let _ = queue!(out, MoveTo(0, (first_prompt_row + offset) as u16), Print(fit(line, width)));
             }
+            let _ = queue!(out, MoveTo(0, height.saturating_sub(1) as u16), Print(fit(&format!("> {}", self.input), width)));
+            let _ = out.flush();
         }
 
-    }
-}
+        fn scroll_by(&mut self, rows: isize) {
+            let (_, height) = terminal::size().unwrap_or((80, 24));
+            let visible = Self::list_height(height as usize).max(1);
+            let last = self.entries.len().saturating_sub(visible);
+            self.scroll = ((self.first_visible(visible) as isize + rows).max(0) as usize).min(last);
+            self.follow = false;
+        }
 
-pub mod ui {
-    use std::io;
-    use csv::StringRecord;
+        /// Waits for a key press, scrolling the list for navigation keys along the way. Ctrl-C
+        /// cancels the import.
+        fn read_key(&mut self) -> Prompted<Option<KeyEvent>> {
+            loop {
+                self.draw();
+                match event::read() {
+                    Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                        match key.code {
+                            KeyCode::Up => self.scroll_by(-1),
+                            KeyCode::Down => self.scroll_by(1),
+                            KeyCode::PageUp => self.scroll_by(-10),
+                            KeyCode::PageDown => self.scroll_by(10),
+                            _ if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                return Err(Navigation::Cancel);
+                            },
+                            _ => {
+                                self.notice.clear();
+                                return Ok(Some(key));
+                            }
+                        }
+                    },
+                    Ok(_) => {},
+                    Err(_) => return Ok(None)
+                }
+            }
+        }
 
-    pub struct UI {
+        fn read_line(&mut self, prompt: &str) -> Prompted<Option<String>> {
+            self.prompt = format!("{}\n(Esc skips, ':b' goes back, ':u' undoes, Ctrl-C cancels the import)", prompt);
+            self.input.clear();
+
+            loop {
+                let key = match self.read_key()? {
+                    Some(key) => key,
+                    None => return Ok(None)
+                };
+                match key.code {
+                    KeyCode::Enter => {
+                        let answer = self.input.trim().to_string();
+                        self.input.clear();
+                        return match navigation(&answer) {
+                            Some(command) => Err(command),
+                            None => Ok(Some(answer))
+                        };
+                    },
+                    KeyCode::Esc => return Err(Navigation::Skip),
+                    KeyCode::Backspace => {
+                        self.input.pop();
+                    },
+                    KeyCode::Char(c) => self.input.push(c),
+                    _ => {}
+                }
+            }
+        }
     }
 
-    impl UI {
-        pub fn display_entry(&self, entry: &StringRecord) {
-            println!("{} {} {}", entry.get(0).unwrap(), entry.get(1).unwrap(), entry.get(2).unwrap());
+    impl UserInterface for TuiUI {
+        fn begin(&mut self, entries: &[StringRecord]) {
+            self.entries = entries.to_vec();
+            self.assignments = entries.iter().map(|_| None).collect();
         }
 
-        pub fn get_type(&self) -> &str {
-            println!("Enter 'p' for personal or 'w' for work");
+        fn display_entry(&mut self, row: usize, _entry: &StringRecord) {
+            self.current = row;
+            if let Some(assignment) = self.assignments.get_mut(row) {
+                *assignment = None;
+            }
+            self.current_type = None;
+            self.categories.clear();
+            self.predictions.clear();
+            self.follow = true;
+        }
 
-            let mut choice = String::new();
-            let _ = io::stdin().read_line(&mut choice);
+        fn get_type(&mut self) -> Prompted<Option<&'static str>> {
+            if self.stopped {
+                return Ok(None);
+            }
 
-            if choice.trim() == "p" {
-                "personal"
-            } else {
-                "work"
+            self.prompt = String::from("p personal, w work, a let the patterns decide\ns skip, b back, u undo, q leave the rest to the patterns, Ctrl-C cancel the import");
+
+            loop {
+                let key = match self.read_key()? {
+                    Some(key) => key,
+                    None => return Ok(None)
+                };
+                match key.code {
+                    KeyCode::Char('p') => {
+                        self.current_type = Some("personal");
+                        return Ok(self.current_type);
+                    },
+                    KeyCode::Char('w') => {
+                        self.current_type = Some("work");
+                        return Ok(self.current_type);
+                    },
+                    KeyCode::Char('a') => return Ok(None),
+                    KeyCode::Char('s') | KeyCode::Esc => return Err(Navigation::Skip),
+                    KeyCode::Char('b') => return Err(Navigation::Back),
+                    KeyCode::Char('u') => return Err(Navigation::Undo),
+                    KeyCode::Char('q') => {
+                        self.stopped = true;
+                        return Ok(None);
+                    },
+                    _ => {}
+                }
             }
         }
 
-        pub fn display_automap(&self, category: &str) {
-            println!("Automagically mapped to {}", category);
+        fn display_automap(&mut self, category: &str) {
+            self.assign(category, true);
         }
 
-        pub fn display_categories(&self, category_type: &str, categories: &Vec<String>) {
-            println!("Existing {} categories", category_type);
-            println!("");
-            // println!("{:?}", config.personal_categories());
-            let cat_iter = categories.iter();
-            let mut cats_for_output: Vec<String> = Vec::new();
-            for cat in cat_iter {
-                cats_for_output.push(String::from(cat));
+        fn display_predictions(&mut self, predictions: &[Prediction]) {
+            self.predictions = predictions.to_vec();
+        }
+
+        fn display_categories(&mut self, _category_type: &str, categories: &[String]) {
+            self.categories = categories.to_vec();
+        }
+
+        fn capture_category(&mut self, categories: &[String]) -> Prompted<Option<String>> {
+            if self.stopped {
+                return Ok(None);
+            }
+
+            let category = choose_category(categories, |prompt| self.read_line(prompt))?;
+            if let Some(category) = &category {
+                self.assign(category, false);
             }
-            cats_for_output.sort();
-            for cat in cats_for_output {
-                println!("{}", cat);
+            self.categories.clear();
+            self.predictions.clear();
+            Ok(category)
+        }
+
+        fn capture_pattern(&mut self, suggestions: &[(String, usize)]) -> Prompted<Option<String>> {
+            if self.stopped {
+                return Ok(None);
             }
+
+            self.suggestions = suggestions.to_vec();
+            let prompt = if suggestions.is_empty() {
+                "Provide a pattern for this category or just hit enter"
+            } else {
+                "Enter a number to use a suggested pattern, type a pattern or just hit enter"
+            };
+            let answer = self.read_line(prompt);
+            self.suggestions.clear();
+
+            Ok(choose_pattern(answer?, suggestions))
         }
 
-        pub fn capture_category(&self) -> String {
-            println!("Enter 'c' to add a category, or enter a pre-existing category");
+        fn review_group(&mut self, category_type: &str, category: &str, pattern: &str, rows: &[usize], entries: &[StringRecord], categories: &[String]) -> Prompted<Review> {
+            if self.stopped {
+                return Ok(Review::Accept);
+            }
+
+            if let Some(&row) = rows.first() {
+                self.current = row;
+                self.follow = true;
+            }
+            let summary = format!("{} {} entries mapped to {} by '{}'", entries.len(), category_type, category, pattern);
 
-            let mut category_choice = String::new();
-            let _ = io::stdin().read_line(&mut category_choice);
+            loop {
+                self.categories.clear();
+                self.prompt = format!("{}
+a accept, r reassign the group, e edit the pattern, s leave pending", summary);
 
-            if category_choice.trim() == "c" {
-                let mut new_category = String::new();
-                let _ = io::stdin().read_line(&mut new_category);
+                let key = match self.read_key()? {
+                    Some(key) => key,
+                    None => return Ok(Review::Accept)
+                };
+                match key.code {
+                    KeyCode::Char('a') | KeyCode::Enter => return Ok(Review::Accept),
+                    KeyCode::Char('s') | KeyCode::Esc => return Err(Navigation::Skip),
+                    KeyCode::Char('r') => {
+                        self.categories = categories.to_vec();
+                        match choose_category(categories, |prompt| self.read_line(prompt)) {
+                            Ok(Some(chosen)) => {
+                                for row in rows {
+                                    if let Some(Some(assignment)) = self.assignments.get_mut(*row) {
+                                        assignment.category = chosen.clone();
+                                    }
+                                }
+                                self.categories.clear();
+                                return Ok(Review::Reassign(chosen));
+                            },
+                            Ok(None) => return Ok(Review::Accept),
+                            Err(Navigation::Cancel) => return Err(Navigation::Cancel),
+                            Err(_) => {}
+                        }
+                    },
+                    KeyCode::Char('e') => {
+                        match self.read_line("Enter the new pattern") {
+                            Ok(Some(refined)) if !refined.is_empty() => return Ok(Review::Refine(refined)),
+                            Err(Navigation::Cancel) => return Err(Navigation::Cancel),
+                            Ok(_) | Err(_) => {}
+                        }
+                    },
+                    _ => {}
+                }
+            }
+        }
 
-                new_category
-            } else {
-                category_choice
+        fn confirm_transfer(&mut self, rows: (usize, usize), debit: &StringRecord, credit: &StringRecord) -> Prompted<bool> {
+            if self.stopped {
+                return Ok(false);
+            }
+
+            self.current = rows.0;
+            self.follow = true;
+            self.prompt = format!(
+                "Transfer of {} from {} to {}?\ny it is, n or s classify them separately",
+                credit.get(2).unwrap_or("").trim(),
+                debit.get(ACCOUNT).unwrap_or(""),
+                credit.get(ACCOUNT).unwrap_or("")
+            );
+
+            loop {
+                let key = match self.read_key()? {
+                    Some(key) => key,
+                    None => return Ok(false)
+                };
+                match key.code {
+                    KeyCode::Char('y') => {
+                        for row in [rows.0, rows.1] {
+                            if let Some(assignment) = self.assignments.get_mut(row) {
+                                *assignment = Some(Assignment { category_type: None, category: String::from(TRANSFER), automatic: true });
+                            }
+                        }
+                        return Ok(true);
+                    },
+                    KeyCode::Char('n') => return Ok(false),
+                    KeyCode::Char('s') | KeyCode::Esc => return Err(Navigation::Skip),
+                    _ => {}
+                }
             }
         }
 
-        pub fn capture_pattern(&self) -> Option<String> {
-            println!("Provide a pattern for this category or just hit enter");
+        fn confirm_refund(&mut self, row: usize, refund: &CategorisedEntry, original: &CategorisedEntry) -> Prompted<bool> {
+            if self.stopped {
+                return Ok(false);
+            }
+
+            self.current = row;
+            self.follow = true;
+            let category = if original.category.is_empty() { String::new() } else { format!(" in {}", original.category) };
+            self.prompt = format!(
+                "Refund of {} from {} {}{}?\ny it is, n or s classify it as usual",
+                money(refund.amount),
+                original.date.format("%Y-%m-%d"),
+                original.description,
+                category
+            );
 
-            let mut pattern_choice = String::new();
-            let _ = io::stdin().read_line(&mut pattern_choice);
+            loop {
+                let key = match self.read_key()? {
+                    Some(key) => key,
+                    None => return Ok(false)
+                };
+                match key.code {
+                    KeyCode::Char('y') => {
+                        if let Some(assignment) = self.assignments.get_mut(row) {
+                            let category = if original.category.is_empty() { String::from("refund") } else { original.category.clone() };
+                            *assignment = Some(Assignment { category_type: None, category, automatic: true });
+                        }
+                        return Ok(true);
+                    },
+                    KeyCode::Char('n') => return Ok(false),
+                    KeyCode::Char('s') | KeyCode::Esc => return Err(Navigation::Skip),
+                    _ => {}
+                }
+            }
+        }
 
-            if pattern_choice.trim() != "" {
-                Some(String::from(pattern_choice.trim()))
+        /// Shown in the heading while the screen is up, since printing would write over it.
+        fn notice(&mut self, message: &str) {
+            if self.active {
+                self.notice = String::from(message);
             } else {
-                None
+                println!("{}", message);
+            }
+        }
+
+        fn display_totals(&mut self, report: &ActivityReport) {
+            self.classified = report.entries().len();
+            self.totals = report.category_types().into_iter().map(|category_type| {
+                let income = money(report.total(&category_type, false));
+                let expense = money(report.total(&category_type, true));
+                (category_type, income, expense)
+            }).collect();
+        }
+
+        fn finish(&mut self) {
+            if self.active {
+                self.active = false;
+                let _ = execute!(io::stdout(), Show, LeaveAlternateScreen);
+                let _ = terminal::disable_raw_mode();
             }
         }
     }
+
+    impl Drop for TuiUI {
+        fn drop(&mut self) {
+            self.finish();
+        }
+    }
+
+    /// Truncates or pads `text` to exactly `width` characters.
+    fn fit(text: &str, width: usize) -> String {
+        let truncated: String = text.chars().take(width).collect();
+        format!("{:<width$}", truncated, width = width)
+    }
 }
@@ -1,17 +1,31 @@
-use csv::{Reader, StringRecord};
-use dirs;
+use csv::{Reader, StringRecord, Writer};
 use std::fs;
 use std::env;
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use yaml_rust::{Yaml, YamlLoader};
 use yaml_rust::emitter::YamlEmitter;
-use yaml_rust::scanner::ScanError;
 use linked_hash_map::LinkedHashMap;
 use chrono::{NaiveDate, Datelike};
 use chrono::format::ParseError;
-use bank_statement_importer::report::ActivityReport;
-use bank_statement_importer::ui::UI;
+use bank_statement_importer::merchant::Normaliser;
+use bank_statement_importer::transfer;
+use bank_statement_importer::currency::Rates;
+use bank_statement_importer::account::{self, Account, AccountType};
+use bank_statement_importer::schema::{self, Problem};
+use bank_statement_importer::migration;
+use bank_statement_importer::store::{self, Lock};
+use bank_statement_importer::report::{self, ActivityReport, CategorisedEntry, money, rank_categories};
+use bank_statement_importer::history::{History, Recategorisation};
+use bank_statement_importer::classifier::{Classifier, Prediction};
+use bank_statement_importer::claim::{self, Claim};
+use bank_statement_importer::html;
+use bank_statement_importer::markdown;
+use bank_statement_importer::tui::TuiUI;
+use bank_statement_importer::trend::{Period, TrendReport};
+use bank_statement_importer::ui::{UserInterface, UI, ScriptedUI, AutoUI, Navigation, Prompted, Review, suggest_patterns};
+use rust_decimal::Decimal;
 
 #[test]
 fn test_category_equality() {
@@ -31,7 +45,7 @@ fn test_category_equality() {
                );
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Category {
     name: String,
     patterns: Vec<String>
@@ -39,13 +53,11 @@ struct Category {
 
 impl Category {
     fn matches_description(&self, description: &str) -> bool {
-        let desc = String::from(description);
-        let mut iter = self.patterns.iter().filter(|p| desc.contains(&p[..]) );
+        self.matching_pattern(description).is_some()
+    }
 
-        match iter.next() {
-            Some(_) => true,
-            None => false
-        }
+    fn matching_pattern(&self, description: &str) -> Option<&String> {
+        self.patterns.iter().find(|p| description.contains(&p[..]))
     }
 }
 
@@ -79,6 +91,7 @@ fn test_adding_categories_to_catalogue() {
         ]);
 }
 
+#[derive(Clone)]
 struct CategoryCatalogue {
     categories: Vec<Category>
 }
@@ -107,23 +120,162 @@ impl CategoryCatalogue {
     }
 
     fn category_exists(&self, category: &str) -> bool {
-        let mut iter = self.categories.iter().filter(|c| c.name == category);
-
-        match iter.next() {
-            Some(_) => true,
-            None => false
-        }
+        self.categories.iter().any(|c| c.name == category)
     }
 
     fn find_cat(&mut self, category: &str) -> &mut Category{
         let mut iter = self.categories.iter_mut().filter(|x| x.name == category);
         iter.next().unwrap()
     }
+
+    fn remove_category(&mut self, category: &str) {
+        self.categories.retain(|c| c.name != category);
+    }
+
+    fn rename_category(&mut self, category: &str, new_name: &str) {
+        if let Some(c) = self.categories.iter_mut().find(|c| c.name == category) {
+            c.name = String::from(new_name);
+        }
+    }
+}
+
+#[test]
+fn test_cleaning_round_trips_and_runs_before_matching() {
+    let contents = "categories:\n  personal: [groceries]\n  work: []\npatterns:\n  personal:\n    groceries: [WOOLWORTHS]\n  work: {}\ncleaning:\n  strip_prefixes: [POS PURCHASE]\n  mask_digits: true\n  aliases:\n    WOOLWORTHS: [WW FOODS]\n";
+    let config = deserialise(String::from(contents)).unwrap();
+
+    assert_eq!(config.normaliser.strip_prefixes, vec!["POS PURCHASE"]);
+    assert!(config.normaliser.mask_digits);
+    assert!(!config.normaliser.collapse_whitespace);
+    assert_eq!(config.match_category("personal", "POS PURCHASE 1234 WW FOODS 12").map(|c| c.name.clone()), Some(String::from("groceries")));
+
+    let reloaded = deserialise(serialise(&config.export())).unwrap();
+    assert_eq!(reloaded.normaliser, config.normaliser);
+    assert!(!serialise(&deserialise(serialise(&config_template())).unwrap().export()).contains("cleaning"));
+}
+
+#[test]
+fn test_accounts_round_trip_and_later_layers_replace_them() {
+    let contents = "categories:\n  personal: []\n  work: []\npatterns:\n  personal: {}\n  work: {}\naccounts:\n  everyday:\n    institution: FNB\n    currency: ZAR\n    type: cheque\n    files: cheque_*.csv\n";
+    let config = deserialise(String::from(contents)).unwrap();
+
+    assert_eq!(config.accounts, vec![Account { name: String::from("everyday"), institution: String::from("FNB"), currency: String::from("ZAR"), account_type: AccountType::Cheque, files: String::from("cheque_*.csv") }]);
+    assert_eq!(deserialise(serialise(&config.export())).unwrap().accounts, config.accounts);
+
+    let override_contents = "categories:\n  personal: []\n  work: []\npatterns:\n  personal: {}\n  work: {}\naccounts:\n  everyday:\n    institution: FNB\n    currency: ZAR\n    type: savings\n    files: savings.csv\n";
+    let files = vec![
+        (PathBuf::from("team.yml"), parse_config(contents).unwrap()),
+        (PathBuf::from("config.yml"), parse_config(override_contents).unwrap()),
+    ];
+    let layered = Config::from_layers(files, 1);
+    assert_eq!(layered.accounts.len(), 1);
+    assert_eq!(layered.accounts[0].account_type, AccountType::Savings);
+}
+
+#[test]
+fn test_records_carry_their_account_and_currency() {
+    let record = statement_record(&StringRecord::from(vec!["20191101", "SHOP", "-10.00", "0.00", "extra"]), "everyday", "ZAR");
+
+    assert_eq!(record, StringRecord::from(vec!["20191101", "SHOP", "-10.00", "0.00", "everyday", "ZAR"]));
+    assert_eq!(statement_record(&StringRecord::from(vec!["20191101", "SHOP", "-10.00"]), "", "").len(), 6);
+}
+
+#[test]
+fn test_statement_currency_comes_from_a_column_or_the_account() {
+    let directory = env::temp_dir().join(format!("bsi_statement_currency_{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    fs::write(directory.join("travel.csv"), "date,description,amount,balance,currency\n20191101,HOTEL,-80.00,0.00,EUR\n20191102,TAXI,-10.00,0.00,\n").unwrap();
+    let accounts = vec![Account { name: String::from("travel"), institution: String::from("Wise"), currency: String::from("USD"), account_type: AccountType::Savings, files: String::from("travel.csv") }];
+
+    let records = read_statements(directory.to_str().unwrap(), &mut Vec::new(), &accounts);
+    let _ = fs::remove_dir_all(&directory);
+
+    assert_eq!(records[0].get(report::CURRENCY), Some("EUR"));
+    assert_eq!(records[1].get(report::CURRENCY), Some("USD"));
+    assert_eq!(records[1].get(report::ACCOUNT), Some("travel"));
 }
 
 struct Config {
     personal: CategoryCatalogue,
-    work: CategoryCatalogue
+    work: CategoryCatalogue,
+    normaliser: Normaliser,
+    accounts: Vec<Account>,
+    /// The base currency reports are given in, if amounts are to be converted.
+    currency: Option<String>,
+    /// The files merged into this config, lowest precedence first. Empty when it was read from one file.
+    layers: Vec<Layer>,
+    /// The layer that new categories and patterns are written to: the user's own config.
+    primary: usize,
+    /// The layer each category, and each of its patterns, came from. Anything not listed belongs to the primary layer.
+    origins: HashMap<(String, String, Option<String>), usize>
+}
+
+#[test]
+fn test_layers_merge_and_are_written_back_to_their_own_files() {
+    let directory = env::temp_dir().join(format!("bsi_layers_{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let team_path = directory.join("team.yml");
+    let user_path = directory.join("config.yml");
+    let team = "# Shared with the team\npatterns:\n  personal:\n    groceries: [SHOP]\n  work:\n    travel: [AIRLINE]\ncategories:\n  personal: [groceries]\n  work: [travel]\n";
+    let user = "categories:\n  personal: [fuel]\n  work: []\npatterns:\n  personal:\n    fuel: [SHOP]\n  work: {}\n";
+    let files = vec![(team_path.clone(), parse_config(team).unwrap()), (user_path.clone(), parse_config(user).unwrap())];
+
+    let mut config = Config::from_layers(files, 1);
+    assert_eq!(config.personal_categories(), vec!["groceries", "fuel"]);
+    assert_eq!(config.match_category("personal", "SHOP 24").map(|c| c.name.clone()), Some(String::from("fuel")));
+    assert_eq!(config.origin("work", "travel", Some("AIRLINE")), 0);
+
+    fs::write(&team_path, team).unwrap();
+    config.find_cat("personal", "groceries").patterns.push(String::from("GROCER"));
+    save_config(&mut config, &user_path).unwrap();
+    let unchanged_team = fs::read_to_string(&team_path).unwrap();
+    let user_after = parse_config(&fs::read_to_string(&user_path).unwrap()).map(Config::new).unwrap();
+
+    config.remove_pattern("work", "travel", "AIRLINE");
+    save_config(&mut config, &user_path).unwrap();
+    let team_after = parse_config(&fs::read_to_string(&team_path).unwrap()).map(Config::new).unwrap();
+    let _ = fs::remove_dir_all(&directory);
+
+    assert_eq!(unchanged_team, team);
+    assert_eq!(user_after.personal.categories, vec![
+        Category { name: String::from("groceries"), patterns: vec![String::from("GROCER")] },
+        Category { name: String::from("fuel"), patterns: vec![String::from("SHOP")] }
+    ]);
+    assert_eq!(team_after.work.categories, vec![Category { name: String::from("travel"), patterns: vec![] }]);
+    assert_eq!(team_after.personal.categories, vec![Category { name: String::from("groceries"), patterns: vec![String::from("SHOP")] }]);
+}
+
+#[test]
+fn test_renaming_and_merging_categories() {
+    let mut config = deserialise(serialise(&config_template())).unwrap();
+    config.add_category("personal", "food");
+    config.add_category("personal", "fuel");
+    config.add_category("personal", "eating out");
+    config.find_cat("personal", "food").patterns.push(String::from("SHOP"));
+    config.find_cat("personal", "eating out").patterns.push(String::from("CAFE"));
+    config.find_cat("personal", "eating out").patterns.push(String::from("SHOP"));
+
+    config.rename_category("personal", "food", "groceries");
+    assert_eq!(config.personal_categories(), vec!["groceries", "fuel", "eating out"]);
+
+    config.merge_category("personal", "eating out", "groceries");
+    assert_eq!(config.personal.categories, vec![
+        Category { name: String::from("groceries"), patterns: vec![String::from("SHOP"), String::from("CAFE")] },
+        Category { name: String::from("fuel"), patterns: vec![] }
+    ]);
+}
+
+/// A config file's path and contents.
+type ConfigFile = (PathBuf, LinkedHashMap<Yaml, Yaml>);
+
+/// One of the files a config is merged from.
+struct Layer {
+    path: PathBuf,
+    /// Everything in the file other than its categories and patterns, to be written back as read.
+    sections: LinkedHashMap<Yaml, Yaml>,
+    /// The layer as it was last read or written, so that a file is only rewritten when what
+    /// belongs to it changes.
+    saved: Yaml
 }
 
 impl Config {
@@ -139,7 +291,81 @@ impl Config {
                           categories[&Yaml::from_str("work")].as_vec().unwrap(),
                           patterns[&Yaml::from_str("work")].as_hash().unwrap(),
                           ),
+            normaliser: contents.get(&Yaml::from_str("cleaning")).map(read_normaliser).unwrap_or_default(),
+            accounts: contents.get(&Yaml::from_str("accounts")).map(read_accounts).unwrap_or_default(),
+            currency: contents.get(&Yaml::from_str("currency")).and_then(|c| c.as_str()).map(String::from),
+            layers: Vec::new(),
+            primary: 0,
+            origins: HashMap::new(),
+        }
+    }
+
+    /// Merges files in order, lowest precedence first. Categories and patterns are combined, keeping
+    /// the first file each came from. Cleaning steps are combined too, with later files deciding
+    /// the on and off steps. An account declared again in a later file replaces the earlier one,
+    /// and so does the currency.
+    fn from_layers(files: Vec<ConfigFile>, primary: usize) -> Config {
+        let mut config = Config::new(config_template().into_hash().unwrap());
+        config.primary = primary;
+        let mut cleaning: LinkedHashMap<Yaml, Yaml> = LinkedHashMap::new();
+
+        for (index, (path, contents)) in files.into_iter().enumerate() {
+            let single = Config::new(contents.clone());
+            for account in single.accounts {
+                config.accounts.retain(|a| a.name != account.name);
+                config.accounts.push(account);
+            }
+            if single.currency.is_some() {
+                config.currency = single.currency;
+            }
+            for (entry_type, catalogue) in [("personal", &single.personal), ("work", &single.work)] {
+                for category in catalogue.categories.iter() {
+                    if !config.category_exists(entry_type, &category.name) {
+                        config.add_category(entry_type, &category.name);
+                        config.origins.insert((String::from(entry_type), category.name.clone(), None), index);
+                    }
+                    for pattern in category.patterns.iter() {
+                        let merged = config.find_cat(entry_type, &category.name);
+                        if !merged.patterns.contains(pattern) {
+                            merged.patterns.push(pattern.clone());
+                            config.origins.insert((String::from(entry_type), category.name.clone(), Some(pattern.clone())), index);
+                        }
+                    }
+                }
+            }
+
+            if let Some(Yaml::Hash(steps)) = contents.get(&Yaml::from_str("cleaning")) {
+                for (step, value) in steps.iter() {
+                    match (cleaning.get_mut(step), value) {
+                        (Some(Yaml::Array(merged)), Yaml::Array(more)) => merged.extend(more.iter().cloned()),
+                        (Some(Yaml::Hash(merged)), Yaml::Hash(more)) => merged.extend(more.iter().map(|(k, v)| (k.clone(), v.clone()))),
+                        _ => {
+                            cleaning.insert(step.clone(), value.clone());
+                        }
+                    }
+                }
+            }
+
+            let sections = contents.into_iter().filter(|(k, _)| k.as_str() != Some("categories") && k.as_str() != Some("patterns")).collect();
+            config.layers.push(Layer { path, sections, saved: Yaml::Null });
         }
+
+        for index in 0..config.layers.len() {
+            config.layers[index].saved = config.export_layer(Some(index));
+        }
+        config.normaliser = read_normaliser(&Yaml::Hash(cleaning));
+        config
+    }
+
+    /// The layer a category, or one of its patterns, came from.
+    fn origin(&self, entry_type: &str, category: &str, pattern: Option<&str>) -> usize {
+        let key = (String::from(entry_type), String::from(category), pattern.map(String::from));
+        self.origins.get(&key).cloned().unwrap_or(self.primary)
+    }
+
+    /// The text patterns are matched against: the description after cleaning, or its canonical merchant.
+    fn normalise(&self, description: &str) -> String {
+        self.normaliser.merchant(description)
     }
 
     fn personal_categories(&self) -> Vec<String> {
@@ -165,57 +391,220 @@ impl Config {
             &self.work.categories
         };
 
-        let mut iter = cats.iter().filter(|c| c.matches_description(entry));
-        iter.next()
+        // A pattern from a later layer overrides one from an earlier layer
+        let merchant = self.normalise(entry);
+        let mut found: Option<(&Category, usize)> = None;
+        for category in cats.iter() {
+            let layer = category.patterns.iter()
+                .filter(|p| merchant.contains(&p[..]))
+                .map(|p| self.origin(entry_type, &category.name, Some(p)))
+                .max();
+            if let Some(layer) = layer {
+                if found.is_none_or(|(_, best)| layer > best) {
+                    found = Some((category, layer));
+                }
+            }
+        }
+        found.map(|(category, _)| category)
+    }
+
+    fn infer_type(&self, entry: &str) -> Option<&'static str> {
+        ["personal", "work"].iter().find(|t| self.match_category(t, entry).is_some()).cloned()
     }
 
     fn add_category(&mut self, entry_type: &str, category: &str) {
         if entry_type == "personal" {
-            &self.personal.add_category(category);
+            self.personal.add_category(category);
         } else {
-            &self.work.add_category(category);
+            self.work.add_category(category);
         }
     }
 
-    fn template() -> Yaml {
-        let mut config: LinkedHashMap<Yaml, Yaml> = LinkedHashMap::new();
-        let patterns: LinkedHashMap<Yaml, Yaml> = LinkedHashMap::new();
-        let mut types: LinkedHashMap<Yaml, Yaml> = LinkedHashMap::new();
-        types.insert(Yaml::from_str("personal"), Yaml::Array(Vec::new()));
-        types.insert(Yaml::from_str("work"), Yaml::Array(Vec::new()));
-        config.insert(Yaml::from_str("categories"), Yaml::Hash(types));
-        config.insert(Yaml::from_str("patterns"), Yaml::Hash(patterns));
-        Yaml::Hash(config)
+    fn category_exists(&self, entry_type: &str, category: &str) -> bool {
+        if entry_type == "personal" {
+            self.personal.category_exists(category)
+        } else {
+            self.work.category_exists(category)
+        }
+    }
+
+    fn remove_category(&mut self, entry_type: &str, category: &str) {
+        if entry_type == "personal" {
+            self.personal.remove_category(category);
+        } else {
+            self.work.remove_category(category);
+        }
+        self.origins.retain(|(t, c, _), _| t != entry_type || c != category);
+    }
+
+    fn remove_pattern(&mut self, entry_type: &str, category: &str, pattern: &str) {
+        if self.category_exists(entry_type, category) {
+            let category = self.find_cat(entry_type, category);
+            if let Some(position) = category.patterns.iter().rposition(|p| p == pattern) {
+                category.patterns.remove(position);
+            }
+        }
+        self.origins.remove(&(String::from(entry_type), String::from(category), Some(String::from(pattern))));
+    }
+
+    /// Renames a category, keeping its place, its patterns and the layers they came from.
+    fn rename_category(&mut self, entry_type: &str, category: &str, new_name: &str) {
+        if entry_type == "personal" {
+            self.personal.rename_category(category, new_name);
+        } else {
+            self.work.rename_category(category, new_name);
+        }
+        self.origins = self.origins.drain().map(|((t, c, p), layer)| {
+            if t == entry_type && c == category {
+                ((t, String::from(new_name), p), layer)
+            } else {
+                ((t, c, p), layer)
+            }
+        }).collect();
+    }
+
+    /// Moves the patterns of one category into another, which must exist, then removes the first.
+    fn merge_category(&mut self, entry_type: &str, category: &str, into: &str) {
+        let patterns = self.find_cat(entry_type, category).patterns.clone();
+        for pattern in patterns {
+            let origin = self.origin(entry_type, category, Some(&pattern));
+            let target = self.find_cat(entry_type, into);
+            if !target.patterns.contains(&pattern) {
+                target.patterns.push(pattern.clone());
+                self.origins.insert((String::from(entry_type), String::from(into), Some(pattern)), origin);
+            }
+        }
+        self.remove_category(entry_type, category);
+    }
+
+    /// Swaps a pattern for another in the same place and the same layer.
+    fn replace_pattern(&mut self, entry_type: &str, category: &str, pattern: &str, replacement: &str) {
+        let origin = self.origins.remove(&(String::from(entry_type), String::from(category), Some(String::from(pattern))));
+        let patterns = &mut self.find_cat(entry_type, category).patterns;
+        if let Some(position) = patterns.iter().position(|p| p == pattern) {
+            patterns[position] = String::from(replacement);
+        }
+        if let Some(origin) = origin {
+            self.origins.insert((String::from(entry_type), String::from(category), Some(String::from(replacement))), origin);
+        }
     }
 
     fn export(&self) -> Yaml {
+        self.export_layer(None)
+    }
+
+    /// The categories and patterns from one layer, along with the rest of that layer's file, or
+    /// the whole config when `layer` is `None`.
+    fn export_layer(&self, layer: Option<usize>) -> Yaml {
+        let in_layer = |origin: usize| layer.is_none_or(|l| l == origin);
+        let sections = layer.map(|l| &self.layers[l].sections);
+
         let mut config: LinkedHashMap<Yaml, Yaml> = LinkedHashMap::new();
+        config.insert(Yaml::from_str("version"), Yaml::Integer(migration::VERSION));
+        if let Some(include) = sections.and_then(|s| s.get(&Yaml::from_str("include"))) {
+            config.insert(Yaml::from_str("include"), include.clone());
+        }
         let mut new_categories: LinkedHashMap<Yaml, Yaml> = LinkedHashMap::new();
-        let mut personal_categories = Vec::new();
-        let mut work_categories = Vec::new();
         let mut patterns: LinkedHashMap<Yaml, Yaml> = LinkedHashMap::new();
-        let mut personal_patterns: LinkedHashMap<Yaml, Yaml> = LinkedHashMap::new();
-        let mut work_patterns: LinkedHashMap<Yaml, Yaml> = LinkedHashMap::new();
-        for cat in self.personal.categories.iter()  {
-            personal_categories.push(Yaml::from_str(&cat.name));
-            let patterns: Vec<Yaml> = cat.patterns.iter().map(|x| Yaml::from_str(x)).collect();
-            personal_patterns.insert(Yaml::from_str(&cat.name), Yaml::Array(patterns));
-        }
-        for cat in self.work.categories.iter()  {
-            work_categories.push(Yaml::from_str(&cat.name));
-            let patterns: Vec<Yaml> = cat.patterns.iter().map(|x| Yaml::from_str(x)).collect();
-            work_patterns.insert(Yaml::from_str(&cat.name), Yaml::Array(patterns));
-        }
-        new_categories.insert(Yaml::from_str("personal"), Yaml::Array(personal_categories));
-        new_categories.insert(Yaml::from_str("work"), Yaml::Array(work_categories));
-        patterns.insert(Yaml::from_str("personal"), Yaml::Hash(personal_patterns));
-        patterns.insert(Yaml::from_str("work"), Yaml::Hash(work_patterns));
+        for (entry_type, catalogue) in [("personal", &self.personal), ("work", &self.work)] {
+            let mut type_categories = Vec::new();
+            let mut type_patterns: LinkedHashMap<Yaml, Yaml> = LinkedHashMap::new();
+            for cat in catalogue.categories.iter() {
+                let cat_patterns: Vec<Yaml> = cat.patterns.iter()
+                    .filter(|p| in_layer(self.origin(entry_type, &cat.name, Some(p))))
+                    .map(|x| Yaml::from_str(x))
+                    .collect();
+                if in_layer(self.origin(entry_type, &cat.name, None)) || !cat_patterns.is_empty() {
+                    type_categories.push(Yaml::from_str(&cat.name));
+                    type_patterns.insert(Yaml::from_str(&cat.name), Yaml::Array(cat_patterns));
+                }
+            }
+            new_categories.insert(Yaml::from_str(entry_type), Yaml::Array(type_categories));
+            patterns.insert(Yaml::from_str(entry_type), Yaml::Hash(type_patterns));
+        }
         config.insert(Yaml::from_str("categories"), Yaml::Hash(new_categories));
         config.insert(Yaml::from_str("patterns"), Yaml::Hash(patterns));
+        match sections {
+            Some(sections) => {
+                for (key, value) in sections.iter().filter(|(k, _)| k.as_str() != Some("version") && k.as_str() != Some("include")) {
+                    config.insert(key.clone(), value.clone());
+                }
+            },
+            None => {
+                if let Some(currency) = &self.currency {
+                    config.insert(Yaml::from_str("currency"), Yaml::String(currency.clone()));
+                }
+                if self.normaliser != Normaliser::default() {
+                    config.insert(Yaml::from_str("cleaning"), write_normaliser(&self.normaliser));
+                }
+                if !self.accounts.is_empty() {
+                    config.insert(Yaml::from_str("accounts"), write_accounts(&self.accounts));
+                }
+            }
+        }
         Yaml::Hash(config)
     }
 }
 
+/// Reads the optional `cleaning` section, in which every step may be left out.
+fn read_normaliser(cleaning: &Yaml) -> Normaliser {
+    let strings = |list: &Yaml| -> Vec<String> {
+        list.as_vec().map(|l| l.iter().filter_map(|s| s.as_str()).map(String::from).collect()).unwrap_or_default()
+    };
+
+    Normaliser {
+        strip_prefixes: strings(&cleaning["strip_prefixes"]),
+        mask_digits: cleaning["mask_digits"].as_bool().unwrap_or(false),
+        collapse_whitespace: cleaning["collapse_whitespace"].as_bool().unwrap_or(false),
+        aliases: cleaning["aliases"].as_hash().map(|aliases| {
+            aliases.iter().filter_map(|(canonical, variants)| canonical.as_str().map(|c| (String::from(c), strings(variants)))).collect()
+        }).unwrap_or_default()
+    }
+}
+
+fn write_normaliser(normaliser: &Normaliser) -> Yaml {
+    let strings = |list: &[String]| Yaml::Array(list.iter().map(|s| Yaml::from_str(s)).collect());
+
+    let mut cleaning: LinkedHashMap<Yaml, Yaml> = LinkedHashMap::new();
+    let mut aliases: LinkedHashMap<Yaml, Yaml> = LinkedHashMap::new();
+    for (canonical, variants) in normaliser.aliases.iter() {
+        aliases.insert(Yaml::String(canonical.clone()), strings(variants));
+    }
+    cleaning.insert(Yaml::from_str("strip_prefixes"), strings(&normaliser.strip_prefixes));
+    cleaning.insert(Yaml::from_str("mask_digits"), Yaml::Boolean(normaliser.mask_digits));
+    cleaning.insert(Yaml::from_str("collapse_whitespace"), Yaml::Boolean(normaliser.collapse_whitespace));
+    cleaning.insert(Yaml::from_str("aliases"), Yaml::Hash(aliases));
+    Yaml::Hash(cleaning)
+}
+
+/// Reads the optional `accounts` section, which is keyed by account name.
+fn read_accounts(accounts: &Yaml) -> Vec<Account> {
+    accounts.as_hash().map(|accounts| {
+        accounts.iter().filter_map(|(name, fields)| {
+            let field = |field: &str| String::from(fields[field].as_str().unwrap_or(""));
+            Some(Account {
+                name: String::from(name.as_str()?),
+                institution: field("institution"),
+                currency: field("currency"),
+                account_type: AccountType::parse(fields["type"].as_str()?)?,
+                files: field("files")
+            })
+        }).collect()
+    }).unwrap_or_default()
+}
+
+fn write_accounts(accounts: &[Account]) -> Yaml {
+    let mut written: LinkedHashMap<Yaml, Yaml> = LinkedHashMap::new();
+    for account in accounts {
+        let mut fields: LinkedHashMap<Yaml, Yaml> = LinkedHashMap::new();
+        fields.insert(Yaml::from_str("institution"), Yaml::String(account.institution.clone()));
+        fields.insert(Yaml::from_str("currency"), Yaml::String(account.currency.clone()));
+        fields.insert(Yaml::from_str("type"), Yaml::from_str(account.account_type.name()));
+        fields.insert(Yaml::from_str("files"), Yaml::String(account.files.clone()));
+        written.insert(Yaml::String(account.name.clone()), Yaml::Hash(fields));
+    }
+    Yaml::Hash(written)
+}
 
 fn config_template() -> Yaml {
     let mut config: LinkedHashMap<Yaml, Yaml> = LinkedHashMap::new();
@@ -225,11 +614,51 @@ fn config_template() -> Yaml {
     types.insert(Yaml::from_str("work"), Yaml::Array(Vec::new()));
     patterns.insert(Yaml::from_str("personal"), Yaml::Hash(LinkedHashMap::new()));
     patterns.insert(Yaml::from_str("work"), Yaml::Hash(LinkedHashMap::new()));
+    config.insert(Yaml::from_str("version"), Yaml::Integer(migration::VERSION));
     config.insert(Yaml::from_str("categories"), Yaml::Hash(types));
     config.insert(Yaml::from_str("patterns"), Yaml::Hash(patterns));
     Yaml::Hash(config)
 }
 
+/// What a test classification left behind.
+#[cfg(test)]
+struct Classified {
+    pending: Vec<StringRecord>,
+    report: ActivityReport,
+    config: Config,
+    /// The config as it was written while classifying, if it was.
+    saved: Option<Config>
+}
+
+/// Classifies `entries` with `script` as the answers, starting from the template config with
+/// `rules`, each a category and its patterns, and with no history or model.
+#[cfg(test)]
+fn classify_scripted(script: &[&str], rules: &[(&str, &str, &[&str])], entries: Vec<StringRecord>) -> Classified {
+    let mut ui = ScriptedUI::new(script.iter().map(|answer| String::from(*answer)).collect());
+    classify_with(&mut ui, rules, &History::new(), &Classifier::new(), entries)
+}
+
+/// Classifies `entries` as `classify_scripted` does, with any interface, history and model.
+#[cfg(test)]
+fn classify_with(ui: &mut dyn UserInterface, rules: &[(&str, &str, &[&str])], history: &History, classifier: &Classifier, entries: Vec<StringRecord>) -> Classified {
+    static RUNS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    let run = RUNS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    let config_path = env::temp_dir().join(format!("bsi_classify_{}_{}.yml", std::process::id(), run));
+
+    let mut config = deserialise(serialise(&config_template())).unwrap();
+    for (entry_type, category, patterns) in rules {
+        config.add_category(entry_type, category);
+        config.find_cat(entry_type, category).patterns.extend(patterns.iter().map(|p| String::from(*p)));
+    }
+    let mut report = ActivityReport::new();
+
+    let pending = classify(entries, &mut config, ui, &mut report, history, classifier, &config_path).unwrap();
+    let saved = fs::read_to_string(&config_path).ok().map(|contents| deserialise(contents).unwrap());
+    let _ = fs::remove_file(&config_path);
+
+    Classified { pending, report, config, saved }
+}
+
 fn serialise(structure: &Yaml) -> String {
     let mut out = String::new();
     let mut emitter = YamlEmitter::new(&mut out);
@@ -238,10 +667,169 @@ fn serialise(structure: &Yaml) -> String {
     out
 }
 
-fn deserialise(contents: String) -> Result<Config, ScanError> {
-    let mut config = YamlLoader::load_from_str(&contents)?;
-    let contents = config.pop().unwrap().into_hash().unwrap();
-    Ok(Config::new(contents))
+/// Parses a config that is not layered, which is checked first so that a bad file is reported
+/// rather than panicking.
+#[cfg(test)]
+fn deserialise(contents: String) -> Result<Config, Vec<Problem>> {
+    parse_config(&contents).map(Config::new)
+}
+
+/// Checks a config file and brings it up to the current version in memory.
+fn parse_config(contents: &str) -> Result<LinkedHashMap<Yaml, Yaml>, Vec<Problem>> {
+    let problems = schema::check(contents);
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+
+    let mut config = YamlLoader::load_from_str(contents).expect("Checked config did not parse").pop().unwrap();
+    migration::migrate(&mut config).map_err(|message| vec![Problem { line: 1, message }])?;
+    Ok(config.into_hash().unwrap())
+}
+
+/// The layers around the user's own config, lowest precedence first: system wide, shared with a
+/// team, the user's own, then one for the project in the current directory. Only the user's own
+/// config has to exist.
+fn layer_paths(config_path: &Path) -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from("/etc/bank_statement_importer/config.yml")];
+    if let Some(config_dir) = dirs::config_dir() {
+        paths.push(config_dir.join("bank_statement_importer").join("team.yml"));
+    }
+    paths.push(config_path.to_path_buf());
+    if let Ok(current_dir) = env::current_dir() {
+        paths.push(current_dir.join(".bank_statement_importer.yml"));
+    }
+    paths.into_iter().filter(|p| p.exists()).collect()
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[test]
+fn test_includes_are_read_before_the_including_file() {
+    let directory = env::temp_dir().join(format!("bsi_include_{}", std::process::id()));
+    fs::create_dir_all(directory.join("shared")).unwrap();
+    fs::write(directory.join("shared").join("rules.yml"), "categories:\n  personal: [groceries]\n  work: []\npatterns:\n  personal:\n    groceries: [SHOP]\n  work: {}\ninclude: [../config.yml]\n").unwrap();
+    fs::write(directory.join("config.yml"), "include: [shared/rules.yml, missing.yml]\ncategories:\n  personal: []\n  work: []\npatterns:\n  personal: {}\n  work: {}\n").unwrap();
+
+    let mut files = Vec::new();
+    let mut problems = Vec::new();
+    read_layer(&directory.join("config.yml"), &mut files, &mut Vec::new(), &mut problems);
+    let _ = fs::remove_dir_all(&directory);
+
+    let names: Vec<String> = files.iter().map(|(p, _)| p.file_name().unwrap().to_string_lossy().into_owned()).collect();
+    assert_eq!(names, vec!["rules.yml", "config.yml"]);
+    assert_eq!(problems.len(), 1);
+    assert!(problems[0].ends_with("which does not exist"));
+}
+
+/// Reads a config file into `files`, after the files it includes, which are relative to it. Files
+/// already read, or being read, are skipped.
+fn read_layer(path: &Path, files: &mut Vec<ConfigFile>, reading: &mut Vec<PathBuf>, problems: &mut Vec<String>) {
+    let path = canonical(path);
+    if files.iter().any(|(p, _)| *p == path) || reading.contains(&path) {
+        return;
+    }
+
+    let contents = match fs::read_to_string(&path).map_err(|e| vec![Problem { line: 1, message: e.to_string() }]).and_then(|c| parse_config(&c)) {
+        Ok(contents) => contents,
+        Err(found) => {
+            problems.extend(found.iter().map(|p| format!("{}: {}", path.display(), p)));
+            return;
+        }
+    };
+
+    reading.push(path.clone());
+    if let Some(Yaml::Array(includes)) = contents.get(&Yaml::from_str("include")) {
+        for include in includes.iter().filter_map(|i| i.as_str()) {
+            let included = path.parent().unwrap_or_else(|| Path::new(".")).join(include);
+            if included.exists() {
+                read_layer(&included, files, reading, problems);
+            } else {
+                problems.push(format!("{}: includes {}, which does not exist", path.display(), included.display()));
+            }
+        }
+    }
+    reading.pop();
+
+    files.push((path, contents));
+}
+
+/// Reads every layer of the config, returning the files in order along with any problems found.
+fn read_layers(config_path: &Path) -> (Vec<ConfigFile>, Vec<String>) {
+    let mut files = Vec::new();
+    let mut problems = Vec::new();
+    for path in layer_paths(config_path) {
+        read_layer(&path, &mut files, &mut Vec::new(), &mut problems);
+    }
+    (files, problems)
+}
+
+#[test]
+fn test_upgrading_config_keeps_backup() {
+    let config_path = env::temp_dir().join(format!("bsi_upgrade_{}.yml", std::process::id()));
+    let original = "categories:\n  personal: [groceries]\n  work: []\npatterns:\n  personal:\n    groceries: [SHOP]\n  work: {}\n";
+    fs::write(&config_path, original).unwrap();
+
+    let upgraded = upgrade_config(&config_path).unwrap();
+    let backup = fs::read_to_string(config_path.with_extension("yml.v0.bak")).unwrap();
+    let config = deserialise(fs::read_to_string(&config_path).unwrap());
+    let again = upgrade_config(&config_path).unwrap();
+    let _ = fs::remove_file(&config_path);
+    let _ = fs::remove_file(config_path.with_extension("yml.v0.bak"));
+
+    assert_eq!(upgraded, Some((0, config_path.with_extension("yml.v0.bak"))));
+    assert_eq!(backup, original);
+    assert_eq!(config.unwrap().personal_categories(), vec!["groceries"]);
+    assert_eq!(again, None);
+}
+
+/// The config file's contents, and what they are once migrated along with the version they were,
+/// or `None` when the file does not parse, which is left for the schema check to report.
+fn migrated_config(config_path: &Path) -> Result<Option<(String, Yaml, i64)>, String> {
+    let contents = fs::read_to_string(config_path).map_err(|e| e.to_string())?;
+
+    let mut config = match YamlLoader::load_from_str(&contents) {
+        Ok(mut documents) if !documents.is_empty() => documents.remove(0),
+        _ => return Ok(None)
+    };
+
+    let version = migration::migrate(&mut config)?;
+    Ok(Some((contents, config, version)))
+}
+
+/// Brings the config file up to the current version under the config lock, first writing a
+/// backup named after the version it was. Returns that version and the backup when anything
+/// changed.
+fn upgrade_config(config_path: &Path) -> Result<Option<(i64, PathBuf)>, String> {
+    // Only files that need upgrading are locked, so that reading a config never waits on a session
+    match migrated_config(config_path)? {
+        Some((_, _, version)) if version != migration::VERSION => {},
+        _ => return Ok(None)
+    }
+
+    let _lock = lock_config(config_path)?;
+    let (contents, config, version) = match migrated_config(config_path)? {
+        Some((contents, config, version)) if version != migration::VERSION => (contents, config, version),
+        _ => return Ok(None)
+    };
+
+    let backup_path = config_path.with_extension(format!("yml.v{}.bak", version));
+    store::write_atomically(&backup_path, &contents).map_err(|e| e.to_string())?;
+    store::write_atomically(config_path, &serialise(&config)).map_err(|e| e.to_string())?;
+
+    Ok(Some((version, backup_path)))
+}
+
+fn load_config(config_path: &Path) -> Result<Config, String> {
+    let (files, problems) = read_layers(config_path);
+    if !problems.is_empty() {
+        return Err(problems.join("\n"));
+    }
+
+    let primary_path = canonical(config_path);
+    let primary = files.iter().position(|(p, _)| *p == primary_path).expect("Config file was not read");
+    Ok(Config::from_layers(files, primary))
 }
 
 fn get_date_boundaries(start_date_string: &str) -> Result<(NaiveDate, NaiveDate), ParseError> {
@@ -259,93 +847,1617 @@ fn get_date_boundaries(start_date_string: &str) -> Result<(NaiveDate, NaiveDate)
 }
 
 
+/// Names a config file to use instead of the default one.
+const CONFIG_VARIABLE: &str = "BANK_STATEMENT_IMPORTER_CONFIG";
+
+/// The files kept next to the config, named after it with these extensions.
+const COMPANION_EXTENSIONS: [&str; 4] = ["history.csv", "pending.csv", "model.csv", "rates.csv"];
+
 fn main() {
-    let mut config_path = dirs::home_dir().expect("Can't find home dir");
-    config_path.push(".bank_statement_importer.yml");
+    let mut args : Vec<String>= env::args().collect();
+
+    let config_path = match take_flag(&mut args, "--config").or_else(|| env::var(CONFIG_VARIABLE).ok().filter(|v| !v.is_empty())) {
+        Some(config_path) => PathBuf::from(config_path),
+        None => {
+            let config_path = dirs::config_dir().expect("Can't find config dir").join("bank_statement_importer").join("config.yml");
+            let legacy_path = dirs::home_dir().expect("Can't find home dir").join(".bank_statement_importer.yml");
+            if migrate_legacy_config(&legacy_path, &config_path).expect("Could not move legacy config") {
+                eprintln!("Config moved from {:?} to {:?}", legacy_path, config_path);
+            }
+            config_path
+        }
+    };
 
     if config_path.exists() {
-        println!("Config exists at {:?}", config_path);
+        eprintln!("Config exists at {:?}", config_path);
+    } else {
+        if let Some(directory) = config_path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(directory).expect("Could not create config directory");
+        }
+        store::write_atomically(&config_path, &serialise(&config_template())).expect("Could not write config file");
+        eprintln!("Config created at {:?}", config_path);
+    }
+
+    match upgrade_config(&config_path) {
+        Ok(Some((version, backup_path))) => eprintln!("Config upgraded from version {} to {}, previous version kept at {:?}", version, migration::VERSION, backup_path),
+        Ok(None) => {},
+        Err(message) => {
+            eprintln!("{}: {}", config_path.display(), message);
+            exit(1);
+        }
+    }
+
+    let history_path = config_path.with_extension("history.csv");
+
+    // Commands return their errors rather than exiting, so that the config lock is released first
+    let result = match args.get(1).map(|a| a.as_str()) {
+        Some("trend") => trend(&args[2..], &config_path, &history_path),
+        Some("claim") => claim(&args[2..], &config_path, &history_path),
+        Some("rules") => rules(&args[2..], &config_path, &history_path),
+        Some("config") => config_command(&args[2..], &config_path),
+        Some("categories") => categories(&args[2..], &config_path, &history_path),
+        Some("patterns") => patterns(&args[2..], &config_path),
+        Some("recategorise") => recategorise(&args[2..], &config_path, &history_path),
+        _ => import(&args[1..], &config_path, &history_path)
+    };
+
+    if let Err(message) = result {
+        eprintln!("{}", message);
+        exit(1);
+    }
+}
+
+#[test]
+fn test_take_flag_removes_flag_and_value() {
+    let mut args: Vec<String> = vec!["bsi", "statements", "--config", "work.yml", "20191101"].into_iter().map(String::from).collect();
+
+    assert_eq!(take_flag(&mut args, "--config"), Some(String::from("work.yml")));
+    assert_eq!(args, vec!["bsi", "statements", "20191101"]);
+    assert_eq!(take_flag(&mut args, "--config"), None);
+}
+
+fn take_flag(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let position = args.iter().position(|a| a == flag)?;
+    args.remove(position);
+    if position < args.len() {
+        Some(args.remove(position))
     } else {
-        fs::write(&config_path, serialise(&config_template())).expect("Could not write config file");
-        println!("Config created at {:?}", config_path);
+        None
     }
+}
 
-    let mut config = deserialise(fs::read_to_string(&config_path).expect("Could not read config file")).expect("Could not parse config contents");
+#[test]
+fn test_migrating_legacy_config_moves_companion_files() {
+    let directory = env::temp_dir().join(format!("bsi_migrate_{}", std::process::id()));
+    let legacy_path = directory.join(".bank_statement_importer.yml");
+    let config_path = directory.join("xdg").join("bank_statement_importer").join("config.yml");
+    fs::create_dir_all(&directory).unwrap();
+    fs::write(&legacy_path, "legacy").unwrap();
+    fs::write(legacy_path.with_extension("history.csv"), "history").unwrap();
 
-    let args : Vec<String>= env::args().collect();
+    let migrated = migrate_legacy_config(&legacy_path, &config_path).unwrap();
+    let config = fs::read_to_string(&config_path).unwrap();
+    let history = fs::read_to_string(config_path.with_extension("history.csv")).unwrap();
+    let again = migrate_legacy_config(&legacy_path, &config_path).unwrap();
+    let _ = fs::remove_dir_all(&directory);
 
-    let input_directory_path = &args[1];
-    let start_date_string = &args[2];
+    assert!(migrated);
+    assert_eq!(config, "legacy");
+    assert_eq!(history, "history");
+    assert!(!again);
+}
 
-    let (start_date, end_date_excl) = get_date_boundaries(start_date_string).expect("Could not set date boundaries");
+/// Moves the config from `legacy_path`, along with its companion files, to `config_path` unless
+/// there is already a config there. Returns whether anything was moved.
+fn migrate_legacy_config(legacy_path: &Path, config_path: &Path) -> std::io::Result<bool> {
+    if config_path.exists() || !legacy_path.exists() {
+        return Ok(false);
+    }
 
-    let mut raw_entries: Vec<StringRecord> = Vec::new();
+    if let Some(directory) = config_path.parent() {
+        fs::create_dir_all(directory)?;
+    }
 
-    for file in fs::read_dir(input_directory_path).unwrap() {
-        let mut reader = Reader::from_path(file.unwrap().path()).unwrap();
-        for result in reader.records() {
-            let record = result.unwrap();
-            let record_date = NaiveDate::parse_from_str(record.get(0).unwrap(), "%Y%m%d").unwrap();
-            if record_date >= start_date && record_date < end_date_excl {
-                raw_entries.push(record);
-            }
+    let companions = COMPANION_EXTENSIONS.iter().map(|e| (legacy_path.with_extension(e), config_path.with_extension(e)));
+    for (from, to) in companions.chain(std::iter::once((legacy_path.to_path_buf(), config_path.to_path_buf()))) {
+        if from.exists() {
+            // Copy rather than rename, which fails when the config directory is on another file system
+            fs::copy(&from, &to)?;
+            fs::remove_file(&from)?;
         }
     }
 
-    let mut classification = HashMap::new();
-    let personal_entries: HashMap<String, Vec<f32>> = HashMap::new();
-    let work_entries: HashMap<String, Vec<f32>> = HashMap::new();
-    classification.insert(String::from("personal"), personal_entries);
-    classification.insert(String::from("work"), work_entries);
+    Ok(true)
+}
+
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter().position(|a| a == flag).and_then(|i| args.get(i + 1)).map(|v| v.as_str())
+}
+
+fn has_flag(args: &[String], flag: &str) -> bool {
+    args.iter().any(|a| a == flag)
+}
 
+#[test]
+fn test_history_is_reported_in_one_currency() {
+    let rates_path = env::temp_dir().join(format!("bsi_one_currency_{}.rates.csv", std::process::id()));
+    fs::write(&rates_path, "date,from,to,rate\n20191101,USD,ZAR,15\n").unwrap();
     let mut report = ActivityReport::new();
-    let ui = UI {};
+    report.add_entry("personal", "travel", &StringRecord::from(vec!["20191101", "HOTEL", "-100.00", "0.00", "travel", "USD"]));
+    report.add_entry("personal", "groceries", &StringRecord::from(vec!["20191102", "SHOP", "-50.00", "0.00", "everyday", "ZAR"]));
+    let entries = report.entries().to_vec();
+    let mut unknown = ActivityReport::new();
+    unknown.add_entry("personal", "groceries", &StringRecord::from(vec!["20191103", "MARKET", "-5.00", "0.00", "card", ""]));
 
-    for entry in raw_entries {
-        
-        ui.display_entry(&entry);
+    let mixed = in_one_currency(entries.clone(), None, &rates_path);
+    let converted = in_one_currency(entries, Some("ZAR"), &rates_path);
+    let without_currency = in_one_currency(unknown.entries().to_vec(), Some("ZAR"), &rates_path);
+    let _ = fs::remove_file(&rates_path);
 
-        let entry_type = ui.get_type();
+    assert_eq!(mixed, Err(String::from("Entries are in USD and ZAR; choose one to report in with --currency CODE")));
+    assert_eq!(converted.unwrap().iter().map(|e| (e.amount, e.base_currency.as_str())).collect::<Vec<_>>(), vec![(Decimal::new(1500, 0), "ZAR"), (Decimal::new(50, 0), "ZAR")]);
+    assert!(without_currency.unwrap_err().starts_with("Entries from account card have no currency to convert to ZAR from\n"));
+}
 
-        let selected_category = match config.match_category(entry_type, entry.get(1).unwrap()) {
-            Some(c) => {
-                ui.display_automap(&c.name);
-                c.name.clone()
-            },
-            None => {
-                    if entry_type == "personal" {
-                        ui.display_categories("personal", &config.personal_categories());
-                    } else {
-                        ui.display_categories("work", &config.work_categories());
-                    }
+/// Why an amount in `currency` from `account` on `date` cannot be reported in `base`, if it cannot.
+fn conversion_problem(rates: &Rates, currency: &str, account: &str, base: &str, date: NaiveDate) -> Option<String> {
+    if currency.is_empty() {
+        let source = if account.is_empty() { String::from("statements that match no account") } else { format!("account {}", account) };
+        Some(format!("Entries from {} have no currency to convert to {} from", source, base.to_uppercase()))
+    } else if rates.convert(Decimal::new(1, 0), currency, base, date).is_none() {
+        Some(format!("No rate from {} to {} on or before {}", currency.to_uppercase(), base.to_uppercase(), date.format("%Y-%m-%d")))
+    } else {
+        None
+    }
+}
 
-                    let category = ui.capture_category();
+fn check_conversions(mut problems: Vec<String>, rates_path: &Path) -> Result<(), String> {
+    if problems.is_empty() {
+        return Ok(());
+    }
 
-                    config.add_category(entry_type, category.trim());
+    problems.sort();
+    problems.dedup();
+    problems.push(format!("Give accounts a currency, and add rates to {:?} with date, from, to and rate columns", rates_path));
+    Err(problems.join("\n"))
+}
 
-                    if let Some(pattern) = ui.capture_pattern() {
-                        let category = config.find_cat(entry_type, category.trim());
-                        category.patterns.push(String::from(pattern));
-                    }
+/// Puts `entries` in one currency: `base` if given, converting their original amounts again,
+/// otherwise the one they were recorded in, which has to be the same for all of them.
+fn in_one_currency(entries: Vec<CategorisedEntry>, base: Option<&str>, rates_path: &Path) -> Result<Vec<CategorisedEntry>, String> {
+    match base {
+        Some(base) => {
+            let rates = Rates::load(rates_path).map_err(|e| format!("Could not read rates file {:?}: {}", rates_path, e))?;
+            check_conversions(entries.iter().filter_map(|e| conversion_problem(&rates, &e.currency, &e.account, base, e.date)).collect(), rates_path)?;
+            let mut report = ActivityReport::from_entries(entries);
+            report.convert_to(base, rates);
+            Ok(report.entries().to_vec())
+        },
+        None => {
+            // Entries converted before the base was recorded could be in any currency
+            if entries.iter().any(|e| e.base_currency.is_empty() && !e.currency.is_empty()) {
+                return Err(String::from("Some entries were converted to a currency that was not recorded; choose one to report in with --currency CODE"));
+            }
+            let mut currencies: Vec<String> = entries.iter().map(|e| e.base_currency.to_uppercase()).filter(|c| !c.is_empty()).collect();
+            currencies.sort();
+            currencies.dedup();
+            if currencies.len() > 1 {
+                return Err(format!("Entries are in {}; choose one to report in with --currency CODE", currencies.join(" and ")));
+            }
+            Ok(entries)
+        }
+    }
+}
 
-                    fs::write(&config_path, serialise(&config.export())).expect("Could not write config file");
+#[test]
+fn test_trend_reports_counts_that_are_not_numbers() {
+    let config_path = env::temp_dir().join(format!("bsi_trend_counts_{}.yml", std::process::id()));
+    let args: Vec<String> = vec!["201911", "--periods", "x"].into_iter().map(String::from).collect();
 
-                    config = deserialise(fs::read_to_string(&config_path).expect("Could not read config file")).expect("Could not parse config contents");
+    let result = trend(&args, &config_path, &config_path.with_extension("history.csv"));
 
-                    category
-            }
-        };
+    assert!(result.unwrap_err().starts_with("--periods must be a number\nUsage: bank_statement_importer trend YYYYMM"));
+}
 
-        if entry_type == "personal" && config.personal_categories().contains(&String::from(selected_category.trim())) {
-            report.add_entry(entry_type, selected_category.trim(), &entry);
-        } else if entry_type == "work" && config.work_categories().contains(&String::from(selected_category.trim())) {
-            report.add_entry(entry_type, selected_category.trim(), &entry);
-        } else {
-            println!("Category {} does not exist", selected_category);
-            exit(3);
+fn trend(args: &[String], config_path: &Path, history_path: &Path) -> Result<(), String> {
+    let usage = "Usage: bank_statement_importer trend YYYYMM [--yoy] [--periods N] [--window N] [--account NAME] [--currency CODE] [--format table|csv|json]";
+    let last = match args.first().and_then(|a| Period::parse(a)) {
+        Some(period) => period,
+        None => return Err(String::from(usage))
+    };
+
+    let year_on_year = has_flag(args, "--yoy");
+    let count = |flag: &str, default: usize| -> Result<usize, String> {
+        match flag_value(args, flag) {
+            Some(value) => value.parse().map_err(|_| format!("{} must be a number\n{}", flag, usage)),
+            None => Ok(default)
         }
+    };
+    let periods = count("--periods", if year_on_year { 3 } else { 12 })?;
+    let window = count("--window", 3)?;
+
+    let history = History::load(history_path).map_err(|e| format!("Could not read history file {:?}: {}", history_path, e))?;
+    let entries: Vec<CategorisedEntry> = match flag_value(args, "--account") {
+        Some(account_name) => history.entries().iter().filter(|e| e.account == account_name).cloned().collect(),
+        None => history.entries().to_vec()
+    };
+    let entries = in_one_currency(entries, flag_value(args, "--currency"), &config_path.with_extension("rates.csv"))?;
+
+    let report = if year_on_year {
+        TrendReport::year_on_year(&entries, last, periods, window)
+    } else {
+        TrendReport::monthly(&entries, last, periods, window)
+    };
+
+    match flag_value(args, "--format").unwrap_or("table") {
+        "table" => print!("{}", report.to_table()),
+        "csv" => print!("{}", report.to_csv()),
+        "json" => println!("{}", report.to_json()),
+        other => return Err(format!("Unknown format {}", other))
+    }
+    Ok(())
+}
+
+#[test]
+fn test_claim_waits_for_the_config_lock() {
+    let directory = env::temp_dir().join(format!("bsi_claim_lock_{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let config_path = directory.join("config.yml");
+    let history_path = directory.join("config.history.csv");
+    let mut report = ActivityReport::new();
+    report.add_entry("work", "travel", &StringRecord::from(vec!["20191103", "AIRLINE", "-300.00", "0.00"]));
+    let mut history = History::new();
+    history.record(&report);
+    history.save(&history_path).unwrap();
+    let before = fs::read_to_string(&history_path).unwrap();
+    let args: Vec<String> = vec!["20191101", "--output", directory.to_str().unwrap()].into_iter().map(String::from).collect();
+
+    let lock = Lock::acquire(&config_path).unwrap();
+    let locked = claim(&args, &config_path, &history_path);
+    let unchanged = fs::read_to_string(&history_path).unwrap() == before;
+    drop(lock);
+    let claimed = claim(&args, &config_path, &history_path);
+    let marked = History::load(&history_path).unwrap().entries()[0].claim_reference.clone();
+    let _ = fs::remove_dir_all(&directory);
+
+    assert!(locked.unwrap_err().contains("is in use by another session"));
+    assert!(unchanged);
+    assert_eq!(claimed, Ok(()));
+    assert_eq!(marked, Some(String::from("CLAIM-201911-01")));
+}
+
+#[test]
+fn test_claim_reports_mistyped_dates() {
+    let config_path = env::temp_dir().join(format!("bsi_claim_dates_{}.yml", std::process::id()));
+    let history_path = config_path.with_extension("history.csv");
+    let args = |args: Vec<&str>| -> Vec<String> { args.into_iter().map(String::from).collect() };
+
+    assert_eq!(claim(&args(vec!["201911"]), &config_path, &history_path), Err(String::from("Could not read start date 201911: premature end of input")));
+    assert_eq!(claim(&args(vec!["20191101", "--end", "2019-11-30"]), &config_path, &history_path), Err(String::from("Could not read end date 2019-11-30: input contains invalid characters")));
+    assert!(!history_path.exists());
+}
+
+fn claim(args: &[String], config_path: &Path, history_path: &Path) -> Result<(), String> {
+    let start_date_string = match args.first() {
+        Some(start_date_string) => start_date_string,
+        None => return Err(String::from("Usage: bank_statement_importer claim YYYYMMDD [--end YYYYMMDD] [--reference REF] [--currency CODE] [--output DIR]"))
+    };
+
+    let (start_date, month_end_excl) = get_date_boundaries(start_date_string).map_err(|e| format!("Could not read start date {}: {}", start_date_string, e))?;
+    let end_date_excl = match flag_value(args, "--end") {
+        Some(end_date_string) => NaiveDate::parse_from_str(end_date_string, "%Y%m%d").map_err(|e| format!("Could not read end date {}: {}", end_date_string, e))?.succ(),
+        None => month_end_excl
+    };
+
+    // Held until the claimed entries are marked, so that an import cannot write history meanwhile
+    let _lock = lock_config(config_path)?;
+    let mut history = History::load(history_path).map_err(|e| format!("Could not read history file {:?}: {}", history_path, e))?;
+    let existing_references = history.claim_references();
+
+    let reference = match flag_value(args, "--reference") {
+        Some(reference) => String::from(reference),
+        None => claim::next_reference(start_date, &existing_references)
+    };
+
+    if existing_references.contains(&reference) {
+        return Err(format!("Claim {} already exists", reference));
+    }
+
+    let mut entries = history.between(start_date, end_date_excl);
+    // Refunds that came after the period still reduce what is claimed for debits in it
+    let later_refunds: Vec<CategorisedEntry> = history.entries().iter()
+        .filter(|r| r.date >= end_date_excl && r.refund_of.as_ref().is_some_and(|debit| entries.iter().any(|e| &e.record_fingerprint == debit)))
+        .cloned()
+        .collect();
+    entries.extend(later_refunds);
+    let entries = in_one_currency(entries, flag_value(args, "--currency"), &config_path.with_extension("rates.csv"))?;
+    let report = ActivityReport::from_entries(entries);
+    let claim = Claim::new(&reference, &report, start_date, end_date_excl);
+
+    if claim.entries().is_empty() {
+        println!("No unclaimed work expenses found");
+        return Ok(());
+    }
+
+    let output_directory = Path::new(flag_value(args, "--output").unwrap_or("."));
+    let csv_path = output_directory.join(format!("{}.csv", claim.reference()));
+    let html_path = output_directory.join(format!("{}.html", claim.reference()));
+    fs::write(&csv_path, claim.to_csv()).expect("Could not write claim CSV");
+    fs::write(&html_path, claim.to_html()).expect("Could not write claim HTML");
+
+    let fingerprints: Vec<String> = claim.entries().iter().map(|e| e.record_fingerprint.clone()).collect();
+    history.mark_claimed(&fingerprints, claim.reference());
+    history.save(history_path).expect("Could not write history file");
+
+    println!("Claim {} for {} ({} entries) written to {:?} and {:?}", claim.reference(), money(claim.total()), fingerprints.len(), csv_path, html_path);
+    Ok(())
+}
+
+#[test]
+fn test_recategorising_legacy_history_follows_matching_patterns() {
+    let directory = env::temp_dir().join(format!("bsi_recategorise_legacy_{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let config_path = directory.join("config.yml");
+    let history_path = directory.join("config.history.csv");
+    fs::write(&config_path, "categories:\n  personal: [groceries, shopping, gifts]\n  work: []\npatterns:\n  personal:\n    groceries: [TESCO]\n    shopping: [STORES]\n    gifts: []\n  work: {}\n").unwrap();
+    fs::write(&history_path, "category_type,category,date,description,expense,amount,record_fingerprint\npersonal,shopping,20191101,TESCO STORES,true,10.00,a\npersonal,gifts,20191102,TESCO EXPRESS,true,20.00,b\n").unwrap();
+    let args: Vec<String> = vec!["20191101", "--yes"].into_iter().map(String::from).collect();
+
+    let result = recategorise(&args, &config_path, &history_path);
+    let history = History::load(&history_path).unwrap();
+    let _ = fs::remove_dir_all(&directory);
+
+    assert_eq!(result, Ok(()));
+    assert_eq!(history.entries().iter().map(|e| (e.category.as_str(), e.manual)).collect::<Vec<_>>(), vec![("groceries", Some(false)), ("gifts", None)]);
+}
+
+#[test]
+fn test_recategorise_reports_mistyped_dates() {
+    let config_path = env::temp_dir().join(format!("bsi_recategorise_dates_{}.yml", std::process::id()));
+    let history_path = config_path.with_extension("history.csv");
+    let args = |args: Vec<&str>| -> Vec<String> { args.into_iter().map(String::from).collect() };
+
+    assert_eq!(recategorise(&args(vec!["2019110"]), &config_path, &history_path), Err(String::from("Could not read start date 2019110: input is out of range")));
+    assert_eq!(recategorise(&args(vec!["20191101", "--end", "20191131"]), &config_path, &history_path), Err(String::from("Could not read end date 20191131: input is out of range")));
+    assert!(!history_path.exists());
+}
+
+fn recategorise(args: &[String], config_path: &Path, history_path: &Path) -> Result<(), String> {
+    let start_date_string = match args.first() {
+        Some(start_date_string) => start_date_string,
+        None => return Err(String::from("Usage: bank_statement_importer recategorise YYYYMMDD [--end YYYYMMDD] [--force] [--yes]"))
+    };
+
+    let (start_date, month_end_excl) = get_date_boundaries(start_date_string).map_err(|e| format!("Could not read start date {}: {}", start_date_string, e))?;
+    let end_date_excl = match flag_value(args, "--end") {
+        Some(end_date_string) => NaiveDate::parse_from_str(end_date_string, "%Y%m%d").map_err(|e| format!("Could not read end date {}: {}", end_date_string, e))?.succ(),
+        None => month_end_excl
+    };
+
+    let _lock = lock_config(config_path)?;
+    let config = load_config(config_path)?;
+    let mut history = History::load(history_path).map_err(|e| format!("Could not read history file {:?}: {}", history_path, e))?;
+
+    let force = has_flag(args, "--force");
+    let (changes, kept): (Vec<Recategorisation>, Vec<Recategorisation>) = history
+        .recategorise(start_date, end_date_excl, |entry_type, description| config.match_category(entry_type, description).map(|c| c.name.clone()))
+        .into_iter()
+        .partition(|c| force || match c.manual {
+            Some(manual) => !manual,
+            // Entries recorded before this was noted count as chosen by a pattern if their category has one that matches
+            None => {
+                let catalogue = if c.category_type == "personal" { &config.personal } else { &config.work };
+                catalogue.categories.iter().any(|category| category.name == c.from && category.matching_pattern(&config.normalise(&c.description)).is_some())
+            }
+        });
+
+    if !kept.is_empty() {
+        println!("Keeping {} manually chosen categories; use --force to change them too", kept.len());
+    }
+
+    if changes.is_empty() {
+        println!("No categories would change");
+        return Ok(());
+    }
+
+    for change in changes.iter() {
+        println!("{} {} {}: {} -> {}", change.date.format("%Y%m%d"), change.description, change.category_type, change.from, change.to);
+    }
+
+    if !has_flag(args, "--yes") {
+        println!("Apply {} changes? Enter 'y' to confirm", changes.len());
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer).expect("Could not read answer");
+        if answer.trim() != "y" {
+            println!("Nothing changed");
+            return Ok(());
+        }
+    }
+
+    history.apply(&changes);
+    history.save(history_path).expect("Could not write history file");
+    Classifier::train(history.entries()).save(&config_path.with_extension("model.csv")).expect("Could not write model file");
+    println!("{} history entries recategorised", changes.len());
+    Ok(())
+}
+
+/// Reads every record from the statements in a directory, noting each file read in `sources`.
+/// Each record is given the name of the account its file belongs to, or an empty one, and its
+/// currency: from a `currency` column if the file has one, otherwise the account's.
+fn read_statements(input_directory_path: &str, sources: &mut Vec<String>, accounts: &[Account]) -> Vec<StringRecord> {
+    let mut records: Vec<StringRecord> = Vec::new();
+
+    for file in fs::read_dir(input_directory_path).unwrap() {
+        let path = file.unwrap().path();
+        sources.push(path.display().to_string());
+        let file_name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let account = account::find(accounts, &file_name);
+        if account.is_none() && !accounts.is_empty() {
+            eprintln!("{} does not belong to any account", file_name);
+        }
+        let account_name = account.map(|a| a.name.as_str()).unwrap_or("");
+        let account_currency = account.map(|a| a.currency.as_str()).unwrap_or("");
+
+        let mut reader = Reader::from_path(path).unwrap();
+        let currency_column = reader.headers().unwrap().iter().position(|h| h.trim().eq_ignore_ascii_case("currency"));
+        for result in reader.records() {
+            let record = result.unwrap();
+            let currency = currency_column.and_then(|c| record.get(c)).map(|c| c.trim()).filter(|c| !c.is_empty()).unwrap_or(account_currency);
+            records.push(statement_record(&record, account_name, currency));
+        }
+    }
+
+    records
+}
+
+/// The date, description, amount and balance of a statement record, followed by its account and
+/// currency.
+fn statement_record(record: &StringRecord, account_name: &str, currency: &str) -> StringRecord {
+    let mut fields: Vec<&str> = record.iter().take(report::ACCOUNT).collect();
+    fields.resize(report::ACCOUNT, "");
+    fields.push(account_name);
+    fields.push(currency);
+    StringRecord::from(fields)
+}
+
+#[test]
+fn test_failed_commands_release_the_lock_and_change_nothing() {
+    let directory = env::temp_dir().join(format!("bsi_failed_command_{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let config_path = directory.join("config.yml");
+    fs::write(&config_path, serialise(&config_template())).unwrap();
+    let args: Vec<String> = vec!["rename", "personal", "nosuch", "other"].into_iter().map(String::from).collect();
+
+    let result = categories(&args, &config_path, &config_path.with_extension("history.csv"));
+    let files = fs::read_dir(&directory).unwrap().count();
+    let _ = fs::remove_dir_all(&directory);
+
+    assert_eq!(result, Err(String::from("There is no personal category nosuch")));
+    assert_eq!(files, 1);
+}
+
+#[test]
+fn test_management_arguments_are_checked_before_locking() {
+    let config_path = env::temp_dir().join(format!("bsi_arguments_first_{}.yml", std::process::id()));
+    fs::write(&config_path, serialise(&config_template())).unwrap();
+    let lock = Lock::acquire(&config_path).unwrap();
+    let arguments = |list: Vec<&str>| -> Vec<String> { list.into_iter().map(String::from).collect() };
+
+    let bad_type = patterns(&arguments(vec!["add", "home", "groceries", "SHOP"]), &config_path);
+    let missing_target = categories(&arguments(vec!["merge", "personal", "food"]), &config_path, &config_path.with_extension("history.csv"));
+    let locked = categories(&arguments(vec!["add", "personal", "food"]), &config_path, &config_path.with_extension("history.csv"));
+    drop(lock);
+    let _ = fs::remove_file(&config_path);
+
+    assert_eq!(bad_type, Err(String::from("Unknown type home; expected personal or work")));
+    assert!(missing_target.unwrap_err().starts_with("Usage:"));
+    assert!(locked.unwrap_err().contains("in use by another session"));
+}
+
+/// Reads the type argument of a management command, which has to be personal or work.
+fn type_argument(arg: Option<&String>, usage: &str) -> Result<&'static str, String> {
+    match arg.map(|a| a.as_str()) {
+        Some("personal") => Ok("personal"),
+        Some("work") => Ok("work"),
+        Some(other) => Err(format!("Unknown type {}; expected personal or work", other)),
+        None => Err(String::from(usage))
+    }
+}
+
+/// Fails unless `category` is one of the categories of `entry_type`.
+fn require_category(config: &Config, entry_type: &str, category: &str) -> Result<(), String> {
+    if config.category_exists(entry_type, category) {
+        Ok(())
+    } else {
+        Err(format!("There is no {} category {}", entry_type, category))
+    }
+}
+
+fn categories(args: &[String], config_path: &Path, history_path: &Path) -> Result<(), String> {
+    let usage = "Usage: bank_statement_importer categories list [TYPE] | add TYPE NAME | rename TYPE NAME NEW_NAME | merge TYPE NAME INTO | delete TYPE NAME [--force]";
+    let command = args.first().map(|a| a.as_str()).unwrap_or("");
+    let argument = |index: usize| -> Result<&str, String> {
+        args.get(index).map(|a| a.as_str()).ok_or_else(|| String::from(usage))
+    };
+
+    if command == "list" {
+        let config = load_config(config_path)?;
+        let history = History::load(history_path).map_err(|e| format!("Could not read history file {:?}: {}", history_path, e))?;
+        let types: Vec<&str> = match args.get(1) {
+            Some(_) => vec![type_argument(args.get(1), usage)?],
+            None => vec!["personal", "work"]
+        };
+        for entry_type in types {
+            let catalogue = if entry_type == "personal" { &config.personal } else { &config.work };
+            for category in catalogue.categories.iter() {
+                let entries = history.entries().iter().filter(|e| e.category_type == entry_type && e.category == category.name).count();
+                println!("{}/{} ({} patterns, {} entries)", entry_type, category.name, category.patterns.len(), entries);
+            }
+        }
+        return Ok(());
+    }
+
+    // Arguments are checked before the config is locked, and the config before anything changes
+    let target = match command {
+        "add" | "delete" => None,
+        "rename" | "merge" => Some(argument(3)?),
+        _ => return Err(String::from(usage))
+    };
+    let entry_type = type_argument(args.get(1), usage)?;
+    let name = argument(2)?;
+    if command == "merge" && target == Some(name) {
+        return Err(String::from("Cannot merge a category into itself"));
+    }
+
+    let _lock = lock_config(config_path)?;
+    let mut config = load_config(config_path)?;
+    let mut history = History::load(history_path).map_err(|e| format!("Could not read history file {:?}: {}", history_path, e))?;
+    let mut history_changed = 0;
+
+    match (command, target) {
+        ("add", _) => {
+            if config.category_exists(entry_type, name) {
+                return Err(format!("{} category {} already exists", entry_type, name));
+            }
+            config.add_category(entry_type, name);
+            println!("Added {} category {}", entry_type, name);
+        },
+        ("rename", Some(new_name)) => {
+            require_category(&config, entry_type, name)?;
+            if config.category_exists(entry_type, new_name) {
+                return Err(format!("{} category {} already exists; use merge to combine them", entry_type, new_name));
+            }
+            config.rename_category(entry_type, name, new_name);
+            history_changed = history.rename_category(entry_type, name, new_name);
+            println!("Renamed {} category {} to {}", entry_type, name, new_name);
+        },
+        ("merge", Some(into)) => {
+            require_category(&config, entry_type, name)?;
+            require_category(&config, entry_type, into)?;
+            config.merge_category(entry_type, name, into);
+            history_changed = history.rename_category(entry_type, name, into);
+            println!("Merged {} category {} into {}", entry_type, name, into);
+        },
+        ("delete", _) => {
+            require_category(&config, entry_type, name)?;
+            let used = history.entries().iter().filter(|e| e.category_type == entry_type && e.category == name).count();
+            if used > 0 && !has_flag(args, "--force") {
+                return Err(format!("{} history entries are in {}; merge it into another category, or use --force to delete it anyway", used, name));
+            }
+            config.remove_category(entry_type, name);
+            println!("Deleted {} category {}", entry_type, name);
+        },
+        _ => return Err(String::from(usage))
+    }
+
+    save_config(&mut config, config_path)?;
+    if history_changed > 0 {
+        history.save(history_path).expect("Could not write history file");
+        println!("{} history entries updated", history_changed);
+    }
+    Ok(())
+}
+
+fn patterns(args: &[String], config_path: &Path) -> Result<(), String> {
+    let usage = "Usage: bank_statement_importer patterns add TYPE CATEGORY PATTERN | remove TYPE CATEGORY PATTERN | move TYPE CATEGORY PATTERN NEW_CATEGORY";
+    let command = args.first().map(|a| a.as_str()).unwrap_or("");
+    // Arguments are checked before the config is locked, and the config before anything changes
+    let (category, pattern) = match (args.get(2), args.get(3)) {
+        (Some(category), Some(pattern)) => (category, pattern),
+        _ => return Err(String::from(usage))
+    };
+    let new_category = match command {
+        "add" | "remove" => None,
+        "move" => Some(args.get(4).ok_or_else(|| String::from(usage))?),
+        _ => return Err(String::from(usage))
+    };
+    let entry_type = type_argument(args.get(1), usage)?;
+
+    let _lock = lock_config(config_path)?;
+    let mut config = load_config(config_path)?;
+    require_category(&config, entry_type, category)?;
+    if let Some(new_category) = new_category {
+        require_category(&config, entry_type, new_category)?;
+    }
+    let present = config.find_cat(entry_type, category).patterns.contains(pattern);
+
+    match (command, new_category) {
+        ("add", _) => {
+            if present {
+                return Err(format!("{} already has pattern {}", category, pattern));
+            }
+            config.find_cat(entry_type, category).patterns.push(pattern.clone());
+            println!("Added pattern {} to {} category {}", pattern, entry_type, category);
+        },
+        _ if !present => return Err(format!("{} has no pattern {}", category, pattern)),
+        ("remove", _) => {
+            config.remove_pattern(entry_type, category, pattern);
+            println!("Removed pattern {} from {} category {}", pattern, entry_type, category);
+        },
+        ("move", Some(new_category)) => {
+            config.remove_pattern(entry_type, category, pattern);
+            if !config.find_cat(entry_type, new_category).patterns.contains(pattern) {
+                config.find_cat(entry_type, new_category).patterns.push(pattern.clone());
+            }
+            println!("Moved pattern {} from {} to {}", pattern, category, new_category);
+        },
+        _ => return Err(String::from(usage))
+    }
+
+    save_config(&mut config, config_path)
+}
+
+fn config_command(args: &[String], config_path: &Path) -> Result<(), String> {
+    if args.first().map(|a| a.as_str()) != Some("check") {
+        return Err(String::from("Usage: bank_statement_importer config check"));
+    }
+
+    let (files, problems) = read_layers(config_path);
+    if !problems.is_empty() {
+        return Err(problems.join("\n"));
+    }
+    for (path, _) in files.iter() {
+        println!("{} is valid", path.display());
+    }
+    Ok(())
+}
+
+fn rules(args: &[String], config_path: &Path, history_path: &Path) -> Result<(), String> {
+    let usage = "Usage: bank_statement_importer rules test (DIRECTORY | --history)";
+
+    if args.first().map(|a| a.as_str()) != Some("test") {
+        return Err(String::from(usage));
+    }
+
+    let config = load_config(config_path)?;
+
+    let transactions: Vec<StringRecord> = if has_flag(args, "--history") {
+        let history = History::load(history_path).map_err(|e| format!("Could not read history file {:?}: {}", history_path, e))?;
+        history.entries().iter().map(|e| {
+            let sign = if e.expense { "-" } else { "" };
+            StringRecord::from(vec![e.date.format("%Y%m%d").to_string(), e.description.clone(), format!("{}{}", sign, money(e.original_amount)), String::from("0.00"), e.account.clone(), e.currency.clone()])
+        }).collect()
+    } else {
+        match args.get(1) {
+            Some(input_directory_path) => read_statements(input_directory_path, &mut Vec::new(), &config.accounts),
+            None => return Err(String::from(usage))
+        }
+    };
+
+    print!("{}", rules_test(&config, &transactions));
+    Ok(())
+}
+
+#[test]
+fn test_rules_test_reports_matches_unmatched_and_overlaps() {
+    let mut config = deserialise(serialise(&config_template())).unwrap();
+    config.add_category("personal", "groceries");
+    config.find_cat("personal", "groceries").patterns.push(String::from("TESCO"));
+    config.find_cat("personal", "groceries").patterns.push(String::from("SHOP"));
+    config.add_category("work", "stationery");
+    config.find_cat("work", "stationery").patterns.push(String::from("PAPER SHOP"));
+    let transactions = vec![
+        StringRecord::from(vec!["20191101", "TESCO STORES", "-10.00", "0.00"]),
+        StringRecord::from(vec!["20191102", "PAPER SHOP", "-5.00", "0.00"]),
+        StringRecord::from(vec!["20191103", "AIRLINE", "-300.00", "0.00"]),
+    ];
+
+    assert_eq!(rules_test(&config, &transactions), [
+        "3 transactions, 2 matched",
+        "",
+        "personal/groceries",
+        "  TESCO (1)",
+        "    20191101 TESCO STORES -10.00",
+        "  SHOP (1)",
+        "    20191102 PAPER SHOP -5.00",
+        "work/stationery",
+        "  PAPER SHOP (1)",
+        "    20191102 PAPER SHOP -5.00",
+        "",
+        "Unmatched (1)",
+        "  20191103 AIRLINE -300.00",
+        "",
+        "Matched by more than one category (1)",
+        "  20191102 PAPER SHOP -5.00: personal/groceries, work/stationery",
+        ""
+    ].join("\n"));
+}
+
+/// Describes what the configured patterns make of `transactions`: what each pattern matches,
+/// what nothing matches, and what patterns in more than one category match.
+fn rules_test(config: &Config, transactions: &[StringRecord]) -> String {
+    let describe = |t: &StringRecord| format!("{} {} {}", t.get(0).unwrap().trim(), t.get(1).unwrap().trim(), t.get(2).unwrap().trim());
+    let catalogues = [("personal", &config.personal), ("work", &config.work)];
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut unmatched: Vec<String> = Vec::new();
+    let mut overlapping: Vec<String> = Vec::new();
+
+    for transaction in transactions {
+        let description = config.normalise(transaction.get(1).unwrap());
+        let matching: Vec<String> = catalogues.iter()
+            .flat_map(|(entry_type, catalogue)| catalogue.categories.iter().filter(|c| c.matches_description(&description)).map(move |c| format!("{}/{}", entry_type, c.name)))
+            .collect();
+
+        if matching.is_empty() {
+            unmatched.push(describe(transaction));
+        } else if matching.len() > 1 {
+            overlapping.push(format!("{}: {}", describe(transaction), matching.join(", ")));
+        }
+    }
+
+    lines.push(format!("{} transactions, {} matched", transactions.len(), transactions.len() - unmatched.len()));
+
+    for (entry_type, catalogue) in catalogues.iter() {
+        for category in catalogue.categories.iter() {
+            if lines.len() == 1 {
+                lines.push(String::new());
+            }
+            lines.push(format!("{}/{}", entry_type, category.name));
+            for pattern in category.patterns.iter() {
+                let matches: Vec<String> = transactions.iter().filter(|t| config.normalise(t.get(1).unwrap()).contains(&pattern[..])).map(describe).collect();
+                lines.push(format!("  {} ({})", pattern, matches.len()));
+                lines.extend(matches.into_iter().map(|m| format!("    {}", m)));
+            }
+        }
+    }
+
+    for (heading, list) in [("Unmatched", unmatched), ("Matched by more than one category", overlapping)] {
+        lines.push(String::new());
+        lines.push(format!("{} ({})", heading, list.len()));
+        lines.extend(list.into_iter().map(|l| format!("  {}", l)));
+    }
+
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+#[test]
+fn test_classify_with_scripted_answers() {
+    let classified = classify_scripted(&["p", "c", "groceries", "y", "SHOP", "p", "w", "trvel", "c", "travel", "y", ""], &[], vec![
+        StringRecord::from(vec!["20191101", "SHOP ONE", "-10.00", "0.00"]),
+        StringRecord::from(vec!["20191102", "SHOP TWO", "-20.00", "0.00"]),
+        StringRecord::from(vec!["20191103", "AIRLINE", "-300.00", "0.00"]),
+    ]);
+    let (report, saved) = (&classified.report, classified.saved.unwrap());
+
+    assert!(classified.pending.is_empty());
+    assert_eq!(report.total("personal", true), Decimal::new(30, 0));
+    assert_eq!(report.total("work", true), Decimal::new(300, 0));
+    assert_eq!(saved.personal.categories, vec![Category { name: String::from("groceries"), patterns: vec![String::from("SHOP")] }]);
+    assert_eq!(saved.work_categories(), vec!["travel"]);
+    assert_eq!(report.entries().iter().map(|e| (e.description.as_str(), e.manual)).collect::<Vec<_>>(), vec![("SHOP ONE", Some(true)), ("AIRLINE", Some(true)), ("SHOP TWO", Some(false))]);
+}
+
+#[test]
+fn test_classify_offers_transfers_between_accounts() {
+    let classified = classify_scripted(&["y", "n", "p", "c", "shopping", "y", "", "p", "c", "refunds", "y", ""], &[], vec![
+        StringRecord::from(vec!["20191101", "CARD PAYMENT", "-500.00", "0.00", "everyday"]),
+        StringRecord::from(vec!["20191102", "SHOP", "-20.00", "0.00", "card"]),
+        StringRecord::from(vec!["20191102", "PAYMENT RECEIVED", "500.00", "0.00", "card"]),
+        StringRecord::from(vec!["20191103", "REFUND", "20.00", "0.00", "everyday"]),
+    ]);
+    let report = &classified.report;
+
+    assert!(classified.pending.is_empty());
+    assert_eq!(report.transfers().len(), 1);
+    assert_eq!(report.total("personal", true), Decimal::new(20, 0));
+    assert_eq!(report.total("personal", false), Decimal::new(20, 0));
+}
+
+#[test]
+fn test_classify_automatically_does_not_assume_transfers() {
+    let entries = vec![
+        StringRecord::from(vec!["20191101", "CARD PAYMENT", "-500.00", "0.00", "everyday"]),
+        StringRecord::from(vec!["20191102", "PAYMENT RECEIVED", "500.00", "0.00", "card"]),
+    ];
+
+    let classified = classify_with(&mut AutoUI {}, &[], &History::new(), &Classifier::new(), entries.clone());
+
+    assert_eq!(classified.pending, entries);
+    assert!(classified.report.transfers().is_empty());
+}
+
+#[test]
+fn test_classify_offers_refunds_of_earlier_purchases() {
+    let mut earlier = ActivityReport::new();
+    earlier.add_entry("personal", "clothing", &StringRecord::from(vec!["20191025", "OUTFITTERS CAPE TOWN", "-100.00", "0.00"]));
+    let mut history = History::new();
+    history.record(&earlier);
+    let mut ui = ScriptedUI::new(vec!["y", "n", "p", "c", "salary", "y", ""].into_iter().map(String::from).collect());
+
+    let classified = classify_with(&mut ui, &[], &history, &Classifier::new(), vec![
+        StringRecord::from(vec!["20191105", "OUTFITTERS REFUND", "40.00", "0.00"]),
+        StringRecord::from(vec!["20191106", "OUTFITTERS REFUND", "30.00", "0.00"]),
+    ]);
+    let report = &classified.report;
+
+    assert!(classified.pending.is_empty());
+    assert!(classified.config.infer_type("OUTFITTERS REFUND").is_none());
+    assert_eq!(report.refunds().len(), 1);
+    assert_eq!(report.entries()[0].category, "clothing");
+    assert_eq!(report.entries()[0].refund_of, Some(history.entries()[0].record_fingerprint.clone()));
+    assert_eq!(report.entries()[1].category, "salary");
+    assert_eq!(report.total("personal", true), Decimal::new(0, 0));
+    assert_eq!(report.earlier_refunds("personal"), Decimal::new(40, 0));
+    assert_eq!(report.total("personal", false), Decimal::new(30, 0));
+}
+
+#[test]
+fn test_classify_offers_refunds_of_purchases_imported_with_them() {
+    let classified = classify_scripted(&["y", "p", "c", "clothing", "y", ""], &[("personal", "salary", &["OUTFITTERS"])], vec![
+        StringRecord::from(vec!["20191101", "POS CLOTHING CO", "-100.00", "0.00"]),
+        StringRecord::from(vec!["20191105", "CLOTHING CO REFUND", "40.00", "0.00"]),
+        StringRecord::from(vec!["20191106", "OUTFITTERS", "-30.00", "0.00"]),
+        StringRecord::from(vec!["20191107", "OUTFITTERS", "10.00", "0.00"]),
+    ]);
+    let report = &classified.report;
+
+    assert!(classified.pending.is_empty());
+    assert_eq!(report.refunds().len(), 1);
+    assert_eq!(report.refunds()[0].category, "clothing");
+    assert_eq!(report.category_totals("personal", true), vec![(String::from("clothing"), Decimal::new(60, 0)), (String::from("salary"), Decimal::new(30, 0))]);
+    assert_eq!(report.total("personal", false), Decimal::new(10, 0));
+}
+
+#[test]
+fn test_classify_leaves_refunds_of_pending_purchases_pending() {
+    let entries = vec![
+        StringRecord::from(vec!["20191101", "CLOTHING CO", "-100.00", "0.00"]),
+        StringRecord::from(vec!["20191105", "CLOTHING CO REFUND", "40.00", "0.00"]),
+    ];
+
+    let classified = classify_scripted(&["y"], &[], entries.clone());
+
+    assert_eq!(classified.pending, entries);
+    assert!(classified.report.entries().is_empty());
+}
+
+#[test]
+fn test_classify_automatically_leaves_unmatched_entries_pending() {
+    let classified = classify_with(&mut AutoUI {}, &[("work", "travel", &["AIRLINE"])], &History::new(), &Classifier::new(), vec![
+        StringRecord::from(vec!["20191101", "SHOP ONE", "-10.00", "0.00"]),
+        StringRecord::from(vec!["20191103", "AIRLINE", "-300.00", "0.00"]),
+    ]);
+
+    assert_eq!(classified.pending, vec![StringRecord::from(vec!["20191101", "SHOP ONE", "-10.00", "0.00"])]);
+    assert_eq!(classified.report.total("work", true), Decimal::new(300, 0));
+    assert!(classified.saved.is_none());
+}
+
+#[test]
+fn test_classify_skip_defers_entry_until_the_end() {
+    let first = StringRecord::from(vec!["20191101", "LANDLORD", "-1000.00", "0.00"]);
+    let second = StringRecord::from(vec!["20191102", "BOOKSHOP", "-20.00", "0.00"]);
+    let third = StringRecord::from(vec!["20191103", "MYSTERY", "-5.00", "0.00"]);
+
+    let classified = classify_scripted(&[":s", "p", "c", "books", "y", "", ":s", "p", "c", "rent", "y", "", ":s"], &[], vec![first, second, third.clone()]);
+    let report = &classified.report;
+
+    assert_eq!(classified.pending, vec![third]);
+    assert_eq!(report.entries().iter().map(|e| e.category.clone()).collect::<Vec<String>>(), vec!["books", "rent"]);
+    assert_eq!(report.entries()[1].description, "LANDLORD");
+}
+
+#[test]
+fn test_classify_back_revisits_previous_entry_keeping_config() {
+    let classified = classify_scripted(&["p", "c", "books", "y", "", ":b", "w", "c", "training", "y", "", "p", "books", ""], &[], vec![
+        StringRecord::from(vec!["20191101", "BOOKSHOP", "-20.00", "0.00"]),
+        StringRecord::from(vec!["20191102", "NOVELS", "-30.00", "0.00"]),
+    ]);
+    let (report, saved) = (&classified.report, classified.saved.unwrap());
+
+    assert_eq!(report.total("work", true), Decimal::new(20, 0));
+    assert_eq!(report.total("personal", true), Decimal::new(30, 0));
+    assert_eq!(saved.personal_categories(), vec!["books"]);
+    assert_eq!(saved.work_categories(), vec!["training"]);
+}
+
+#[test]
+fn test_classify_undo_removes_added_category_and_pattern() {
+    let classified = classify_scripted(&["p", "c", "bokos", "y", "BOOK", ":u", "p", "c", "books", "y", "BOOKSHOP", "p", "books", ""], &[], vec![
+        StringRecord::from(vec!["20191101", "BOOKSHOP", "-20.00", "0.00"]),
+        StringRecord::from(vec!["20191102", "NOVELS", "-30.00", "0.00"]),
+    ]);
+
+    assert_eq!(classified.report.entries().iter().map(|e| e.category.clone()).collect::<Vec<String>>(), vec!["books", "books"]);
+    assert_eq!(classified.saved.unwrap().personal.categories, vec![Category { name: String::from("books"), patterns: vec![String::from("BOOKSHOP")] }]);
+}
+
+#[test]
+fn test_classify_takes_suggested_pattern_by_number() {
+    let classified = classify_scripted(&["p", "c", "groceries", "y", "1", "p"], &[], vec![
+        StringRecord::from(vec!["20191101", "CARD 1234 TESCO STORES 3345", "-10.00", "0.00"]),
+        StringRecord::from(vec!["20191102", "CARD 1234 TESCO STORES 2187", "-20.00", "0.00"]),
+    ]);
+
+    assert!(classified.pending.is_empty());
+    assert_eq!(classified.report.total("personal", true), Decimal::new(30, 0));
+    assert_eq!(classified.saved.unwrap().personal.categories, vec![Category { name: String::from("groceries"), patterns: vec![String::from("TESCO")] }]);
+}
+
+#[test]
+fn test_classify_lists_predicted_category_first() {
+    let mut past = ActivityReport::new();
+    past.add_entry("personal", "restaurants", &StringRecord::from(vec!["20191001", "PIZZA EXPRESS LEEDS", "-20.00", "0.00"]));
+    past.add_entry("personal", "groceries", &StringRecord::from(vec!["20191002", "TESCO STORES", "-30.00", "0.00"]));
+    past.add_entry("personal", "groceries", &StringRecord::from(vec!["20191003", "TESCO EXPRESS", "-5.00", "0.00"]));
+    let mut ui = ScriptedUI::new(vec!["p", "1", ""].into_iter().map(String::from).collect());
+
+    let classified = classify_with(&mut ui, &[("personal", "groceries", &[]), ("personal", "restaurants", &[])], &History::new(), &Classifier::train(past.entries()), vec![
+        StringRecord::from(vec!["20191101", "PIZZA EXPRESS YORK", "-25.00", "0.00"])
+    ]);
+
+    assert_eq!(classified.report.entries()[0].category, "restaurants");
+}
+
+#[test]
+fn test_classify_predicts_when_only_the_other_type_has_a_pattern() {
+    let mut past = ActivityReport::new();
+    past.add_entry("personal", "restaurants", &StringRecord::from(vec!["20191001", "PIZZA EXPRESS LEEDS", "-20.00", "0.00"]));
+    past.add_entry("personal", "groceries", &StringRecord::from(vec!["20191002", "TESCO STORES", "-30.00", "0.00"]));
+    let mut ui = ScriptedUI::new(vec!["p", "1", ""].into_iter().map(String::from).collect());
+    let rules: &[(&str, &str, &[&str])] = &[("personal", "groceries", &[]), ("personal", "restaurants", &[]), ("work", "meals", &["PIZZA"])];
+
+    let classified = classify_with(&mut ui, rules, &History::new(), &Classifier::train(past.entries()), vec![
+        StringRecord::from(vec!["20191101", "PIZZA EXPRESS YORK", "-25.00", "0.00"])
+    ]);
+
+    assert_eq!(classified.report.entries()[0].category, "restaurants");
+}
+
+#[test]
+fn test_classify_review_reassigns_group_and_moves_pattern() {
+    let classified = classify_scripted(&["p", "p", "r", "fuel"], &[("personal", "groceries", &["GARAGE"]), ("personal", "fuel", &[])], vec![
+        StringRecord::from(vec!["20191101", "GARAGE ONE", "-40.00", "0.00"]),
+        StringRecord::from(vec!["20191102", "GARAGE TWO", "-50.00", "0.00"]),
+    ]);
+
+    assert!(classified.pending.is_empty());
+    assert_eq!(classified.report.entries().iter().map(|e| e.category.clone()).collect::<Vec<String>>(), vec!["fuel", "fuel"]);
+    assert_eq!(classified.saved.unwrap().personal.categories, vec![
+        Category { name: String::from("groceries"), patterns: vec![] },
+        Category { name: String::from("fuel"), patterns: vec![String::from("GARAGE")] }
+    ]);
+}
+
+#[test]
+fn test_classify_review_refined_pattern_requeues_entries_it_no_longer_matches() {
+    let classified = classify_scripted(&["p", "p", "e", "SHOP ONE", "p", "c", "books", "y", ""], &[("personal", "groceries", &["SHOP"])], vec![
+        StringRecord::from(vec!["20191101", "SHOP ONE", "-10.00", "0.00"]),
+        StringRecord::from(vec!["20191102", "BOOKSHOP", "-20.00", "0.00"]),
+    ]);
+
+    assert!(classified.pending.is_empty());
+    assert_eq!(classified.report.entries().iter().map(|e| (e.description.clone(), e.category.clone())).collect::<Vec<(String, String)>>(), vec![
+        (String::from("SHOP ONE"), String::from("groceries")),
+        (String::from("BOOKSHOP"), String::from("books"))
+    ]);
+    assert_eq!(classified.saved.unwrap().personal.categories[0], Category { name: String::from("groceries"), patterns: vec![String::from("SHOP ONE")] });
+}
+
+#[test]
+fn test_classify_review_skip_leaves_group_pending() {
+    let entry = StringRecord::from(vec!["20191103", "AIRLINE", "-300.00", "0.00"]);
+
+    let classified = classify_scripted(&["w", ":s"], &[("work", "travel", &["AIRLINE"])], vec![entry.clone()]);
+
+    assert_eq!(classified.pending, vec![entry]);
+    assert!(classified.report.entries().is_empty());
+    assert!(classified.saved.is_none());
+}
+
+/// How many of the most recently used categories are listed ahead of the most frequently used.
+const RECENT_CATEGORIES: usize = 3;
+
+/// How many of the classifier's suggestions are shown, and listed first, for an entry no pattern matches.
+const PREDICTIONS: usize = 3;
+
+/// Classifies `raw_entries` into the report, returning those left pending. When the user cancels,
+/// any categories and patterns already saved are put back as they were.
+fn classify(raw_entries: Vec<StringRecord>, config: &mut Config, ui: &mut dyn UserInterface, report: &mut ActivityReport, history: &History, classifier: &Classifier, config_path: &Path) -> Prompted<Vec<StringRecord>> {
+    let before = config.export();
+    let (personal, work, origins) = (config.personal.clone(), config.work.clone(), config.origins.clone());
+
+    ui.begin(&raw_entries);
+    let classified = classify_entries(raw_entries, config, ui, report, history, classifier, config_path);
+    ui.finish();
+
+    if classified.is_err() && config.export() != before {
+        config.personal = personal;
+        config.work = work;
+        config.origins = origins;
+        save_config_while_classifying(config, config_path, ui);
+    }
+    classified
+}
+
+/// An entry being imported, after its row among those the user interface began with.
+type Row = (usize, StringRecord);
+
+fn classify_entries(raw_entries: Vec<StringRecord>, config: &mut Config, ui: &mut dyn UserInterface, report: &mut ActivityReport, history: &History, classifier: &Classifier, config_path: &Path) -> Prompted<Vec<StringRecord>> {
+    let rows = offer_transfers(raw_entries, ui, report)?;
+    let (rows, refunds) = offer_refunds(rows, config, ui, report, history)?;
+    let mut queue: VecDeque<Row> = rows.into_iter().collect();
+    let mut steps: Vec<Step> = Vec::new();
+    let mut skipped: Vec<usize> = Vec::new();
+    let mut pending: Vec<StringRecord> = Vec::new();
+    let mut automapped: Vec<Automapped> = Vec::new();
+
+    loop {
+        while let Some(current) = queue.pop_front() {
+            let classified = classify_entry(&current, &queue, config, ui, report, history, classifier);
+            let (row, entry) = current;
+            match classified {
+                Ok(Some(step)) => {
+                    if step.added_category.is_some() || step.added_pattern.is_some() {
+                        save_config_while_classifying(config, config_path, ui);
+                    }
+                    if let Some(pattern) = &step.matched_pattern {
+                        automapped.push(Automapped { row, entry: entry.clone(), entry_type: step.entry_type, category: step.category.clone(), pattern: pattern.clone() });
+                    }
+                    steps.push(step);
+                    ui.display_totals(report);
+                },
+                Ok(None) => pending.push(entry),
+                Err(Navigation::Skip) => {
+                    if skipped.contains(&row) {
+                        pending.push(entry);
+                    } else {
+                        skipped.push(row);
+                        queue.push_back((row, entry));
+                    }
+                },
+                Err(Navigation::Cancel) => return Err(Navigation::Cancel),
+                Err(navigation) => {
+                    queue.push_front((row, entry));
+
+                    match steps.pop() {
+                        Some(step) => {
+                            if step.recorded {
+                                report.remove_record(&step.entry);
+                            }
+                            automapped.retain(|a| a.row != step.row);
+                            if navigation == Navigation::Undo && step.revert(config) {
+                                save_config_while_classifying(config, config_path, ui);
+                            }
+                            queue.push_front((step.row, step.entry));
+                            ui.display_totals(report);
+                        },
+                        None => ui.notice("Nothing to go back to")
+                    }
+                }
+            }
+        }
+
+        if automapped.is_empty() {
+            break;
+        }
+
+        // Entries reviewed so far are settled, so going back stops here
+        steps.clear();
+        let (unmatched, left_pending) = review(std::mem::take(&mut automapped), config, ui, report, config_path)?;
+        queue.extend(unmatched);
+        pending.extend(left_pending);
+        ui.display_totals(report);
+    }
+
+    // Refunds of debits imported alongside them follow those debits, or wait with them
+    for (refund, debit) in refunds {
+        match report.entries().iter().find(|e| e.record_fingerprint == debit).cloned() {
+            Some(original) => if !report.add_refund(&refund, &original) {
+                ui.notice("Skipping refund - already present");
+            },
+            None => pending.push(refund)
+        }
+    }
+    ui.display_totals(report);
+
+    Ok(pending)
+}
+
+/// How many days apart the two sides of a transfer between accounts may be dated.
+const TRANSFER_DAYS: i64 = 3;
+
+/// Offers each pair of entries that looks like a transfer between accounts, adding those
+/// confirmed to the report. Returns the entries left to classify, with their rows.
+fn offer_transfers(entries: Vec<StringRecord>, ui: &mut dyn UserInterface, report: &mut ActivityReport) -> Prompted<Vec<Row>> {
+    let mut transferred: Vec<usize> = Vec::new();
+
+    for (debit, credit) in transfer::pair(&entries, TRANSFER_DAYS) {
+        let confirmed = loop {
+            match ui.confirm_transfer((debit, credit), &entries[debit], &entries[credit]) {
+                Ok(confirmed) => break confirmed,
+                Err(Navigation::Skip) => break false,
+                Err(Navigation::Cancel) => return Err(Navigation::Cancel),
+                Err(_) => ui.notice("There is nothing to go back to before transfers")
+            }
+        };
+
+        if confirmed {
+            if !report.add_transfer(&entries[debit], &entries[credit]) {
+                ui.notice("Skipping transfer - already present");
+            }
+            transferred.push(debit);
+            transferred.push(credit);
+            ui.display_totals(report);
+        }
+    }
+
+    Ok(entries.into_iter().enumerate().filter(|(row, _)| !transferred.contains(row)).collect())
+}
+
+/// A refund and the fingerprint of the debit it waits on.
+type WaitingRefund = (StringRecord, String);
+
+/// Offers each credit among `rows` that could refund an earlier debit from the same merchant,
+/// in `history`, the report or `entries` themselves, before anything is classified. Credits a
+/// pattern matches are left alone unless they say they are refunds. Refunds of debits already
+/// classified go straight into the report; the others are returned with the fingerprint of their
+/// debit, to be added once it is. Returns the entries left to classify and those refunds.
+fn offer_refunds(rows: Vec<Row>, config: &Config, ui: &mut dyn UserInterface, report: &mut ActivityReport, history: &History) -> Prompted<(Vec<Row>, Vec<WaitingRefund>)> {
+    let recorded = |entry: &CategorisedEntry| history.entries().iter().any(|h| h.record_fingerprint == entry.record_fingerprint);
+    let unclassified: Vec<CategorisedEntry> = rows.iter().map(|(_, e)| report.entry("", "", e)).filter(|e| e.expense && !recorded(e)).collect();
+    let mut earlier: Vec<CategorisedEntry> = history.entries().iter().cloned().chain(unclassified.iter().cloned()).collect();
+    let mut refunded: Vec<usize> = Vec::new();
+    let mut waiting: Vec<WaitingRefund> = Vec::new();
+
+    for (row, record) in rows.iter() {
+        let credit = report.entry("", "", record);
+        if credit.expense || recorded(&credit) || (config.infer_type(&credit.description).is_some() && !report::marked_as_refund(&credit.description)) {
+            continue;
+        }
+        let original = match report.refunded_entry(&credit, &earlier) {
+            Some(original) => original.clone(),
+            None => continue
+        };
+
+        let confirmed = loop {
+            match ui.confirm_refund(*row, &credit, &original) {
+                Ok(confirmed) => break confirmed,
+                Err(Navigation::Skip) => break false,
+                Err(Navigation::Cancel) => return Err(Navigation::Cancel),
+                Err(_) => ui.notice("There is nothing to go back to before refunds")
+            }
+        };
+
+        if confirmed {
+            if unclassified.iter().any(|e| e.record_fingerprint == original.record_fingerprint) {
+                // Noted among the earlier entries so that what is left of the debit is right
+                let mut refund = credit;
+                refund.refund_of = Some(original.record_fingerprint.clone());
+                earlier.push(refund);
+                waiting.push((record.clone(), original.record_fingerprint.clone()));
+            } else if !report.add_refund(record, &original) {
+                ui.notice("Skipping refund - already present");
+            }
+            refunded.push(*row);
+            ui.display_totals(report);
+        }
+    }
+
+    let rows = rows.into_iter().filter(|(row, _)| !refunded.contains(row)).collect();
+    Ok((rows, waiting))
+}
+
+/// What classifying one entry changed, so that it can be gone back over or undone.
+struct Step {
+    row: usize,
+    entry: StringRecord,
+    entry_type: &'static str,
+    category: String,
+    /// Set when a pattern chose the category, in which case the entry waits for review rather
+    /// than going straight into the report.
+    matched_pattern: Option<String>,
+    recorded: bool,
+    added_category: Option<String>,
+    added_pattern: Option<(String, String)>
+}
+
+impl Step {
+    /// Removes the category and pattern this step added, returning whether there were any.
+    fn revert(&self, config: &mut Config) -> bool {
+        if let Some((category, pattern)) = &self.added_pattern {
+            config.remove_pattern(self.entry_type, category, pattern);
+        }
+        if let Some(category) = &self.added_category {
+            config.remove_category(self.entry_type, category);
+        }
+        self.added_category.is_some() || self.added_pattern.is_some()
+    }
+}
+
+fn classify_entry(current: &Row, queue: &VecDeque<Row>, config: &mut Config, ui: &mut dyn UserInterface, report: &mut ActivityReport, history: &History, classifier: &Classifier) -> Prompted<Option<Step>> {
+    let (row, entry) = (current.0, &current.1);
+    ui.display_entry(row, entry);
+
+    let description = entry.get(1).unwrap();
+    let predict = |config: &Config, entry_type: Option<&str>| -> Vec<Prediction> {
+        classifier.predict(description).into_iter()
+            .filter(|p| entry_type.is_none_or(|t| p.category_type == t) && config.category_exists(&p.category_type, &p.category))
+            .take(PREDICTIONS)
+            .collect()
+    };
+
+    // Before the type is chosen, predictions only help when no pattern of either type matches
+    let predicted_early = config.infer_type(description).is_none();
+    ui.display_predictions(&if predicted_early { predict(config, None) } else { Vec::new() });
+
+    let entry_type = match ui.get_type()?.or_else(|| config.infer_type(description)) {
+        Some(entry_type) => entry_type,
+        None => return Ok(None)
+    };
+
+    let mut step = Step { row, entry: entry.clone(), entry_type, category: String::new(), matched_pattern: None, recorded: false, added_category: None, added_pattern: None };
+
+    let selected_category = match config.match_category(entry_type, description) {
+        Some(c) => {
+            ui.display_automap(&c.name);
+            step.matched_pattern = c.matching_pattern(&config.normalise(description)).cloned();
+            c.name.clone()
+        },
+        None => {
+            let predictions = predict(config, Some(entry_type));
+            if !predicted_early {
+                ui.display_predictions(&predictions);
+            }
+
+            let categories = if entry_type == "personal" {
+                config.personal_categories()
+            } else {
+                config.work_categories()
+            };
+            let mut categories = rank_categories(&categories, entry_type, history.entries().iter().chain(report.entries()), RECENT_CATEGORIES);
+            for prediction in predictions.iter().rev() {
+                if let Some(position) = categories.iter().position(|c| *c == prediction.category) {
+                    let predicted = categories.remove(position);
+                    categories.insert(0, predicted);
+                }
+            }
+
+            ui.display_categories(entry_type, &categories);
+
+            let category = match ui.capture_category(&categories)? {
+                Some(category) => category,
+                None => return Ok(None)
+            };
+
+            let suggestions: Vec<(String, usize)> = suggest_patterns(&config.normalise(description)).into_iter().map(|pattern| {
+                let matches = queue.iter().filter(|(_, e)| config.normalise(e.get(1).unwrap()).contains(&pattern[..])).count();
+                (pattern, matches)
+            }).collect();
+            let pattern = ui.capture_pattern(&suggestions)?;
+
+            if !config.category_exists(entry_type, &category) {
+                config.add_category(entry_type, &category);
+                step.added_category = Some(category.clone());
+            }
+
+            if let Some(pattern) = pattern {
+                config.find_cat(entry_type, &category).patterns.push(pattern.clone());
+                step.added_pattern = Some((category.clone(), pattern));
+            }
+
+            category
+        }
+    };
+
+    if step.matched_pattern.is_none() {
+        step.recorded = report.add_manual_entry(entry_type, &selected_category, entry);
+        if !step.recorded {
+            ui.notice("Skipping entry - already present");
+        }
+    }
+    step.category = selected_category;
+
+    Ok(Some(step))
+}
+
+/// An entry whose category was chosen by a pattern, waiting for review.
+struct Automapped {
+    row: usize,
+    entry: StringRecord,
+    entry_type: &'static str,
+    category: String,
+    pattern: String
+}
+
+/// Adds an entry whose pattern was reviewed, telling the user if its record is already there.
+fn add_reviewed(ui: &mut dyn UserInterface, report: &mut ActivityReport, entry_type: &str, category: &str, entry: &StringRecord) {
+    if !report.add_entry(entry_type, category, entry) {
+        ui.notice("Skipping entry - already present");
+    }
+}
+
+/// Asks about automatically mapped entries a group at a time, one group for each category and
+/// pattern. Accepted entries go into the report. Returns the entries a refined pattern no longer
+/// matches, which need classifying again, and those left pending.
+fn review(automapped: Vec<Automapped>, config: &mut Config, ui: &mut dyn UserInterface, report: &mut ActivityReport, config_path: &Path) -> Prompted<(Vec<Row>, Vec<StringRecord>)> {
+    let mut groups: Vec<(&'static str, String, String, Vec<Row>)> = Vec::new();
+    for a in automapped {
+        match groups.iter_mut().find(|(t, c, p, _)| *t == a.entry_type && *c == a.category && *p == a.pattern) {
+            Some((_, _, _, rows)) => rows.push((a.row, a.entry)),
+            None => groups.push((a.entry_type, a.category, a.pattern, vec![(a.row, a.entry)]))
+        }
+    }
+
+    let mut unmatched: Vec<Row> = Vec::new();
+    let mut pending: Vec<StringRecord> = Vec::new();
+
+    for (entry_type, category, pattern, group) in groups {
+        let (rows, entries): (Vec<usize>, Vec<StringRecord>) = group.into_iter().unzip();
+        let categories = if entry_type == "personal" {
+            config.personal_categories()
+        } else {
+            config.work_categories()
+        };
+
+        let decision = loop {
+            match ui.review_group(entry_type, &category, &pattern, &rows, &entries, &categories) {
+                Ok(decision) => break Some(decision),
+                Err(Navigation::Skip) => break None,
+                Err(Navigation::Cancel) => return Err(Navigation::Cancel),
+                Err(_) => ui.notice("Entries already reviewed cannot be gone back over")
+            }
+        };
+
+        match decision {
+            Some(Review::Accept) => {
+                for entry in entries.iter() {
+                    add_reviewed(ui, report, entry_type, &category, entry);
+                }
+            },
+            Some(Review::Reassign(new_category)) => {
+                config.remove_pattern(entry_type, &category, &pattern);
+                config.add_category(entry_type, &new_category);
+                config.find_cat(entry_type, &new_category).patterns.push(pattern.clone());
+                save_config_while_classifying(config, config_path, ui);
+
+                for entry in entries.iter() {
+                    add_reviewed(ui, report, entry_type, &new_category, entry);
+                }
+            },
+            Some(Review::Refine(new_pattern)) => {
+                config.replace_pattern(entry_type, &category, &pattern, &new_pattern);
+                save_config_while_classifying(config, config_path, ui);
+
+                for (row, entry) in rows.into_iter().zip(entries) {
+                    if config.normalise(entry.get(1).unwrap()).contains(&new_pattern[..]) {
+                        add_reviewed(ui, report, entry_type, &category, &entry);
+                    } else {
+                        unmatched.push((row, entry));
+                    }
+                }
+            },
+            None => pending.extend(entries)
+        }
+    }
+
+    Ok((unmatched, pending))
+}
+
+/// Writes a config read from one file back to it, or each layer of a layered config back to its
+/// own file, backing up each file first. Layers whose categories, patterns and origins have not
+/// changed since they were read are left alone. The files of other layers, such as a team's shared
+/// rules, are locked while they are written, as the session already holds the lock on its own.
+fn save_config(config: &mut Config, config_path: &Path) -> Result<(), String> {
+    let write = |path: &Path, exported: &Yaml| -> Result<(), String> {
+        store::back_up(path, BACKUPS)
+            .and_then(|_| store::write_atomically(path, &serialise(exported)))
+            .map_err(|e| format!("Could not write {}: {}", path.display(), e))
+    };
+
+    if config.layers.is_empty() {
+        return write(config_path, &config.export());
+    }
+
+    for index in 0..config.layers.len() {
+        let exported = config.export_layer(Some(index));
+        if exported == config.layers[index].saved {
+            continue;
+        }
+
+        let path = config.layers[index].path.clone();
+        let _lock = if index == config.primary {
+            None
+        } else {
+            Some(Lock::acquire(&path).map_err(|e| format!("Could not write {}: {}", path.display(), e))?)
+        };
+        write(&path, &exported)?;
+        config.layers[index].saved = exported;
+    }
+    Ok(())
+}
+
+/// Saves the config part way through classifying, where a failed write is reported rather than
+/// ending the session. Whatever was not written is tried again with the next change.
+fn save_config_while_classifying(config: &mut Config, config_path: &Path, ui: &mut dyn UserInterface) {
+    if let Err(message) = save_config(config, config_path) {
+        ui.notice(&message);
+    }
+}
+
+fn save_pending(pending_path: &Path, pending: &[StringRecord]) -> Result<(), csv::Error> {
+    let mut records: Vec<StringRecord> = Vec::new();
+
+    // Pending files written by older releases have no account or currency column
+    let complete = |record: &StringRecord| statement_record(record, record.get(report::ACCOUNT).unwrap_or(""), record.get(report::CURRENCY).unwrap_or(""));
+
+    if pending_path.exists() {
+        for result in Reader::from_path(pending_path)?.records() {
+            records.push(complete(&result?));
+        }
+    }
+
+    for record in pending.iter().map(complete) {
+        if !records.contains(&record) {
+            records.push(record);
+        }
+    }
+
+    let mut writer = Writer::from_path(pending_path)?;
+    writer.write_record(["date", "description", "amount", "balance", "account", "currency"])?;
+    for record in records.iter() {
+        writer.write_record(record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// How many timestamped copies of the config are kept.
+const BACKUPS: usize = 10;
+
+/// Stops another session changing the config while this one can. The lock is released when the
+/// returned `Lock` is dropped, so callers return errors rather than exiting while they hold it.
+fn lock_config(config_path: &Path) -> Result<Lock, String> {
+    Lock::acquire(config_path).map_err(|e| e.to_string())
+}
+
+#[test]
+fn test_cancelling_an_import_writes_nothing() {
+    let directory = env::temp_dir().join(format!("bsi_import_cancel_{}", std::process::id()));
+    let statements = directory.join("statements");
+    fs::create_dir_all(&statements).unwrap();
+    fs::write(statements.join("current.csv"), "date,description,amount,balance\n20191101,BOOKSHOP,-10.00,0.00\n20191102,GARAGE,-40.00,0.00\n").unwrap();
+    let config_path = directory.join("config.yml");
+    let config = serialise(&config_template());
+    fs::write(&config_path, &config).unwrap();
+    let script_path = directory.join("script.txt");
+    fs::write(&script_path, "p\nc\nbooks\ny\nBOOK\n:c\n").unwrap();
+    let args: Vec<String> = vec![statements.to_str().unwrap(), "20191101", "--script", script_path.to_str().unwrap()].into_iter().map(String::from).collect();
+
+    let result = import(&args, &config_path, &config_path.with_extension("history.csv"));
+    let config_after = fs::read_to_string(&config_path).unwrap();
+    let written: Vec<bool> = ["history.csv", "pending.csv", "model.csv"].iter().map(|extension| config_path.with_extension(extension).exists()).collect();
+    let _ = fs::remove_dir_all(&directory);
+
+    assert_eq!(result, Err(String::from("Import cancelled; nothing was written")));
+    assert_eq!(config_after, config);
+    assert_eq!(written, vec![false, false, false]);
+}
+
+fn import(args: &[String], config_path: &Path, history_path: &Path) -> Result<(), String> {
+    let (input_directory_path, start_date_string) = match (args.first(), args.get(1)) {
+        (Some(input_directory_path), Some(start_date_string)) => (input_directory_path, start_date_string),
+        _ => return Err(String::from("Usage: bank_statement_importer DIRECTORY YYYYMMDD [--account NAME] [--currency CODE] [--refunds-as-income] [--format table|markdown] [--html FILE] [--pending FILE] [--auto | --script FILE | --tui]"))
+    };
+
+    let format = flag_value(args, "--format").unwrap_or("table");
+    if format != "table" && format != "markdown" {
+        return Err(format!("Unknown format {}", format));
+    }
+
+    let (start_date, end_date_excl) = get_date_boundaries(start_date_string).map_err(|e| format!("Could not read start date {}: {}", start_date_string, e))?;
+
+    let _lock = lock_config(config_path)?;
+    let mut config = load_config(config_path)?;
+
+    let mut sources: Vec<String> = Vec::new();
+
+    let account_name = flag_value(args, "--account");
+    if let Some(account_name) = account_name {
+        if !config.accounts.iter().any(|a| a.name == account_name) {
+            return Err(format!("There is no account {}", account_name));
+        }
+    }
+
+    let raw_entries: Vec<StringRecord> = read_statements(input_directory_path, &mut sources, &config.accounts).into_iter().filter(|record| {
+        let record_date = NaiveDate::parse_from_str(record.get(0).unwrap(), "%Y%m%d").unwrap();
+        record_date >= start_date && record_date < end_date_excl && account_name.is_none_or(|a| record.get(report::ACCOUNT) == Some(a))
+    }).collect();
+
+    let mut report = ActivityReport::with_normaliser(config.normaliser.clone());
+    if let Some(base) = flag_value(args, "--currency").map(String::from).or_else(|| config.currency.clone()) {
+        let rates_path = config_path.with_extension("rates.csv");
+        let rates = Rates::load(&rates_path).map_err(|e| format!("Could not read rates file {:?}: {}", rates_path, e))?;
+        check_conversions(raw_entries.iter().filter_map(|record| {
+            let date = NaiveDate::parse_from_str(record.get(0).unwrap(), "%Y%m%d").unwrap();
+            conversion_problem(&rates, record.get(report::CURRENCY).unwrap_or(""), record.get(report::ACCOUNT).unwrap_or(""), &base, date)
+        }).collect(), &rates_path)?;
+        report.convert_to(&base, rates);
+    }
+    if has_flag(args, "--refunds-as-income") {
+        report.count_refunds_as_income();
+    }
+
+    let mut ui: Box<dyn UserInterface> = if let Some(script_path) = flag_value(args, "--script") {
+        Box::new(ScriptedUI::from_file(Path::new(script_path)).expect("Could not read script file"))
+    } else if has_flag(args, "--auto") {
+        Box::new(AutoUI {})
+    } else if has_flag(args, "--tui") {
+        Box::new(TuiUI::new())
+    } else {
+        Box::new(UI {})
+    };
+
+    let mut history = History::load(history_path).map_err(|e| format!("Could not read history file {:?}: {}", history_path, e))?;
+
+    let model_path = config_path.with_extension("model.csv");
+    // The model is only ever learnt from history, so a damaged one is learnt again
+    let mut classifier = Classifier::load(&model_path).unwrap_or_else(|e| {
+        eprintln!("Could not read model file {:?}, so it will be rebuilt from history: {}", model_path, e);
+        Classifier::new()
+    });
+    if classifier.is_empty() {
+        classifier = Classifier::train(history.entries());
+    }
+
+    let pending = classify(raw_entries, &mut config, ui.as_mut(), &mut report, &history, &classifier, config_path)
+        .map_err(|_| String::from("Import cancelled; nothing was written"))?;
+
+    if !pending.is_empty() {
+        let pending_path = match flag_value(args, "--pending") {
+            Some(pending_path) => PathBuf::from(pending_path),
+            None => config_path.with_extension("pending.csv")
+        };
+        save_pending(&pending_path, &pending).expect("Could not write pending file");
+        println!("{} entries left pending in {:?}", pending.len(), pending_path);
+    }
+
+    if format == "markdown" {
+        print!("{}", markdown::render(&report, start_date, end_date_excl, &sources));
+    } else {
+        let unit = report.base_currency().map(|base| format!(" {}", base)).unwrap_or_default();
+        println!("Work Expense: {}{}", report.total("work", true), unit);
+        println!("Personal Expense: {}{}", report.total("personal", true), unit);
+        for category_type in ["work", "personal"] {
+            let refunded = report.earlier_refunds(category_type);
+            if refunded != Decimal::new(0, 0) {
+                println!("Refunds of earlier {} purchases, not taken off the expense above: {}{}", category_type, refunded, unit);
+            }
+        }
+        for account in report.accounts() {
+            println!("{} Expense: {}{} Income: {}{}", account, report.account_total(&account, true), unit, report.account_total(&account, false), unit);
+        }
+    }
+
+    if let Some(html_path) = flag_value(args, "--html") {
+        fs::write(html_path, html::render(&report, start_date, end_date_excl)).expect("Could not write HTML report");
+        println!("HTML report written to {}", html_path);
     }
 
-    println!("Work Expense: {}", report.total("work", true));
-    println!("Personal Expense: {}", report.total("personal", true));
+    history.record(&report);
+    history.save(history_path).expect("Could not write history file");
+    Classifier::train(history.entries()).save(&model_path).expect("Could not write model file");
+    Ok(())
 }